@@ -22,9 +22,9 @@ impl DamageTrailsBuilder {
 }
 
 impl AnalyzerBuilder for DamageTrailsBuilder {
-    fn build(&self, meta: &ReplayMeta) -> Box<dyn Analyzer> {
-        Box::new(DamageMonitor {
-            version: Version::from_client_exe(&meta.clientVersionFromExe),
+    fn build(&self, meta: &ReplayMeta) -> Result<Box<dyn Analyzer>, wows_replays::ErrorKind> {
+        Ok(Box::new(DamageMonitor {
+            version: meta.clientVersionFromExe.parse::<Version>()?,
             username: meta.playerName.clone(),
             avatarid: None,
             shipid: None,
@@ -34,7 +34,7 @@ impl AnalyzerBuilder for DamageTrailsBuilder {
             meta: Some((*meta).clone()),
             output: self.output.clone(),
             damages: vec![],
-        })
+        }))
     }
 }
 
@@ -257,6 +257,7 @@ impl Analyzer for DamageMonitor {
                 entity_id,
                 method,
                 args,
+                ..
             }) => {
                 if *method == "receiveDamageStat" {
                     let value = serde_pickle::de::value_from_slice(
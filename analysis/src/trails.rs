@@ -20,13 +20,16 @@ impl TrailsBuilder {
 }
 
 impl AnalyzerBuilder for TrailsBuilder {
-    fn build(&self, meta: &wows_replays::ReplayMeta) -> Box<dyn Analyzer> {
-        Box::new(TrailRenderer {
+    fn build(
+        &self,
+        meta: &wows_replays::ReplayMeta,
+    ) -> Result<Box<dyn Analyzer>, wows_replays::ErrorKind> {
+        Ok(Box::new(TrailRenderer {
             trails: HashMap::new(),
             player_trail: vec![],
             output: self.output.clone(),
             meta: Some((*meta).clone()),
-        })
+        }))
     }
 }
 
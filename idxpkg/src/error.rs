@@ -0,0 +1,24 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ErrorKind {
+    #[error("I/O error")]
+    Io {
+        #[from]
+        err: std::io::Error,
+    },
+    #[error("Malformed idx file {path}: {reason}")]
+    BadIdx { path: String, reason: String },
+    #[error("No game version found in {path}")]
+    NoBinVersion { path: String },
+    #[error("Resource {path} not found")]
+    NotFound { path: String },
+    #[error("Resource {path} is not a directory")]
+    NotADirectory { path: String },
+    #[error("Resource {path} is corrupt: {reason}")]
+    BadEntry { path: String, reason: String },
+    #[error("Invalid pattern {pattern}: {reason}")]
+    BadPattern { pattern: String, reason: String },
+    #[error("Failed to extract {failed} of {total} resources")]
+    ExtractFailed { failed: usize, total: usize },
+}
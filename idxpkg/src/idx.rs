@@ -0,0 +1,307 @@
+//! Parsing of the .idx files, which index the resources stored in a .pkg file
+
+use nom::{
+    bytes::complete::{tag, take, take_till},
+    number::complete::{le_i32, le_i64, le_u32, le_u64},
+    IResult,
+};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+#[derive(Debug)]
+struct Header {
+    num_nodes: i32,
+    num_files: i32,
+    third_offset: i64,
+    trailer_offset: i64,
+    first_block: Vec<u8>,
+    unknown1: i64,
+    unknown2: i64,
+}
+
+#[derive(Debug)]
+struct Node {
+    name: String,
+    id: u64,
+    parent: u64,
+    unknown: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RawFileRecord {
+    pub id: u64,
+    pub offset: i64,
+    pub length: i32,
+    /// The CRC-32 of the uncompressed contents
+    pub crc32: u32,
+    pub uncompressed_length: i64,
+    raw: Vec<u8>,
+}
+
+fn parse_header(i: &[u8]) -> IResult<&[u8], Header> {
+    let (i, _) = tag([0x49, 0x53, 0x46, 0x50])(i)?; // ISFP
+    let (i, first_block) = take(12usize)(i)?;
+    let (i, num_nodes) = le_i32(i)?;
+    let (i, num_files) = le_i32(i)?;
+    let (i, unknown1) = le_i64(i)?;
+    let (i, unknown2) = le_i64(i)?;
+    let (i, third_offset) = le_i64(i)?;
+    let (i, trailer_offset) = le_i64(i)?;
+    Ok((
+        i,
+        Header {
+            num_nodes,
+            num_files,
+            third_offset,
+            first_block: first_block.to_owned(),
+            unknown1,
+            unknown2,
+            trailer_offset,
+        },
+    ))
+}
+
+fn parse_node(i: &[u8]) -> IResult<&[u8], (&[u8], i64, u64, u64)> {
+    let (i, unknown) = take(8usize)(i)?;
+    let (i, ptr) = le_i64(i)?;
+    let (i, id) = le_u64(i)?;
+    let (i, parent) = le_u64(i)?;
+    Ok((i, (unknown, ptr, id, parent)))
+}
+
+fn parse_pointers_with_strings(num_nodes: usize, i: &[u8]) -> Result<(&[u8], Vec<Node>), String> {
+    let mut i = i;
+    let mut nodes = vec![];
+    for idx in 0..num_nodes {
+        let (new_i, (unknown, ptr, id, parent)) =
+            parse_node(i).map_err(|e| format!("Bad node {}: {:?}", idx, e))?;
+
+        // The string is relative to the start of the node, and nul-terminated
+        let string = usize::try_from(ptr)
+            .ok()
+            .and_then(|ptr| i.get(ptr..))
+            .and_then(|s| s.iter().position(|b| *b == 0).map(|len| &s[..len]))
+            .ok_or_else(|| format!("Name of node {} is out of bounds", idx))?;
+        let string = std::str::from_utf8(string)
+            .map_err(|_| format!("Name of node {} is not UTF-8", idx))?;
+        nodes.push(Node {
+            name: string.to_owned(),
+            id,
+            parent,
+            unknown: unknown.to_owned(),
+        });
+
+        i = new_i;
+    }
+    Ok((i, nodes))
+}
+
+fn parse_file_record(i: &[u8]) -> IResult<&[u8], RawFileRecord> {
+    let orig_i = i;
+    let (i, id) = le_u64(i)?;
+    let (i, _) = take(8usize)(i)?;
+    let (i, offset) = le_i64(i)?;
+    let (i, _) = take(8usize)(i)?;
+    let (i, length) = le_i32(i)?;
+    let (i, crc32) = le_u32(i)?;
+    let (i, uncompressed_length) = le_i64(i)?;
+    Ok((
+        i,
+        RawFileRecord {
+            id,
+            offset,
+            length,
+            crc32,
+            uncompressed_length,
+            raw: orig_i.to_owned(),
+        },
+    ))
+}
+
+fn parse_file_records(num_files: usize, i: &[u8]) -> Result<(&[u8], Vec<RawFileRecord>), String> {
+    let mut i = i;
+    let mut records = vec![];
+    for idx in 0..num_files {
+        let record = i
+            .get(..48)
+            .ok_or_else(|| format!("File record {} is truncated", idx))?;
+        let (_new_i, record) =
+            parse_file_record(record).map_err(|e| format!("Bad file record {}: {:?}", idx, e))?;
+        records.push(record);
+        i = &i[48..];
+    }
+    Ok((i, records))
+}
+
+fn parse_trailer(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (i, _) = le_i64(i)?;
+    let (i, _) = le_i64(i)?;
+    let (i, _) = le_u64(i)?;
+    let (i, pkg_name) = take_till(|b| b == 0)(i)?;
+    let (i, _) = tag([0])(i)?;
+    Ok((i, pkg_name))
+}
+
+/// Returns the data starting at the given offset, which is relative to the end of the
+/// first 16 bytes of the file
+fn at_offset<'a>(i: &'a [u8], offset: i64, what: &str) -> Result<&'a [u8], String> {
+    usize::try_from(offset)
+        .ok()
+        .and_then(|offset| i.get(offset.checked_add(0x10)?..))
+        .ok_or_else(|| format!("{} offset {} is out of bounds", what, offset))
+}
+
+pub(crate) struct IdxFile {
+    pub pkg_name: String,
+    nodes: Vec<Node>,
+    pub files: Vec<RawFileRecord>,
+}
+
+impl IdxFile {
+    /// Parses an idx file, returning a description of the problem if it is malformed
+    pub fn parse(i: &[u8]) -> Result<IdxFile, String> {
+        let orig_i = i;
+        let (i, header) = parse_header(i).map_err(|e| format!("Bad header: {:?}", e))?;
+        let count = |n: i32, what: &str| {
+            usize::try_from(n).map_err(|_| format!("Negative number of {}: {}", what, n))
+        };
+        let (_, nodes) = parse_pointers_with_strings(count(header.num_nodes, "nodes")?, i)?;
+        let (_, files) = parse_file_records(
+            count(header.num_files, "files")?,
+            at_offset(orig_i, header.third_offset, "File record")?,
+        )?;
+        let (_, pkg_name) = parse_trailer(at_offset(orig_i, header.trailer_offset, "Trailer")?)
+            .map_err(|e| format!("Bad trailer: {:?}", e))?;
+        let pkg_name =
+            std::str::from_utf8(pkg_name).map_err(|_| "Package name is not UTF-8".to_string())?;
+        Ok(IdxFile {
+            pkg_name: pkg_name.to_owned(),
+            nodes,
+            files,
+        })
+    }
+
+    /// Returns the path of each file, built by following the chain of parent nodes
+    pub fn paths(&self) -> Result<Vec<(String, &RawFileRecord)>, String> {
+        let mut node_indices = HashMap::new();
+        for (idx, node) in self.nodes.iter().enumerate() {
+            node_indices.insert(node.id, idx);
+        }
+
+        let mut paths = vec![];
+        for file in self.files.iter() {
+            let mut parts = vec![];
+            let mut current_search = file.id;
+            while let Some(idx) = node_indices.get(&current_search) {
+                // Every node can appear at most once in a path, anything longer is a cycle
+                if parts.len() > self.nodes.len() {
+                    return Err(format!("Path of file {} has a cycle", file.id));
+                }
+                current_search = self.nodes[*idx].parent;
+                parts.push(self.nodes[*idx].name.as_str());
+            }
+            if parts.is_empty() {
+                return Err(format!("File {} has no node", file.id));
+            }
+
+            parts.reverse();
+            paths.push((parts.join("/"), file));
+        }
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Writes an idx file for a package with the given nodes, as (id, parent, name), and
+    /// files, as (id, offset, length, uncompressed length, CRC-32)
+    pub fn write_idx(
+        pkg_name: &str,
+        nodes: &[(u64, u64, &str)],
+        files: &[(u64, i64, i32, i64, u32)],
+    ) -> Vec<u8> {
+        let nodes_start = 56;
+        let names_start = nodes_start + 32 * nodes.len();
+        let mut names = vec![];
+        let mut out = vec![];
+        out.extend(b"ISFP");
+        out.extend([0; 12].iter());
+        out.extend(&(nodes.len() as i32).to_le_bytes());
+        out.extend(&(files.len() as i32).to_le_bytes());
+        out.extend(&[0; 16]);
+        let files_start = names_start + nodes.iter().map(|n| n.2.len() + 1).sum::<usize>();
+        let trailer_start = files_start + 48 * files.len();
+        out.extend(&(files_start as i64 - 0x10).to_le_bytes());
+        out.extend(&(trailer_start as i64 - 0x10).to_le_bytes());
+        for (idx, (id, parent, name)) in nodes.iter().enumerate() {
+            let ptr = names_start + names.len() - (nodes_start + 32 * idx);
+            out.extend(&[0; 8]);
+            out.extend(&(ptr as i64).to_le_bytes());
+            out.extend(&id.to_le_bytes());
+            out.extend(&parent.to_le_bytes());
+            names.extend(name.as_bytes());
+            names.push(0);
+        }
+        out.extend(names);
+        for (id, offset, length, uncompressed_length, crc32) in files.iter() {
+            out.extend(&id.to_le_bytes());
+            out.extend(&[0; 8]);
+            out.extend(&offset.to_le_bytes());
+            out.extend(&[0; 8]);
+            out.extend(&length.to_le_bytes());
+            out.extend(&crc32.to_le_bytes());
+            out.extend(&uncompressed_length.to_le_bytes());
+        }
+        out.extend(&[0; 24]);
+        out.extend(pkg_name.as_bytes());
+        out.push(0);
+        out
+    }
+
+    #[test]
+    fn parse_idx() {
+        let data = write_idx(
+            "system_data_0001.pkg",
+            &[
+                (1, 100, "scripts"),
+                (2, 1, "entities.xml"),
+                (3, 1, "entity_defs"),
+                (4, 3, "Avatar.def"),
+            ],
+            &[(2, 0, 10, 10, 0), (4, 10, 5, 20, 0)],
+        );
+        let idx = IdxFile::parse(&data).unwrap();
+        assert_eq!(idx.pkg_name, "system_data_0001.pkg");
+        let paths: Vec<_> = idx
+            .paths()
+            .unwrap()
+            .into_iter()
+            .map(|(path, file)| (path, file.offset, file.length, file.uncompressed_length))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("scripts/entities.xml".to_string(), 0, 10, 10),
+                ("scripts/entity_defs/Avatar.def".to_string(), 10, 5, 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_idx() {
+        let data = write_idx("a.pkg", &[(1, 0, "a"), (2, 1, "b")], &[(2, 0, 1, 1, 0)]);
+        // Every truncation is an error rather than a panic
+        for len in 0..data.len() {
+            assert!(IdxFile::parse(&data[..len]).is_err(), "{}", len);
+        }
+        assert!(IdxFile::parse(b"nope").is_err());
+
+        // Files without a node, and cycles of parents, have no path
+        let data = write_idx("a.pkg", &[(1, 0, "a")], &[(2, 0, 1, 1, 0)]);
+        assert!(IdxFile::parse(&data).unwrap().paths().is_err());
+        let data = write_idx("a.pkg", &[(1, 2, "a"), (2, 1, "b")], &[(2, 0, 1, 1, 0)]);
+        assert!(IdxFile::parse(&data).unwrap().paths().is_err());
+    }
+}
@@ -0,0 +1,8 @@
+//! Reads the game resources stored in World of Warships' .idx/.pkg files
+
+pub mod error;
+mod idx;
+mod manager;
+
+pub use error::ErrorKind;
+pub use manager::{DirEntry, EntryKind, EntryReader, FileRecord, IdxPkgManager};
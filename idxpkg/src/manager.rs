@@ -0,0 +1,649 @@
+use memmap::MmapOptions;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::error::ErrorKind;
+use crate::idx::IdxFile;
+
+/// A resource stored in a .pkg file
+#[derive(Debug, Clone)]
+pub struct FileRecord {
+    /// The name of the .pkg file containing the resource
+    pub pkg_name: String,
+    /// The resource's path, e.g. "scripts/entities.xml"
+    pub path: String,
+    pub id: u64,
+    pub offset: usize,
+    pub length: usize,
+    pub uncompressed_length: usize,
+    /// The CRC-32 of the uncompressed contents
+    pub crc32: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// An entry of a directory in the virtual directory tree of a package set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    /// The path of the entry, which can be passed to [IdxPkgManager::get] or
+    /// [IdxPkgManager::read_dir]
+    pub path: String,
+    pub kind: EntryKind,
+}
+
+/// Strips the slashes around a path, so that "/scripts/" and "scripts" are the same
+/// directory and "" is the root
+fn normalize(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+fn parent(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => ("", path),
+    }
+}
+
+/// Encapsulates everything required to interact with a idx/pkg set
+pub struct IdxPkgManager {
+    files: Vec<FileRecord>,
+    /// Index into `files` by path
+    paths: HashMap<String, usize>,
+    /// The entries of each directory, sorted by name
+    dirs: HashMap<String, Vec<DirEntry>>,
+    pkg_prefix: PathBuf,
+    pkgs: Mutex<HashMap<String, Arc<memmap::Mmap>>>,
+}
+
+impl IdxPkgManager {
+    /// Creates a new resource manager from a path to the .idx files (usually
+    /// <game path>/bin/<number>/idx/) and a path to the .pkg files (usually
+    /// <game path>/res_packages/)
+    pub fn new(idx_path: &Path, pkg_path: &Path) -> Result<Self, ErrorKind> {
+        let mut idx_files = vec![];
+        for entry in walkdir::WalkDir::new(idx_path).sort_by_file_name() {
+            let entry = entry.map_err(std::io::Error::from)?;
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+
+            let contents = std::fs::read(path)?;
+            let idx_file = IdxFile::parse(&contents).map_err(|reason| ErrorKind::BadIdx {
+                path: path.display().to_string(),
+                reason,
+            })?;
+            idx_files.push((path.to_path_buf(), idx_file));
+        }
+        Self::from_idx_files(idx_files, pkg_path)
+    }
+
+    /// Opens the resources of a game install. If no bin/ version is given, the most recent
+    /// one is used.
+    pub fn open_install(wows_path: &Path, bin_version: Option<&str>) -> Result<Self, ErrorKind> {
+        let bin_version = match bin_version {
+            Some(bin_version) => bin_version.to_owned(),
+            None => Self::latest_bin_version(wows_path)?.to_string(),
+        };
+        let idx_prefix = wows_path.join("bin").join(bin_version).join("idx");
+        let pkg_prefix = wows_path.join("res_packages");
+        Self::new(&idx_prefix, &pkg_prefix)
+    }
+
+    /// Returns the most recent of the numbered directories in the install's bin/ directory
+    pub fn latest_bin_version(wows_path: &Path) -> Result<u32, ErrorKind> {
+        let bin_path = wows_path.join("bin");
+        let mut latest = None;
+        for entry in std::fs::read_dir(&bin_path)? {
+            let version = entry?
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<u32>().ok());
+            latest = latest.max(version);
+        }
+        latest.ok_or_else(|| ErrorKind::NoBinVersion {
+            path: bin_path.display().to_string(),
+        })
+    }
+
+    fn from_idx_files(
+        idx_files: Vec<(PathBuf, IdxFile)>,
+        pkg_path: &Path,
+    ) -> Result<Self, ErrorKind> {
+        let mut files = vec![];
+        let mut paths = HashMap::new();
+        let mut dirs: HashMap<String, BTreeMap<String, EntryKind>> = HashMap::new();
+        dirs.insert(String::new(), BTreeMap::new());
+        for (idx_path, idx_file) in idx_files.iter() {
+            let bad_idx = |reason| ErrorKind::BadIdx {
+                path: idx_path.display().to_string(),
+                reason,
+            };
+            for (path, file) in idx_file.paths().map_err(bad_idx)? {
+                let field = |value: i64, name: &str| {
+                    usize::try_from(value)
+                        .map_err(|_| bad_idx(format!("{} of {} has bad {}", value, path, name)))
+                };
+                let record = FileRecord {
+                    pkg_name: idx_file.pkg_name.clone(),
+                    id: file.id,
+                    offset: field(file.offset, "offset")?,
+                    length: field(file.length.into(), "length")?,
+                    uncompressed_length: field(file.uncompressed_length, "uncompressed length")?,
+                    crc32: file.crc32,
+                    path,
+                };
+
+                // If several idx files have the same path, the first one wins
+                if paths.contains_key(&record.path) {
+                    continue;
+                }
+                let mut child = (record.path.as_str(), EntryKind::File);
+                loop {
+                    let (dir, name) = parent(child.0);
+                    let entries = dirs.entry(dir.to_owned()).or_default();
+                    let existing = entries.insert(name.to_owned(), child.1);
+                    if matches!(existing, Some(kind) if kind != child.1) {
+                        return Err(bad_idx(format!(
+                            "{} is both a file and a directory",
+                            child.0
+                        )));
+                    }
+                    if dir.is_empty() || existing.is_some() {
+                        break;
+                    }
+                    child = (dir, EntryKind::Directory);
+                }
+                paths.insert(record.path.clone(), files.len());
+                files.push(record);
+            }
+        }
+
+        let dirs = dirs
+            .into_iter()
+            .map(|(dir, entries)| {
+                let entries = entries
+                    .into_iter()
+                    .map(|(name, kind)| DirEntry {
+                        path: if dir.is_empty() {
+                            name.clone()
+                        } else {
+                            format!("{}/{}", dir, name)
+                        },
+                        name,
+                        kind,
+                    })
+                    .collect();
+                (dir, entries)
+            })
+            .collect();
+        Ok(Self {
+            files,
+            paths,
+            dirs,
+            pkg_prefix: pkg_path.to_path_buf(),
+            pkgs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns an iterator over all of the FileRecords contained in this package set.
+    pub fn iter(&self) -> std::slice::Iter<'_, FileRecord> {
+        self.files.iter()
+    }
+
+    /// Looks up the file at the given path
+    pub fn get(&self, path: &str) -> Option<&FileRecord> {
+        self.paths.get(normalize(path)).map(|idx| &self.files[*idx])
+    }
+
+    /// Lists the entries of the directory at the given path, sorted by name. The root
+    /// directory is "".
+    pub fn read_dir(&self, path: &str) -> Result<&[DirEntry], ErrorKind> {
+        let path = normalize(path);
+        match self.dirs.get(path) {
+            Some(entries) => Ok(entries),
+            None if self.paths.contains_key(path) => Err(ErrorKind::NotADirectory {
+                path: path.to_owned(),
+            }),
+            None => Err(ErrorKind::NotFound {
+                path: path.to_owned(),
+            }),
+        }
+    }
+
+    /// Returns the files whose paths match a glob pattern such as "scripts/**" or
+    /// "scripts/entity_defs/*.def", sorted by path. `*` doesn't match across directories.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<&FileRecord>, ErrorKind> {
+        let matcher = globset::GlobBuilder::new(normalize(pattern))
+            .literal_separator(true)
+            .build()
+            .map_err(|e| ErrorKind::BadPattern {
+                pattern: pattern.to_owned(),
+                reason: e.to_string(),
+            })?
+            .compile_matcher();
+        let mut files: Vec<_> = self
+            .files
+            .iter()
+            .filter(|record| matcher.is_match(&record.path))
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(files)
+    }
+
+    fn pkg(&self, pkg_name: &str) -> Result<Arc<memmap::Mmap>, ErrorKind> {
+        let mut pkgs = self.pkgs.lock().unwrap();
+        if let Some(m) = pkgs.get(pkg_name) {
+            return Ok(m.clone());
+        }
+        let f = std::fs::File::open(self.pkg_prefix.join(pkg_name))?;
+        let map = Arc::new(unsafe { MmapOptions::new().map(&f)? });
+        pkgs.insert(pkg_name.to_owned(), map.clone());
+        Ok(map)
+    }
+
+    /// Opens a reader which decompresses the given FileRecord as it is read. Note that in
+    /// order to get a FileRecord, one should first call iter() or get().
+    pub fn open(&self, record: &FileRecord) -> Result<EntryReader, ErrorKind> {
+        let map = self.pkg(&record.pkg_name)?;
+        let end = record
+            .offset
+            .checked_add(record.length)
+            .filter(|end| *end <= map.len())
+            .ok_or_else(|| ErrorKind::BadEntry {
+                path: record.path.clone(),
+                reason: format!("It lies outside of {}", record.pkg_name),
+            })?;
+        let data = PkgSlice {
+            map,
+            pos: record.offset,
+            end,
+        };
+
+        // TODO: Determine the actual way they encode this data
+        let inner = if record.length != record.uncompressed_length {
+            Inner::Deflated(
+                flate2::read::DeflateDecoder::new(data).take(record.uncompressed_length as u64),
+            )
+        } else {
+            Inner::Stored(data)
+        };
+        Ok(EntryReader {
+            inner,
+            hasher: Some(crc32fast::Hasher::new()),
+            crc32: record.crc32,
+        })
+    }
+
+    /// Extracts the given FileRecord into an array of bytes. Note that in order to
+    /// get a FileRecord, one should first call iter() or get().
+    pub fn extract(&self, record: &FileRecord) -> Result<Vec<u8>, ErrorKind> {
+        let mut contents = Vec::with_capacity(record.uncompressed_length);
+        self.open(record)?
+            .read_to_end(&mut contents)
+            .map_err(|e| ErrorKind::BadEntry {
+                path: record.path.clone(),
+                reason: e.to_string(),
+            })?;
+        if contents.len() != record.uncompressed_length {
+            return Err(ErrorKind::BadEntry {
+                path: record.path.clone(),
+                reason: format!(
+                    "Expected {} bytes, got {}",
+                    record.uncompressed_length,
+                    contents.len()
+                ),
+            });
+        }
+        Ok(contents)
+    }
+
+    /// Extracts the given files in parallel into `out_dir`, each at its path relative to
+    /// `out_dir`. `progress` is called as each file is done. If any files couldn't be
+    /// extracted, the others are still extracted and an error is returned at the end.
+    pub fn extract_tree<F>(
+        &self,
+        records: &[&FileRecord],
+        out_dir: &Path,
+        progress: F,
+    ) -> Result<(), ErrorKind>
+    where
+        F: Fn(&FileRecord, &Result<(), ErrorKind>) + Sync,
+    {
+        let failed = records
+            .par_iter()
+            .filter(|record| {
+                let result = self.extract_into(record, out_dir);
+                progress(record, &result);
+                result.is_err()
+            })
+            .count();
+        if failed > 0 {
+            return Err(ErrorKind::ExtractFailed {
+                failed,
+                total: records.len(),
+            });
+        }
+        Ok(())
+    }
+
+    fn extract_into(&self, record: &FileRecord, out_dir: &Path) -> Result<(), ErrorKind> {
+        // Paths come from the idx files, make sure they can't escape the output directory
+        let relative = Path::new(&record.path);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(ErrorKind::BadEntry {
+                path: record.path.clone(),
+                reason: "Its path leaves the output directory".to_string(),
+            });
+        }
+        let contents = self.extract(record)?;
+        let path = out_dir.join(relative);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+/// The stored bytes of a resource, within a mapped .pkg file
+struct PkgSlice {
+    map: Arc<memmap::Mmap>,
+    pos: usize,
+    end: usize,
+}
+
+impl Read for PkgSlice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = (&self.map[self.pos..self.end]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+enum Inner {
+    Stored(PkgSlice),
+    Deflated(std::io::Take<flate2::read::DeflateDecoder<PkgSlice>>),
+}
+
+/// Reads the contents of a resource, returned by [IdxPkgManager::open]. Reaching the end
+/// of the contents fails if they don't match the resource's checksum.
+pub struct EntryReader {
+    inner: Inner,
+    /// The checksum of what has been read so far, until the end has been checked
+    hasher: Option<crc32fast::Hasher>,
+    crc32: u32,
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = match &mut self.inner {
+            Inner::Stored(r) => r.read(buf)?,
+            Inner::Deflated(r) => r.read(buf)?,
+        };
+        if n > 0 {
+            if let Some(hasher) = self.hasher.as_mut() {
+                hasher.update(&buf[..n]);
+            }
+        } else if !buf.is_empty() {
+            if let Some(hasher) = self.hasher.take() {
+                let crc32 = hasher.finalize();
+                if crc32 != self.crc32 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("CRC-32 is {:08x}, expected {:08x}", crc32, self.crc32),
+                    ));
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::idx::test::write_idx;
+    use std::io::Write;
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// Writes a package set with a stored and a compressed file to a temporary directory
+    fn install(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("idxpkg-{}-{}", name, std::process::id()));
+        let entities = b"<root><Avatar/></root>".to_vec();
+        let avatar = b"<root>".repeat(100);
+        let compressed = deflate(&avatar);
+        let mut pkg = entities.clone();
+        pkg.extend(&compressed);
+
+        let idx = write_idx(
+            "system_data_0001.pkg",
+            &[
+                (1, 100, "scripts"),
+                (2, 1, "entities.xml"),
+                (3, 1, "entity_defs"),
+                (4, 3, "Avatar.def"),
+            ],
+            &[
+                (
+                    2,
+                    0,
+                    entities.len() as i32,
+                    entities.len() as i64,
+                    crc32(&entities),
+                ),
+                (
+                    4,
+                    entities.len() as i64,
+                    compressed.len() as i32,
+                    avatar.len() as i64,
+                    crc32(&avatar),
+                ),
+            ],
+        );
+        std::fs::create_dir_all(dir.join("bin/1234/idx")).unwrap();
+        std::fs::create_dir_all(dir.join("bin/999/idx")).unwrap();
+        std::fs::create_dir_all(dir.join("bin/other")).unwrap();
+        std::fs::create_dir_all(dir.join("res_packages")).unwrap();
+        std::fs::write(dir.join("bin/1234/idx/system_data.idx"), idx).unwrap();
+        std::fs::write(dir.join("res_packages/system_data_0001.pkg"), pkg).unwrap();
+        dir
+    }
+
+    #[test]
+    fn lookup_and_extract() {
+        let dir = install("extract");
+        assert_eq!(IdxPkgManager::latest_bin_version(&dir).unwrap(), 1234);
+        let mgr = IdxPkgManager::open_install(&dir, None).unwrap();
+        assert_eq!(mgr.iter().count(), 2);
+
+        let record = mgr.get("scripts/entities.xml").unwrap();
+        assert_eq!(mgr.extract(record).unwrap(), b"<root><Avatar/></root>");
+        let record = mgr.get("/scripts/entity_defs/Avatar.def").unwrap();
+        assert_eq!(mgr.extract(record).unwrap(), b"<root>".repeat(100));
+
+        // Streaming gives the same contents
+        let mut streamed: Vec<u8> = vec![];
+        let mut buf = [0; 7];
+        let mut reader = mgr.open(record).unwrap();
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            streamed.extend(&buf[..n]);
+        }
+        assert_eq!(streamed, b"<root>".repeat(100));
+
+        assert!(mgr.get("scripts").is_none());
+        assert!(mgr.get("scripts/missing.def").is_none());
+
+        // Records pointing past the end of their package are errors
+        let mut bad = record.clone();
+        bad.offset += 1000;
+        assert!(matches!(mgr.extract(&bad), Err(ErrorKind::BadEntry { .. })));
+        let mut bad = record.clone();
+        bad.uncompressed_length += 1;
+        assert!(matches!(mgr.extract(&bad), Err(ErrorKind::BadEntry { .. })));
+        let mut bad = record.clone();
+        bad.crc32 += 1;
+        assert!(matches!(mgr.extract(&bad), Err(ErrorKind::BadEntry { .. })));
+        let mut reader = mgr.open(&bad).unwrap();
+        assert!(std::io::copy(&mut reader, &mut std::io::sink()).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn directory_tree() {
+        let dir = install("tree");
+        let mgr = IdxPkgManager::open_install(&dir, Some("1234")).unwrap();
+        let entry = |name: &str, path: &str, kind| DirEntry {
+            name: name.to_string(),
+            path: path.to_string(),
+            kind,
+        };
+        assert_eq!(
+            mgr.read_dir("").unwrap(),
+            &[entry("scripts", "scripts", EntryKind::Directory)]
+        );
+        assert_eq!(
+            mgr.read_dir("scripts/").unwrap(),
+            &[
+                entry("entities.xml", "scripts/entities.xml", EntryKind::File),
+                entry("entity_defs", "scripts/entity_defs", EntryKind::Directory),
+            ]
+        );
+        assert_eq!(
+            mgr.read_dir("scripts/entity_defs").unwrap(),
+            &[entry(
+                "Avatar.def",
+                "scripts/entity_defs/Avatar.def",
+                EntryKind::File
+            )]
+        );
+        assert!(matches!(
+            mgr.read_dir("scripts/entities.xml"),
+            Err(ErrorKind::NotADirectory { .. })
+        ));
+        assert!(matches!(
+            mgr.read_dir("gui"),
+            Err(ErrorKind::NotFound { .. })
+        ));
+
+        // Missing and malformed installs are errors
+        assert!(IdxPkgManager::open_install(&dir, Some("999"))
+            .unwrap()
+            .read_dir("")
+            .unwrap()
+            .is_empty());
+        assert!(IdxPkgManager::open_install(&dir, Some("1")).is_err());
+        std::fs::write(dir.join("bin/1234/idx/broken.idx"), b"ISFP").unwrap();
+        assert!(matches!(
+            IdxPkgManager::open_install(&dir, None),
+            Err(ErrorKind::BadIdx { .. })
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn glob_extract_tree() {
+        let dir = install("glob");
+        let mgr = IdxPkgManager::open_install(&dir, None).unwrap();
+        let glob = |pattern: &str| -> Vec<String> {
+            mgr.glob(pattern)
+                .unwrap()
+                .iter()
+                .map(|record| record.path.clone())
+                .collect()
+        };
+        let all = vec![
+            "scripts/entities.xml".to_string(),
+            "scripts/entity_defs/Avatar.def".to_string(),
+        ];
+        assert_eq!(glob("scripts/**"), all);
+        assert_eq!(glob("**/*.{def,xml}"), all);
+        assert_eq!(glob("scripts/*"), &all[..1]);
+        assert_eq!(glob("**/*.def"), &all[1..]);
+        assert!(glob("gui/**").is_empty());
+        assert!(matches!(
+            mgr.glob("scripts/[a"),
+            Err(ErrorKind::BadPattern { .. })
+        ));
+
+        let out = dir.join("out");
+        let records = mgr.glob("scripts/**").unwrap();
+        let done = Mutex::new(vec![]);
+        mgr.extract_tree(&records, &out, |record, result| {
+            assert!(result.is_ok());
+            done.lock().unwrap().push(record.path.clone());
+        })
+        .unwrap();
+        let mut done = done.into_inner().unwrap();
+        done.sort();
+        assert_eq!(done, all);
+        assert_eq!(
+            std::fs::read(out.join("scripts/entities.xml")).unwrap(),
+            b"<root><Avatar/></root>"
+        );
+        assert_eq!(
+            std::fs::read(out.join("scripts/entity_defs/Avatar.def")).unwrap(),
+            b"<root>".repeat(100)
+        );
+
+        // Bad files are reported, and don't stop the others from being extracted
+        let mut corrupt = records[0].clone();
+        corrupt.crc32 += 1;
+        let mut escaping = records[0].clone();
+        escaping.path = "../escaped.xml".to_string();
+        let out = dir.join("out2");
+        let failed = Mutex::new(vec![]);
+        let result = mgr.extract_tree(
+            &[&corrupt, records[1], &escaping],
+            &out,
+            |record, result| {
+                if result.is_err() {
+                    failed.lock().unwrap().push(record.path.clone());
+                }
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(ErrorKind::ExtractFailed {
+                failed: 2,
+                total: 3
+            })
+        ));
+        assert_eq!(failed.into_inner().unwrap().len(), 2);
+        assert!(!out.join("scripts/entities.xml").exists());
+        assert!(out.join("scripts/entity_defs/Avatar.def").exists());
+        assert!(!dir.join("escaped.xml").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 pub trait AnalyzerBuilder {
-    fn build(&self, meta: &crate::ReplayMeta) -> Box<dyn Analyzer>;
+    /// Fails if the replay can't be analyzed, e.g. because its version is malformed
+    fn build(&self, meta: &crate::ReplayMeta) -> Result<Box<dyn Analyzer>, crate::ErrorKind>;
 }
 
 pub trait Analyzer {
@@ -23,12 +24,20 @@ impl AnalyzerAdapter {
             a.finish();
         }
     }
-}
 
-impl crate::packet2::PacketProcessor for AnalyzerAdapter {
-    fn process(&mut self, packet: crate::packet2::Packet<'_, '_>) {
-        for a in self.analyzers.iter_mut() {
-            a.process(&packet);
+    /// Runs every packet from the iterator (see [crate::packet2::Parser::packets]) through
+    /// the analyzers, then finishes them.
+    pub fn consume<'replay, 'argtype, I>(&mut self, packets: I) -> Result<(), crate::ErrorKind>
+    where
+        I: Iterator<Item = Result<crate::packet2::Packet<'replay, 'argtype>, crate::ErrorKind>>,
+    {
+        for packet in packets {
+            let packet = packet?;
+            for a in self.analyzers.iter_mut() {
+                a.process(&packet);
+            }
         }
+        self.finish();
+        Ok(())
     }
 }
@@ -13,12 +13,12 @@ impl ChatLoggerBuilder {
 }
 
 impl AnalyzerBuilder for ChatLoggerBuilder {
-    fn build(&self, meta: &crate::ReplayMeta) -> Box<dyn Analyzer> {
-        let version = crate::version::Version::from_client_exe(&meta.clientVersionFromExe);
-        Box::new(ChatLogger {
+    fn build(&self, meta: &crate::ReplayMeta) -> Result<Box<dyn Analyzer>, crate::ErrorKind> {
+        let version = meta.clientVersionFromExe.parse()?;
+        Ok(Box::new(ChatLogger {
             usernames: HashMap::new(),
             version,
-        })
+        }))
     }
 }
 
@@ -1,8 +1,13 @@
 use crate::analyzer::{Analyzer, AnalyzerBuilder};
-use crate::packet2::{EntityMethodPacket, Packet, PacketType};
+use crate::packet2::{
+    EntityMethodPacket, OwnedBasePlayerCreatePacket, OwnedCellPlayerCreatePacket,
+    OwnedEntityCreatePacket, OwnedEntityMethodPacket, OwnedEntityPropertyPacket,
+    OwnedInvalidPacket, OwnedMapPacket, OwnedPropertyUpdatePacket, Packet, PacketType,
+};
+use crate::rpc::typedefs::OwnedArgValue;
 use crate::unpack_rpc_args;
 use modular_bitfield::prelude::*;
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::TryInto;
 
@@ -23,8 +28,8 @@ impl DecoderBuilder {
 }
 
 impl AnalyzerBuilder for DecoderBuilder {
-    fn build(&self, meta: &crate::ReplayMeta) -> Box<dyn Analyzer> {
-        let version = crate::version::Version::from_client_exe(&meta.clientVersionFromExe);
+    fn build(&self, meta: &crate::ReplayMeta) -> Result<Box<dyn Analyzer>, crate::ErrorKind> {
+        let version = meta.clientVersionFromExe.parse()?;
         let mut decoder = Decoder {
             silent: self.silent,
             output: self.path.as_ref().map(|path| {
@@ -35,12 +40,12 @@ impl AnalyzerBuilder for DecoderBuilder {
         if !self.no_meta {
             decoder.write(&serde_json::to_string(&meta).unwrap());
         }
-        Box::new(decoder)
+        Ok(Box::new(decoder))
     }
 }
 
 /// Enumerates voicelines which can be said in the game.
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum VoiceLine {
     IntelRequired,
     FairWinds,
@@ -67,7 +72,7 @@ pub enum VoiceLine {
 }
 
 /// Enumerates the ribbons which appear in the top-right
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
 pub enum Ribbon {
     PlaneShotDown,
     Incapacitation,
@@ -99,7 +104,7 @@ pub enum Ribbon {
     Unknown(i8),
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
 pub enum DeathCause {
     Secondaries,
     Artillery,
@@ -117,7 +122,7 @@ pub enum DeathCause {
 }
 
 /// Contains the information describing a player
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OnArenaStateReceivedPlayer {
     /// The username of this player
     pub username: String,
@@ -141,7 +146,7 @@ pub struct OnArenaStateReceivedPlayer {
 }
 
 /// Indicates that the given attacker has dealt damage
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DamageReceived {
     /// Ship ID of the aggressor
     aggressor: i32,
@@ -150,7 +155,7 @@ pub struct DamageReceived {
 }
 
 /// Sent to update the minimap display
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MinimapUpdate {
     /// The ship ID of the ship to update
     entity_id: i32,
@@ -171,7 +176,7 @@ pub struct MinimapUpdate {
 }
 
 /// Enumerates usable consumables in-game
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum Consumable {
     DamageControl,
     SpottingAircraft,
@@ -190,7 +195,7 @@ pub enum Consumable {
     Unknown(i8),
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum CameraMode {
     OverheadMap,
     FollowingShells,
@@ -203,7 +208,7 @@ pub enum CameraMode {
 
 /// Enumerates the "cruise states". See <https://github.com/lkolbly/wows-replays/issues/14#issuecomment-976784004>
 /// for more information.
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum CruiseState {
     /// Possible values for the throttle range from -1 for reverse to 4 for full power ahead.
     Throttle,
@@ -282,7 +287,7 @@ pub enum DecodedPacketPayload<'replay, 'argtype, 'rawpacket> {
     EntityMethod(&'rawpacket EntityMethodPacket<'argtype>),
     EntityProperty(&'rawpacket crate::packet2::EntityPropertyPacket<'argtype>),
     BasePlayerCreate(&'rawpacket crate::packet2::BasePlayerCreatePacket<'replay, 'argtype>),
-    CellPlayerCreate(&'rawpacket crate::packet2::CellPlayerCreatePacket<'replay>),
+    CellPlayerCreate(&'rawpacket crate::packet2::CellPlayerCreatePacket<'replay, 'argtype>),
     EntityEnter(&'rawpacket crate::packet2::EntityEnterPacket),
     EntityLeave(&'rawpacket crate::packet2::EntityLeavePacket),
     EntityCreate(&'rawpacket crate::packet2::EntityCreatePacket<'argtype>),
@@ -381,11 +386,41 @@ pub enum DecodedPacketPayload<'replay, 'argtype, 'rawpacket> {
     Map(&'rawpacket crate::packet2::MapPacket<'replay>),
     /// A string representation of the game version this replay is from.
     Version(String),
+    /// A Version packet whose version disagrees with the replay metadata, or can't be parsed.
+    /// The specs were picked using the metadata, so the packets may be decoded wrongly.
+    VersionMismatch {
+        /// The version in the packet
+        version: String,
+        /// The version in the replay metadata
+        replay_version: String,
+    },
     Camera(&'rawpacket crate::packet2::CameraPacket),
     /// Indicates a change in the current camera mode
     CameraMode(CameraMode),
     /// If true, indicates that the player has enabled the "free look" camera (by holding right click)
     CameraFreeLook(bool),
+    /// Gives or takes away the client's control of an entity
+    EntityControl(&'rawpacket crate::packet2::EntityControlPacket),
+    /// The ship ID of the player's own ship
+    OwnShip(u32),
+    /// The server's clock at the start of the game, in seconds
+    ServerTimestamp(f64),
+    /// Sent once per server tick, with the length of a tick in seconds
+    ServerTick(f64),
+    /// Unknown, only seen while the connection is being set up
+    InitFlag(u8),
+    /// Indicates that the connection has been set up
+    InitComplete,
+    /// Unknown, sent once for the player's avatar while the connection is being set up
+    AvatarInit(&'rawpacket crate::packet2::AvatarInitPacket),
+    /// Unknown, but appears alongside the camera packets
+    Camera18(&'rawpacket [f32; 13]),
+    /// Indicates the position of an object which doesn't move on its own, such as a smoke screen
+    EntityPosition(&'rawpacket crate::packet2::EntityPositionPacket),
+    /// Possibly indicates that the player has locked onto the given target
+    TargetLock(&'rawpacket crate::packet2::TargetLockPacket),
+    /// The last packet in the replay, whose contents are unknown
+    ReplayEnd(&'rawpacket [u8; 16]),
     /// This is a packet of unknown type
     Unknown(&'replay [u8]),
     /// This is a packet of known type, but which we were unable to parse
@@ -477,7 +512,6 @@ where
         version: &crate::version::Version,
         audit: bool,
         payload: &'rawpacket crate::packet2::PacketType<'replay, 'argtype>,
-        packet_type: u32,
     ) -> Self {
         match payload {
             PacketType::EntityMethod(ref em) => {
@@ -570,25 +604,43 @@ where
             PacketType::EntityLeave(e) => DecodedPacketPayload::EntityLeave(e),
             PacketType::EntityCreate(e) => DecodedPacketPayload::EntityCreate(e),
             PacketType::PropertyUpdate(update) => DecodedPacketPayload::PropertyUpdate(update),
-            PacketType::Version(version) => DecodedPacketPayload::Version(version.clone()),
-            PacketType::Unknown(u) => {
-                if packet_type == 0x18 {
-                    if audit
-                        && u != &[
-                            00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00,
-                            00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00, 00,
-                            00, 00, 00, 00, 00, 00, 0x80, 0xbf, 00, 00, 0x80, 0xbf, 00, 00, 0x80,
-                            0xbf,
-                        ]
-                    {
-                        DecodedPacketPayload::Audit(format!("Camera18 unexpected value!"))
-                    } else {
-                        DecodedPacketPayload::Unknown(&u)
+            PacketType::Version(packet_version) => {
+                match packet_version.parse::<crate::version::Version>() {
+                    Ok(v) if audit && !v.matches(version) => DecodedPacketPayload::Audit(format!(
+                        "Version: Packet version {} disagrees with replay metadata version {}",
+                        v, version
+                    )),
+                    Err(_) if audit => DecodedPacketPayload::Audit(format!(
+                        "Version: Unable to parse packet version {:?}",
+                        packet_version
+                    )),
+                    Ok(v) if v.matches(version) => {
+                        DecodedPacketPayload::Version(packet_version.clone())
                     }
+                    _ => DecodedPacketPayload::VersionMismatch {
+                        version: packet_version.clone(),
+                        replay_version: version.to_string(),
+                    },
+                }
+            }
+            PacketType::EntityControl(p) => DecodedPacketPayload::EntityControl(p),
+            PacketType::OwnShip(entity_id) => DecodedPacketPayload::OwnShip(*entity_id),
+            PacketType::ServerTimestamp(t) => DecodedPacketPayload::ServerTimestamp(*t),
+            PacketType::ServerTick(period) => DecodedPacketPayload::ServerTick(*period),
+            PacketType::InitFlag(flag) => DecodedPacketPayload::InitFlag(*flag),
+            PacketType::InitComplete => DecodedPacketPayload::InitComplete,
+            PacketType::AvatarInit(p) => DecodedPacketPayload::AvatarInit(p),
+            PacketType::Camera18(values) => {
+                if audit && (values[..10] != [0.0; 10] || values[10..] != [-1.0; 3]) {
+                    DecodedPacketPayload::Audit(format!("Camera18 unexpected value!"))
                 } else {
-                    DecodedPacketPayload::Unknown(&u)
+                    DecodedPacketPayload::Camera18(values)
                 }
             }
+            PacketType::EntityPosition(p) => DecodedPacketPayload::EntityPosition(p),
+            PacketType::TargetLock(p) => DecodedPacketPayload::TargetLock(p),
+            PacketType::ReplayEnd(contents) => DecodedPacketPayload::ReplayEnd(contents),
+            PacketType::Unknown(u) => DecodedPacketPayload::Unknown(&u),
             PacketType::Invalid(u) => DecodedPacketPayload::Invalid(&u),
         }
     }
@@ -735,47 +787,54 @@ where
                         }
                     }
 
-                    let keys: HashMap<&'static str, i64> = if version
-                        .is_at_least(&crate::version::Version::from_client_exe("0,10,9,0"))
-                    {
-                        // 0.10.9 inserted things at 0x1 and 0x1F
-                        let mut h = HashMap::new();
-                        h.insert("avatarid", 0x2);
-                        h.insert("clan", 0x6);
-                        h.insert("health", 0x17);
-                        h.insert("username", 0x18);
-                        h.insert("shipid", 0x20);
-                        h.insert("playerid", 0x21);
-                        h.insert("playeravatarid", 0x22);
-                        h.insert("team", 0x23);
-                        h
-                    } else if version
-                        .is_at_least(&crate::version::Version::from_client_exe("0,10,7,0"))
-                    {
-                        // 0.10.7
-                        let mut h = HashMap::new();
-                        h.insert("avatarid", 0x1);
-                        h.insert("clan", 0x5);
-                        h.insert("health", 0x16);
-                        h.insert("username", 0x17);
-                        h.insert("shipid", 0x1e);
-                        h.insert("playerid", 0x1f);
-                        h.insert("playeravatarid", 0x20);
-                        h.insert("team", 0x21);
-                        h
-                    } else {
-                        // 0.10.6 and earlier
-                        let mut h = HashMap::new();
-                        h.insert("avatarid", 0x1);
-                        h.insert("clan", 0x5);
-                        h.insert("health", 0x15);
-                        h.insert("username", 0x16);
-                        h.insert("shipid", 0x1d);
-                        h.insert("playerid", 0x1e);
-                        h.insert("playeravatarid", 0x1f);
-                        h.insert("team", 0x20);
-                        h
-                    };
+                    let keys: HashMap<&'static str, i64> =
+                        if version.is_at_least(&crate::version::Version {
+                            major: 0,
+                            minor: 10,
+                            patch: 9,
+                            build: 0,
+                        }) {
+                            // 0.10.9 inserted things at 0x1 and 0x1F
+                            let mut h = HashMap::new();
+                            h.insert("avatarid", 0x2);
+                            h.insert("clan", 0x6);
+                            h.insert("health", 0x17);
+                            h.insert("username", 0x18);
+                            h.insert("shipid", 0x20);
+                            h.insert("playerid", 0x21);
+                            h.insert("playeravatarid", 0x22);
+                            h.insert("team", 0x23);
+                            h
+                        } else if version.is_at_least(&crate::version::Version {
+                            major: 0,
+                            minor: 10,
+                            patch: 7,
+                            build: 0,
+                        }) {
+                            // 0.10.7
+                            let mut h = HashMap::new();
+                            h.insert("avatarid", 0x1);
+                            h.insert("clan", 0x5);
+                            h.insert("health", 0x16);
+                            h.insert("username", 0x17);
+                            h.insert("shipid", 0x1e);
+                            h.insert("playerid", 0x1f);
+                            h.insert("playeravatarid", 0x20);
+                            h.insert("team", 0x21);
+                            h
+                        } else {
+                            // 0.10.6 and earlier
+                            let mut h = HashMap::new();
+                            h.insert("avatarid", 0x1);
+                            h.insert("clan", 0x5);
+                            h.insert("health", 0x15);
+                            h.insert("username", 0x16);
+                            h.insert("shipid", 0x1d);
+                            h.insert("playerid", 0x1e);
+                            h.insert("playeravatarid", 0x1f);
+                            h.insert("team", 0x20);
+                            h
+                        };
 
                     /*
                     1: Player ID
@@ -1111,17 +1170,231 @@ where
         let decoded = Self {
             clock: packet.clock,
             packet_type: packet.packet_type,
-            payload: DecodedPacketPayload::from(
-                version,
-                audit,
-                &packet.payload,
-                packet.packet_type,
-            ),
+            payload: DecodedPacketPayload::from(version, audit, &packet.payload),
         };
         decoded
     }
 }
 
+/// An owned version of [DecodedPacketPayload], which can be stored, sent between threads and
+/// deserialized. See [DecodedPacketPayload] for what each variant means.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OwnedDecodedPacketPayload {
+    Chat {
+        entity_id: u32,
+        sender_id: i32,
+        audience: String,
+        message: String,
+    },
+    VoiceLine {
+        sender_id: i32,
+        is_global: bool,
+        message: VoiceLine,
+    },
+    Ribbon(Ribbon),
+    Position(crate::packet2::PositionPacket),
+    PlayerOrientation(crate::packet2::PlayerOrientationPacket),
+    DamageStat(Vec<((i64, i64), (i64, f64))>),
+    ShipDestroyed {
+        killer: i32,
+        victim: i32,
+        cause: DeathCause,
+    },
+    EntityMethod(OwnedEntityMethodPacket),
+    EntityProperty(OwnedEntityPropertyPacket),
+    BasePlayerCreate(OwnedBasePlayerCreatePacket),
+    CellPlayerCreate(OwnedCellPlayerCreatePacket),
+    EntityEnter(crate::packet2::EntityEnterPacket),
+    EntityLeave(crate::packet2::EntityLeavePacket),
+    EntityCreate(OwnedEntityCreatePacket),
+    OnArenaStateReceived {
+        arg0: i64,
+        arg1: i8,
+        arg2: HashMap<i64, Vec<Option<HashMap<String, String>>>>,
+        players: Vec<OnArenaStateReceivedPlayer>,
+    },
+    CheckPing(u64),
+    DamageReceived {
+        victim: u32,
+        aggressors: Vec<DamageReceived>,
+    },
+    MinimapUpdate {
+        updates: Vec<MinimapUpdate>,
+        arg1: Vec<OwnedArgValue>,
+    },
+    PropertyUpdate(OwnedPropertyUpdatePacket),
+    BattleEnd {
+        winning_team: i8,
+        unknown: u8,
+    },
+    Consumable {
+        entity: u32,
+        consumable: Consumable,
+        duration: f32,
+    },
+    CruiseState {
+        state: CruiseState,
+        value: i32,
+    },
+    Map(OwnedMapPacket),
+    Version(String),
+    VersionMismatch {
+        version: String,
+        replay_version: String,
+    },
+    Camera(crate::packet2::CameraPacket),
+    CameraMode(CameraMode),
+    CameraFreeLook(bool),
+    EntityControl(crate::packet2::EntityControlPacket),
+    OwnShip(u32),
+    ServerTimestamp(f64),
+    ServerTick(f64),
+    InitFlag(u8),
+    InitComplete,
+    AvatarInit(crate::packet2::AvatarInitPacket),
+    Camera18([f32; 13]),
+    EntityPosition(crate::packet2::EntityPositionPacket),
+    TargetLock(crate::packet2::TargetLockPacket),
+    ReplayEnd([u8; 16]),
+    Unknown(Vec<u8>),
+    Invalid(OwnedInvalidPacket),
+    Audit(String),
+}
+
+impl<'replay, 'argtype, 'rawpacket> DecodedPacketPayload<'replay, 'argtype, 'rawpacket> {
+    /// Copies everything borrowed from the replay, the raw packet and the entity specs into
+    /// an [OwnedDecodedPacketPayload]
+    pub fn into_owned(self) -> OwnedDecodedPacketPayload {
+        match self {
+            Self::Chat {
+                entity_id,
+                sender_id,
+                audience,
+                message,
+            } => OwnedDecodedPacketPayload::Chat {
+                entity_id,
+                sender_id,
+                audience: audience.to_string(),
+                message: message.to_string(),
+            },
+            Self::VoiceLine {
+                sender_id,
+                is_global,
+                message,
+            } => OwnedDecodedPacketPayload::VoiceLine {
+                sender_id,
+                is_global,
+                message,
+            },
+            Self::Ribbon(ribbon) => OwnedDecodedPacketPayload::Ribbon(ribbon),
+            Self::Position(p) => OwnedDecodedPacketPayload::Position(p),
+            Self::PlayerOrientation(p) => OwnedDecodedPacketPayload::PlayerOrientation(p),
+            Self::DamageStat(stats) => OwnedDecodedPacketPayload::DamageStat(stats),
+            Self::ShipDestroyed {
+                killer,
+                victim,
+                cause,
+            } => OwnedDecodedPacketPayload::ShipDestroyed {
+                killer,
+                victim,
+                cause,
+            },
+            Self::EntityMethod(p) => OwnedDecodedPacketPayload::EntityMethod(p.into()),
+            Self::EntityProperty(p) => OwnedDecodedPacketPayload::EntityProperty(p.into()),
+            Self::BasePlayerCreate(p) => OwnedDecodedPacketPayload::BasePlayerCreate(p.into()),
+            Self::CellPlayerCreate(p) => OwnedDecodedPacketPayload::CellPlayerCreate(p.into()),
+            Self::EntityEnter(p) => OwnedDecodedPacketPayload::EntityEnter(p.clone()),
+            Self::EntityLeave(p) => OwnedDecodedPacketPayload::EntityLeave(p.clone()),
+            Self::EntityCreate(p) => OwnedDecodedPacketPayload::EntityCreate(p.into()),
+            Self::OnArenaStateReceived {
+                arg0,
+                arg1,
+                arg2,
+                players,
+            } => OwnedDecodedPacketPayload::OnArenaStateReceived {
+                arg0,
+                arg1,
+                arg2,
+                players,
+            },
+            Self::CheckPing(ping) => OwnedDecodedPacketPayload::CheckPing(ping),
+            Self::DamageReceived { victim, aggressors } => {
+                OwnedDecodedPacketPayload::DamageReceived { victim, aggressors }
+            }
+            Self::MinimapUpdate { updates, arg1 } => OwnedDecodedPacketPayload::MinimapUpdate {
+                updates,
+                arg1: arg1.iter().map(|arg| arg.into()).collect(),
+            },
+            Self::PropertyUpdate(p) => OwnedDecodedPacketPayload::PropertyUpdate(p.into()),
+            Self::BattleEnd {
+                winning_team,
+                unknown,
+            } => OwnedDecodedPacketPayload::BattleEnd {
+                winning_team,
+                unknown,
+            },
+            Self::Consumable {
+                entity,
+                consumable,
+                duration,
+            } => OwnedDecodedPacketPayload::Consumable {
+                entity,
+                consumable,
+                duration,
+            },
+            Self::CruiseState { state, value } => {
+                OwnedDecodedPacketPayload::CruiseState { state, value }
+            }
+            Self::Map(p) => OwnedDecodedPacketPayload::Map(p.into()),
+            Self::Version(version) => OwnedDecodedPacketPayload::Version(version),
+            Self::VersionMismatch {
+                version,
+                replay_version,
+            } => OwnedDecodedPacketPayload::VersionMismatch {
+                version,
+                replay_version,
+            },
+            Self::Camera(p) => OwnedDecodedPacketPayload::Camera(p.clone()),
+            Self::CameraMode(mode) => OwnedDecodedPacketPayload::CameraMode(mode),
+            Self::CameraFreeLook(freelook) => OwnedDecodedPacketPayload::CameraFreeLook(freelook),
+            Self::EntityControl(p) => OwnedDecodedPacketPayload::EntityControl(p.clone()),
+            Self::OwnShip(entity_id) => OwnedDecodedPacketPayload::OwnShip(entity_id),
+            Self::ServerTimestamp(t) => OwnedDecodedPacketPayload::ServerTimestamp(t),
+            Self::ServerTick(period) => OwnedDecodedPacketPayload::ServerTick(period),
+            Self::InitFlag(flag) => OwnedDecodedPacketPayload::InitFlag(flag),
+            Self::InitComplete => OwnedDecodedPacketPayload::InitComplete,
+            Self::AvatarInit(p) => OwnedDecodedPacketPayload::AvatarInit(p.clone()),
+            Self::Camera18(values) => OwnedDecodedPacketPayload::Camera18(*values),
+            Self::EntityPosition(p) => OwnedDecodedPacketPayload::EntityPosition(p.clone()),
+            Self::TargetLock(p) => OwnedDecodedPacketPayload::TargetLock(p.clone()),
+            Self::ReplayEnd(contents) => OwnedDecodedPacketPayload::ReplayEnd(*contents),
+            Self::Unknown(data) => OwnedDecodedPacketPayload::Unknown(data.to_vec()),
+            Self::Invalid(p) => OwnedDecodedPacketPayload::Invalid(p.into()),
+            Self::Audit(audit) => OwnedDecodedPacketPayload::Audit(audit),
+        }
+    }
+}
+
+/// An owned version of [DecodedPacket], which can be stored, sent between threads and
+/// deserialized.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedDecodedPacket {
+    pub packet_type: u32,
+    pub clock: f32,
+    pub payload: OwnedDecodedPacketPayload,
+}
+
+impl<'replay, 'argtype, 'rawpacket> DecodedPacket<'replay, 'argtype, 'rawpacket> {
+    /// Copies everything borrowed into an [OwnedDecodedPacket]
+    pub fn into_owned(self) -> OwnedDecodedPacket {
+        OwnedDecodedPacket {
+            packet_type: self.packet_type,
+            clock: self.clock,
+            payload: self.payload.into_owned(),
+        }
+    }
+}
+
 struct Decoder {
     silent: bool,
     output: Option<Box<dyn std::io::Write>>,
@@ -1163,3 +1436,76 @@ impl Analyzer for Decoder {
         self.write(&encoded);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::version::Version;
+
+    #[test]
+    fn version_mismatch_audit() {
+        let version = "0,10,3,3747819".parse::<Version>().unwrap();
+        let decode = |packet_version: &str, audit: bool| {
+            let payload = PacketType::Version(packet_version.to_string());
+            match DecodedPacketPayload::from(&version, audit, &payload) {
+                DecodedPacketPayload::Version(_) => None,
+                DecodedPacketPayload::Audit(audit) => Some(audit),
+                DecodedPacketPayload::VersionMismatch {
+                    version,
+                    replay_version,
+                } => Some(format!("{} {}", version, replay_version)),
+                payload => panic!("{:?}", payload),
+            }
+        };
+        // The Version packet has no build number
+        assert_eq!(decode("0,10,3,0", true), None);
+        assert!(decode("0,10,4,0", true).unwrap().contains("0.10.4.0"));
+        assert!(decode("garbage", true).is_some());
+
+        // Mismatches are reported without auditing too
+        assert_eq!(decode("0,10,3,0", false), None);
+        assert_eq!(
+            decode("0,10,4,0", false),
+            Some("0,10,4,0 0.10.3.3747819".to_string())
+        );
+        assert!(decode("garbage", false).is_some());
+    }
+
+    #[test]
+    fn camera18() {
+        let version = "0,10,3,3747819".parse::<Version>().unwrap();
+        let mut values = [0.0; 13];
+        values[10..].copy_from_slice(&[-1.0; 3]);
+        let expected = PacketType::Camera18(values);
+        assert!(matches!(
+            DecodedPacketPayload::from(&version, true, &expected),
+            DecodedPacketPayload::Camera18(_)
+        ));
+
+        // Unexpected values are only flagged when auditing
+        let odd = [1.5; 13];
+        let payload = PacketType::Camera18(odd);
+        assert!(matches!(
+            DecodedPacketPayload::from(&version, true, &payload),
+            DecodedPacketPayload::Audit(_)
+        ));
+        match DecodedPacketPayload::from(&version, false, &payload) {
+            DecodedPacketPayload::Camera18(decoded) => assert_eq!(decoded, &odd),
+            payload => panic!("{:?}", payload),
+        }
+    }
+
+    #[test]
+    fn bad_meta_version() {
+        let replay = crate::ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let mut meta = replay.meta;
+        meta.clientVersionFromExe = "0,10,three".to_string();
+        assert!(matches!(
+            DecoderBuilder::new(true, true, None).build(&meta),
+            Err(crate::ErrorKind::BadVersion { .. })
+        ));
+    }
+}
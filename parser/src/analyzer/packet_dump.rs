@@ -10,8 +10,8 @@ impl PacketDumpBuilder {
 }
 
 impl AnalyzerBuilder for PacketDumpBuilder {
-    fn build(&self, _: &crate::ReplayMeta) -> Box<dyn Analyzer> {
-        Box::new(PacketDump {})
+    fn build(&self, _: &crate::ReplayMeta) -> Result<Box<dyn Analyzer>, crate::ErrorKind> {
+        Ok(Box::new(PacketDump {}))
     }
 }
 
@@ -11,7 +11,7 @@ impl SummaryBuilder {
 }
 
 impl AnalyzerBuilder for SummaryBuilder {
-    fn build(&self, meta: &crate::ReplayMeta) -> Box<dyn Analyzer> {
+    fn build(&self, meta: &crate::ReplayMeta) -> Result<Box<dyn Analyzer>, crate::ErrorKind> {
         println!("Username: {}", meta.playerName);
         println!("Date/time: {}", meta.dateTime);
         println!("Map: {}", meta.mapDisplayName);
@@ -20,10 +20,10 @@ impl AnalyzerBuilder for SummaryBuilder {
         println!("Game version: {}", meta.clientVersionFromExe);
         println!();
 
-        Box::new(Summary {
+        Ok(Box::new(Summary {
             ribbons: HashMap::new(),
             damage: HashMap::new(),
-        })
+        }))
     }
 }
 
@@ -81,6 +81,7 @@ impl Analyzer for Summary {
                         entity_id: _entity_id,
                         method,
                         args,
+                        ..
                     }),
                 ..
             } => {
@@ -36,18 +36,18 @@ impl SurveyBuilder {
 }
 
 impl AnalyzerBuilder for SurveyBuilder {
-    fn build(&self, meta: &crate::ReplayMeta) -> Box<dyn Analyzer> {
-        let version = crate::version::Version::from_client_exe(&meta.clientVersionFromExe);
+    fn build(&self, meta: &crate::ReplayMeta) -> Result<Box<dyn Analyzer>, crate::ErrorKind> {
+        let version = meta.clientVersionFromExe.parse()?;
         {
             let mut stats: RefMut<_> = self.stats.borrow_mut();
             stats.date_time = meta.dateTime.clone();
         }
-        Box::new(Survey {
+        Ok(Box::new(Survey {
             skip_decoder: self.skip_decoder,
-            decoder: decoder::DecoderBuilder::new(true, true, None).build(meta),
+            decoder: decoder::DecoderBuilder::new(true, true, None).build(meta)?,
             stats: self.stats.clone(),
             version: version,
-        })
+        }))
     }
 }
 
@@ -0,0 +1,969 @@
+//! Removes player identities from a replay so that it can be shared publicly.
+//!
+//! Usernames, clan tags and account IDs are each replaced by a pseudonym (e.g. `Player3`),
+//! consistently across the metadata, the JSON header blocks, RPC arguments, entity
+//! properties, pickled blobs and chat messages. Everything else in the packet stream is left byte-for-byte intact.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::analyzer::decoder::{DecodedPacket, DecodedPacketPayload};
+use crate::error::*;
+use crate::nested_property_path::{PropertyNesting, UpdateAction};
+use crate::packet2::{
+    BasePlayerCreatePacket, CellPlayerCreatePacket, EntityCreatePacket, EntityMethodPacket,
+    EntityPropertyPacket, Packet, PacketProcessor, PacketType, Parser, PropertyUpdatePacket,
+};
+use crate::rpc::entitydefs::{EntitySpec, Flags};
+use crate::rpc::typedefs::ArgValue;
+use crate::version::Version;
+use crate::{ReplayFile, ReplayWriter};
+
+/// The table of substitutions to apply to a replay.
+#[derive(Debug, Default)]
+pub struct Anonymizer {
+    /// Maps usernames to their pseudonyms
+    pub names: HashMap<String, String>,
+    /// Maps clan tags to their pseudonyms
+    pub clans: HashMap<String, String>,
+    /// Maps account IDs to their pseudonymous IDs
+    pub account_ids: HashMap<i64, i64>,
+}
+
+/// Collects the players from the `onArenaStateReceived` player list
+struct PlayerCollector {
+    version: Version,
+    players: Vec<(String, String)>,
+}
+
+impl PacketProcessor for PlayerCollector {
+    fn process(&mut self, packet: Packet<'_, '_>) {
+        match &packet.payload {
+            PacketType::EntityMethod(method) if method.method == "onArenaStateReceived" => {}
+            _ => return,
+        }
+        let decoded = DecodedPacket::from(&self.version, false, &packet);
+        if let DecodedPacketPayload::OnArenaStateReceived { players, .. } = decoded.payload {
+            for player in players.into_iter() {
+                self.players.push((player.username, player.clan));
+            }
+        }
+    }
+}
+
+impl Anonymizer {
+    /// Builds the substitution table from the players listed in the replay metadata and
+    /// in the arena state sent at the start of the battle.
+    pub fn new(replay: &ReplayFile, specs: &[EntitySpec]) -> Result<Anonymizer, ErrorKind> {
+        let mut anonymizer = Anonymizer::default();
+        for vehicle in replay.meta.vehicles.iter() {
+            anonymizer.add_name(&vehicle.name);
+            anonymizer.add_account_id(vehicle.id);
+        }
+        anonymizer.add_name(&replay.meta.playerName);
+
+        let mut collector = PlayerCollector {
+            version: replay.meta.clientVersionFromExe.parse()?,
+            players: vec![],
+        };
+        Parser::new(specs).parse_packets(&replay.packet_data, &mut collector)?;
+        for (username, clan) in collector.players.iter() {
+            anonymizer.add_name(username);
+            anonymizer.add_clan(clan);
+        }
+        Ok(anonymizer)
+    }
+
+    fn add_name(&mut self, name: &str) {
+        if !name.is_empty() && !self.names.contains_key(name) {
+            let pseudonym = format!("Player{}", self.names.len() + 1);
+            self.names.insert(name.to_string(), pseudonym);
+        }
+    }
+
+    fn add_clan(&mut self, clan: &str) {
+        if !clan.is_empty() && !self.clans.contains_key(clan) {
+            let pseudonym = format!("CLAN{}", self.clans.len() + 1);
+            self.clans.insert(clan.to_string(), pseudonym);
+        }
+    }
+
+    fn add_account_id(&mut self, id: i64) {
+        // Bots have an account ID of zero
+        if id != 0 && !self.account_ids.contains_key(&id) {
+            let pseudonym = self.account_ids.len() as i64 + 1;
+            self.account_ids.insert(id, pseudonym);
+        }
+    }
+
+    /// Returns the pseudonym for a string which is exactly a username or clan tag
+    fn replacement(&self, s: &str) -> Option<&str> {
+        self.names
+            .get(s)
+            .or_else(|| self.clans.get(s))
+            .map(|s| s.as_str())
+    }
+
+    /// Returns the pseudonymous ID for an account ID
+    fn replace_id(&self, id: i64) -> Option<i64> {
+        self.account_ids.get(&id).copied()
+    }
+
+    fn replace_bytes(&self, data: &[u8]) -> Option<&str> {
+        std::str::from_utf8(data)
+            .ok()
+            .and_then(|s| self.replacement(s))
+    }
+
+    /// Replaces every username mentioned in a chat message. Names only match as whole
+    /// tokens, so a short name like "GG" doesn't rewrite the inside of other words.
+    fn rewrite_chat(&self, message: &str) -> String {
+        // Usernames are made up of letters, digits and underscores
+        let is_name_char = |c: char| c.is_alphanumeric() || c == '_';
+
+        let mut names: Vec<_> = self.names.iter().collect();
+        names.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+
+        let mut out = String::new();
+        let mut rest = message;
+        let mut at_boundary = true;
+        'outer: while let Some(c) = rest.chars().next() {
+            if at_boundary {
+                for (name, pseudonym) in names.iter() {
+                    if !rest.starts_with(name.as_str()) {
+                        continue;
+                    }
+                    let after = &rest[name.len()..];
+                    if !matches!(after.chars().next(), Some(c) if is_name_char(c)) {
+                        out.push_str(pseudonym);
+                        rest = after;
+                        at_boundary = false;
+                        continue 'outer;
+                    }
+                }
+            }
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+            at_boundary = !is_name_char(c);
+        }
+        out
+    }
+
+    /// Rewrites the strings and account IDs in a protocol 2 pickle, leaving all other
+    /// opcodes untouched. Returns None if nothing needed to change.
+    fn rewrite_pickle(&self, pickle: &[u8]) -> Result<Option<Vec<u8>>, ErrorKind> {
+        let truncated = || ErrorKind::ParsingFailure("Truncated pickle".to_string());
+        let read_u32 = |data: &[u8]| -> Result<usize, ErrorKind> {
+            let bytes = data.get(..4).ok_or_else(truncated)?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        };
+        let line_len = |data: &[u8]| -> Result<usize, ErrorKind> {
+            data.iter()
+                .position(|b| *b == b'\n')
+                .map(|n| n + 1)
+                .ok_or_else(truncated)
+        };
+
+        let mut out = Vec::with_capacity(pickle.len());
+        let mut changed = false;
+        let mut i = 0;
+        while i < pickle.len() {
+            let opcode = pickle[i];
+            let rest = &pickle[i + 1..];
+            let arg_len = match opcode {
+                // SHORT_BINSTRING, BINSTRING, BINUNICODE
+                b'U' | b'T' | b'X' => {
+                    let (prefix, len) = if opcode == b'U' {
+                        (1, *rest.first().ok_or_else(truncated)? as usize)
+                    } else {
+                        (4, read_u32(rest)?)
+                    };
+                    let data = rest.get(prefix..prefix + len).ok_or_else(truncated)?;
+                    if let Some(pseudonym) = self.replace_bytes(data) {
+                        let pseudonym = pseudonym.as_bytes();
+                        if opcode == b'U' && pseudonym.len() < 0x100 {
+                            out.push(b'U');
+                            out.push(pseudonym.len() as u8);
+                        } else {
+                            out.push(if opcode == b'X' { b'X' } else { b'T' });
+                            out.extend_from_slice(&(pseudonym.len() as u32).to_le_bytes());
+                        }
+                        out.extend_from_slice(pseudonym);
+                        changed = true;
+                        i += 1 + prefix + len;
+                        continue;
+                    }
+                    prefix + len
+                }
+                // BININT
+                b'J' => {
+                    let value = read_u32(rest)? as u32 as i32;
+                    if let Some(pseudonym) = self.replace_id(value as i64) {
+                        out.push(b'J');
+                        out.extend_from_slice(&(pseudonym as i32).to_le_bytes());
+                        changed = true;
+                        i += 5;
+                        continue;
+                    }
+                    4
+                }
+                // LONG1, LONG4, which hold little-endian two's complement integers
+                0x8a | 0x8b => {
+                    let (prefix, len) = if opcode == 0x8a {
+                        (1, *rest.first().ok_or_else(truncated)? as usize)
+                    } else {
+                        (4, read_u32(rest)?)
+                    };
+                    let data = rest.get(prefix..prefix + len).ok_or_else(truncated)?;
+                    if len <= 8 {
+                        let negative = matches!(data.last(), Some(b) if b & 0x80 != 0);
+                        let mut bytes = [if negative { 0xff } else { 0 }; 8];
+                        bytes[..len].copy_from_slice(data);
+                        if let Some(pseudonym) = self.replace_id(i64::from_le_bytes(bytes)) {
+                            out.extend_from_slice(&[0x8a, 8]);
+                            out.extend_from_slice(&pseudonym.to_le_bytes());
+                            changed = true;
+                            i += 1 + prefix + len;
+                            continue;
+                        }
+                    }
+                    prefix + len
+                }
+                // BININT1, BINGET, BINPUT, PROTO, EXT1
+                b'K' | b'h' | b'q' | 0x80 | 0x82 => 1,
+                // BININT2, EXT2
+                b'M' | 0x83 => 2,
+                // LONG_BINGET, LONG_BINPUT, EXT4
+                b'j' | b'r' | 0x84 => 4,
+                // BINFLOAT
+                b'G' => 8,
+                // INT, LONG, FLOAT, STRING, UNICODE, GET, PUT, PERSID
+                b'I' | b'L' | b'F' | b'S' | b'V' | b'g' | b'p' | b'P' => line_len(rest)?,
+                // GLOBAL, INST
+                b'c' | b'i' => {
+                    let first = line_len(rest)?;
+                    first + line_len(&rest[first..])?
+                }
+                b'(' | b'.' | b'0' | b'1' | b'2' | b'N' | 0x88 | 0x89 | b'}' | b']' | b')'
+                | b't' | 0x85 | 0x86 | 0x87 | b'a' | b'e' | b's' | b'u' | b'd' | b'l' | b'b'
+                | b'R' | 0x81 | b'o' | b'Q' => 0,
+                _ => {
+                    return Err(ErrorKind::ParsingFailure(format!(
+                        "Unsupported pickle opcode 0x{:02x}",
+                        opcode
+                    )));
+                }
+            };
+            let end = i + 1 + arg_len;
+            out.extend_from_slice(pickle.get(i..end).ok_or_else(truncated)?);
+            i = end;
+        }
+        Ok(if changed { Some(out) } else { None })
+    }
+
+    /// Rewrites an RPC value in place, returning whether anything changed.
+    fn rewrite_value(&self, value: &mut ArgValue, is_chat: bool) -> Result<bool, ErrorKind> {
+        let mut changed = false;
+        match value {
+            ArgValue::String(s) | ArgValue::UnicodeString(s) => {
+                if let Some(pseudonym) = self.replace_bytes(s) {
+                    *s = pseudonym.as_bytes().to_vec();
+                    changed = true;
+                } else if is_chat {
+                    if let Ok(message) = std::str::from_utf8(s) {
+                        let rewritten = self.rewrite_chat(message);
+                        changed = rewritten != message;
+                        *s = rewritten.into_bytes();
+                    }
+                }
+            }
+            // Account IDs don't fit in fewer than 32 bits, and their pseudonyms are smaller
+            ArgValue::Int32(n) => {
+                if let Some(pseudonym) = self.replace_id(*n as i64) {
+                    *n = pseudonym as i32;
+                    changed = true;
+                }
+            }
+            ArgValue::Uint32(n) => {
+                if let Some(pseudonym) = self.replace_id(*n as i64) {
+                    *n = pseudonym as u32;
+                    changed = true;
+                }
+            }
+            ArgValue::Int64(n) => {
+                if let Some(pseudonym) = self.replace_id(*n) {
+                    *n = pseudonym;
+                    changed = true;
+                }
+            }
+            ArgValue::Uint64(n) => {
+                if let Some(pseudonym) = self.replace_id(*n as i64) {
+                    *n = pseudonym as u64;
+                    changed = true;
+                }
+            }
+            // The game pickles its blobs with protocol 2
+            ArgValue::Blob(blob) if blob.starts_with(&[0x80, 2]) => {
+                if let Some(rewritten) = self.rewrite_pickle(blob)? {
+                    *blob = rewritten;
+                    changed = true;
+                }
+            }
+            ArgValue::Array(values) | ArgValue::Tuple(values) => {
+                for value in values.iter_mut() {
+                    changed |= self.rewrite_value(value, is_chat)?;
+                }
+            }
+            ArgValue::FixedDict(dict) | ArgValue::NullableFixedDict(Some(dict)) => {
+                for value in dict.values_mut() {
+                    changed |= self.rewrite_value(value, is_chat)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(changed)
+    }
+
+    /// Rewrites a JSON header block, including object keys (the battle results are keyed
+    /// by account ID).
+    pub fn rewrite_json(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::String(s) => {
+                if let Some(pseudonym) = self.replacement(s) {
+                    *s = pseudonym.to_string();
+                }
+            }
+            serde_json::Value::Number(n) => {
+                if let Some(id) = n.as_i64().and_then(|n| self.account_ids.get(&n)) {
+                    *value = (*id).into();
+                }
+            }
+            serde_json::Value::Array(values) => {
+                for value in values.iter_mut() {
+                    self.rewrite_json(value);
+                }
+            }
+            serde_json::Value::Object(map) => {
+                for (key, mut value) in std::mem::take(map).into_iter() {
+                    self.rewrite_json(&mut value);
+                    let key = match self.replacement(&key) {
+                        Some(pseudonym) => pseudonym.to_string(),
+                        None => match key
+                            .parse::<i64>()
+                            .ok()
+                            .and_then(|n| self.account_ids.get(&n))
+                        {
+                            Some(id) => id.to_string(),
+                            None => key,
+                        },
+                    };
+                    map.insert(key, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Rewrites each value of a property map, returning the new map if anything changed
+    fn rewrite_props<'argtype>(
+        &self,
+        props: &HashMap<&'argtype str, ArgValue<'argtype>>,
+    ) -> Result<Option<HashMap<&'argtype str, ArgValue<'argtype>>>, ErrorKind> {
+        let mut props = props.clone();
+        let mut changed = false;
+        for value in props.values_mut() {
+            changed |= self.rewrite_value(value, false)?;
+        }
+        Ok(if changed { Some(props) } else { None })
+    }
+
+    /// Returns the anonymized payload of a packet, or None if it can be copied as-is. The
+    /// `encoder` must have parsed the anonymized packets before this one, since encoding
+    /// depends on the state of the world.
+    fn rewrite_payload(
+        &self,
+        encoder: &Parser,
+        specs: &[EntitySpec],
+        payload: &PacketType<'_, '_>,
+    ) -> Result<Option<Vec<u8>>, ErrorKind> {
+        match payload {
+            PacketType::EntityMethod(p) => {
+                let mut args = p.args.clone();
+                let is_chat = p.method == "onChatMessage";
+                let mut changed = false;
+                for arg in args.iter_mut() {
+                    changed |= self.rewrite_value(arg, is_chat)?;
+                }
+                if !changed {
+                    return Ok(None);
+                }
+                let packet = EntityMethodPacket {
+                    entity_id: p.entity_id,
+                    method: p.method,
+                    component: p.component,
+                    direction: p.direction,
+                    args,
+                };
+                encoder
+                    .encode_payload(&PacketType::EntityMethod(packet))
+                    .map(Some)
+            }
+            PacketType::EntityProperty(p) => {
+                let mut value = p.value.clone();
+                if !self.rewrite_value(&mut value, false)? {
+                    return Ok(None);
+                }
+                let packet = EntityPropertyPacket {
+                    entity_id: p.entity_id,
+                    property: p.property,
+                    value,
+                };
+                encoder
+                    .encode_payload(&PacketType::EntityProperty(packet))
+                    .map(Some)
+            }
+            PacketType::PropertyUpdate(p) => {
+                let mut action = p.update_cmd.action.clone();
+                let changed = match &mut action {
+                    UpdateAction::SetKey { value, .. } | UpdateAction::SetElement { value, .. } => {
+                        self.rewrite_value(value, false)?
+                    }
+                    UpdateAction::SetRange { values, .. } => {
+                        let mut changed = false;
+                        for value in values.iter_mut() {
+                            changed |= self.rewrite_value(value, false)?;
+                        }
+                        changed
+                    }
+                    UpdateAction::RemoveRange { .. } => false,
+                };
+                if !changed {
+                    return Ok(None);
+                }
+                let packet = PropertyUpdatePacket {
+                    entity_id: p.entity_id,
+                    property: p.property,
+                    update_cmd: PropertyNesting {
+                        levels: p.update_cmd.levels.clone(),
+                        action,
+                    },
+                };
+                encoder
+                    .encode_payload(&PacketType::PropertyUpdate(packet))
+                    .map(Some)
+            }
+            PacketType::EntityCreate(p) => {
+                let props = match self.rewrite_props(&p.props)? {
+                    Some(props) => props,
+                    None => return Ok(None),
+                };
+                let packet = EntityCreatePacket {
+                    entity_id: p.entity_id,
+                    entity_type: p.entity_type,
+                    space_id: p.space_id,
+                    vehicle_id: p.vehicle_id,
+                    position: p.position.clone(),
+                    rotation: p.rotation.clone(),
+                    state_length: p.state_length,
+                    props,
+                };
+                encoder
+                    .encode_payload(&PacketType::EntityCreate(packet))
+                    .map(Some)
+            }
+            PacketType::CellPlayerCreate(p) => {
+                let props = match self.rewrite_props(&p.props)? {
+                    Some(props) => props,
+                    None => return Ok(None),
+                };
+                let packet = CellPlayerCreatePacket {
+                    entity_id: p.entity_id,
+                    space_id: p.space_id,
+                    vehicle_id: p.vehicle_id,
+                    position: p.position.clone(),
+                    rotation: p.rotation.clone(),
+                    props,
+                    value: p.value,
+                };
+                encoder
+                    .encode_payload(&PacketType::CellPlayerCreate(packet))
+                    .map(Some)
+            }
+            PacketType::BasePlayerCreate(p) => {
+                // The part of the state which wasn't decoded could mention a player
+                if let Some(e) = &p.error {
+                    return Err(ErrorKind::ParsingFailure(format!(
+                        "Can't anonymize the state of entity {}: {:?}",
+                        p.entity_id, e
+                    )));
+                }
+                let props = match self.rewrite_props(&p.props)? {
+                    Some(props) => props,
+                    None => return Ok(None),
+                };
+                let spec = specs
+                    .iter()
+                    .find(|spec| spec.name == p.entity_type)
+                    .ok_or_else(|| {
+                        ErrorKind::ParsingFailure(format!("Unknown entity type {}", p.entity_type))
+                    })?;
+                // The state is the properties in the spec's order, followed by data which
+                // is copied as-is
+                let mut state = vec![];
+                let mut decoded_len = 0;
+                for property in spec.properties.iter() {
+                    if property.flags != Flags::BaseAndClient {
+                        continue;
+                    }
+                    let name = property.name.as_str();
+                    state.extend(property.prop_type.encode_value(&props[name])?);
+                    decoded_len += property.prop_type.encode_value(&p.props[name])?.len();
+                }
+                state.extend_from_slice(&p.state[decoded_len..]);
+                let packet = BasePlayerCreatePacket {
+                    entity_id: p.entity_id,
+                    entity_type: p.entity_type,
+                    state: &state,
+                    props,
+                    error: None,
+                };
+                encoder
+                    .encode_payload(&PacketType::BasePlayerCreate(packet))
+                    .map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Anonymizes a packet stream, re-encoding any packets which mention a player
+    fn anonymize_packets(
+        &self,
+        packet_data: &[u8],
+        specs: &[EntitySpec],
+    ) -> Result<Vec<u8>, ErrorKind> {
+        let mut parser = Parser::new(specs);
+        // Follows the anonymized packets, to encode the next one
+        let mut encoder = Parser::new(specs);
+        let mut out = Vec::with_capacity(packet_data.len());
+        for packet in parser.packets(packet_data) {
+            let packet = packet?;
+            let rewritten = self.rewrite_payload(&encoder, specs, &packet.payload)?;
+            let payload = rewritten.as_deref().unwrap_or(packet.raw);
+            let start = out.len();
+            out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            out.extend_from_slice(&packet.packet_type.to_le_bytes());
+            out.extend_from_slice(&packet.clock.to_le_bytes());
+            out.extend_from_slice(payload);
+            for packet in encoder.packets(&out[start..]) {
+                packet?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Produces an anonymized copy of the given replay.
+    pub fn anonymize(
+        &self,
+        replay: &ReplayFile,
+        specs: &[EntitySpec],
+    ) -> Result<ReplayWriter, ErrorKind> {
+        let packet_data = self.anonymize_packets(&replay.packet_data, specs)?;
+        let mut blocks = replay.blocks.clone();
+        for block in blocks.iter_mut() {
+            self.rewrite_json(block);
+        }
+        Ok(ReplayWriter {
+            blocks,
+            packet_data,
+        })
+    }
+}
+
+/// Anonymizes every player in the given replay.
+pub fn anonymize(replay: &ReplayFile, specs: &[EntitySpec]) -> Result<ReplayWriter, ErrorKind> {
+    Anonymizer::new(replay, specs)?.anonymize(replay, specs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::entitydefs::MethodDirection;
+    use crate::rpc::typedefs::{ArgType, PrimitiveType};
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn rewrite_pickle() {
+        let mut anonymizer = Anonymizer::default();
+        anonymizer.add_name("lkolbly");
+        anonymizer.add_account_id(403703021);
+
+        // pickle.dumps({'name': 'lkolbly', 'id': 403703021}, 2) under Python 2
+        let mut pickle = vec![0x80, 2, b'}', b'q', 0, b'(', b'U', 4];
+        pickle.extend_from_slice(b"name");
+        pickle.extend_from_slice(&[b'U', 7]);
+        pickle.extend_from_slice(b"lkolbly");
+        pickle.extend_from_slice(&[b'U', 2, b'i', b'd', b'J']);
+        pickle.extend_from_slice(&403703021i32.to_le_bytes());
+        pickle.extend_from_slice(b"u.");
+
+        let rewritten = anonymizer.rewrite_pickle(&pickle).unwrap().unwrap();
+        let value = serde_pickle::value_from_slice(&rewritten, Default::default()).unwrap();
+        let value = match value {
+            serde_pickle::Value::Dict(d) => d,
+            x => panic!("{:?}", x),
+        };
+        assert_eq!(
+            value[&serde_pickle::HashableValue::Bytes(b"name".to_vec())],
+            serde_pickle::Value::Bytes(b"Player1".to_vec())
+        );
+        assert_eq!(
+            value[&serde_pickle::HashableValue::Bytes(b"id".to_vec())],
+            serde_pickle::Value::I64(1)
+        );
+
+        anonymizer.names.clear();
+        anonymizer.account_ids.clear();
+        assert!(anonymizer.rewrite_pickle(&pickle).unwrap().is_none());
+    }
+
+    #[test]
+    fn rewrite_pickle_long() {
+        let mut anonymizer = Anonymizer::default();
+        anonymizer.add_account_id(403703021);
+
+        // pickle.dumps([403703021L, -1L], 2) under Python 2
+        let mut pickle = vec![0x80, 2, b']', b'q', 0, b'(', 0x8a, 4];
+        pickle.extend_from_slice(&403703021i32.to_le_bytes());
+        pickle.extend_from_slice(&[0x8a, 1, 0xff, b'e', b'.']);
+
+        let rewritten = anonymizer.rewrite_pickle(&pickle).unwrap().unwrap();
+        assert_eq!(
+            serde_pickle::value_from_slice(&rewritten, Default::default()).unwrap(),
+            serde_pickle::Value::List(vec![
+                serde_pickle::Value::I64(1),
+                serde_pickle::Value::I64(-1)
+            ])
+        );
+    }
+
+    #[test]
+    fn rewrite_chat() {
+        let mut anonymizer = Anonymizer::default();
+        anonymizer.add_name("lkolbly");
+        anonymizer.add_name("rkolbly");
+        assert_eq!(
+            anonymizer.rewrite_chat("rkolbly, cover lkolbly! ☺"),
+            "Player2, cover Player1! ☺"
+        );
+
+        // Names inside of other words are left alone
+        let mut anonymizer = Anonymizer::default();
+        anonymizer.add_name("a");
+        anonymizer.add_name("GG");
+        assert_eq!(
+            anonymizer.rewrite_chat("GG a, a bad GGame at GG_x (a)"),
+            "Player2 Player1, Player1 bad GGame at GG_x (Player1)"
+        );
+    }
+
+    const NAME: &str = "lkolbly";
+    const ID: i64 = 403703021;
+
+    /// A value of the given type in which every string is a username and every integer
+    /// which can hold an account ID is one
+    fn identifying(ty: &ArgType) -> ArgValue<'_> {
+        match ty {
+            ArgType::Primitive(PrimitiveType::String) => ArgValue::String(NAME.into()),
+            ArgType::Primitive(PrimitiveType::UnicodeString) => {
+                ArgValue::UnicodeString(NAME.into())
+            }
+            ArgType::Primitive(PrimitiveType::Int32) => ArgValue::Int32(ID as i32),
+            ArgType::Primitive(PrimitiveType::Uint32) => ArgValue::Uint32(ID as u32),
+            ArgType::Primitive(PrimitiveType::Int64) => ArgValue::Int64(ID),
+            ArgType::Primitive(PrimitiveType::Uint64) => ArgValue::Uint64(ID as u64),
+            ArgType::Primitive(PrimitiveType::Blob) => {
+                // pickle.dumps((name, id, long(id)), 2) under Python 2
+                let mut pickle = vec![0x80, 2, b'U', NAME.len() as u8];
+                pickle.extend_from_slice(NAME.as_bytes());
+                pickle.push(b'J');
+                pickle.extend_from_slice(&(ID as i32).to_le_bytes());
+                pickle.extend_from_slice(&[0x8a, 4]);
+                pickle.extend_from_slice(&(ID as i32).to_le_bytes());
+                pickle.extend_from_slice(&[0x87, b'.']);
+                ArgValue::Blob(pickle)
+            }
+            ArgType::Primitive(_) => ty.default_value(),
+            ArgType::Array((size, t)) => {
+                ArgValue::Array((0..size.unwrap_or(1)).map(|_| identifying(t)).collect())
+            }
+            ArgType::Tuple((t, size)) => {
+                ArgValue::Tuple((0..size.unwrap_or(1)).map(|_| identifying(t)).collect())
+            }
+            ArgType::FixedDict((allow_none, props)) => {
+                let d = props
+                    .iter()
+                    .map(|prop| (prop.name.as_str(), identifying(&prop.prop_type)))
+                    .collect();
+                if *allow_none {
+                    ArgValue::NullableFixedDict(Some(d))
+                } else {
+                    ArgValue::FixedDict(d)
+                }
+            }
+        }
+    }
+
+    /// Builds a packet stream by encoding packets
+    struct StreamBuilder<'a> {
+        parser: Parser<'a>,
+        data: Vec<u8>,
+    }
+
+    impl<'a> StreamBuilder<'a> {
+        fn push(&mut self, packet_type: u32, payload: PacketType<'_, 'a>) {
+            let payload = self.parser.encode_payload(&payload).unwrap();
+            let start = self.data.len();
+            self.data
+                .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            self.data.extend_from_slice(&packet_type.to_le_bytes());
+            self.data.extend_from_slice(&0.0f32.to_le_bytes());
+            self.data.extend_from_slice(&payload);
+            for packet in self.parser.packets(&self.data[start..]) {
+                let packet = packet.unwrap();
+                assert!(
+                    !matches!(packet.payload, PacketType::Invalid(_)),
+                    "{:?}",
+                    packet.payload
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn anonymize_every_packet_kind() {
+        let version = "0,10,3,0".parse::<Version>().unwrap();
+        let datafiles =
+            crate::version::Datafiles::new(std::path::PathBuf::from("../versions"), version)
+                .unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+        let spec = |name: &str| specs.iter().find(|spec| spec.name == name).unwrap();
+        let (avatar, vehicle) = (spec("Avatar"), spec("Vehicle"));
+        let (avatar_id, vehicle_id) = (100, 200);
+
+        let mut builder = StreamBuilder {
+            parser: Parser::new(&specs),
+            data: vec![],
+        };
+        let mut state = vec![];
+        for property in avatar.properties.iter() {
+            if property.flags == Flags::BaseAndClient {
+                let value = identifying(&property.prop_type);
+                state.extend(property.prop_type.encode_value(&value).unwrap());
+            }
+        }
+        state.extend_from_slice(b"trailing data");
+        builder.push(
+            0x0,
+            PacketType::BasePlayerCreate(BasePlayerCreatePacket {
+                entity_id: avatar_id,
+                entity_type: "Avatar",
+                state: &state,
+                props: HashMap::new(),
+                error: None,
+            }),
+        );
+        builder.push(
+            0x1,
+            PacketType::CellPlayerCreate(CellPlayerCreatePacket {
+                entity_id: avatar_id,
+                space_id: 1,
+                vehicle_id: 0,
+                position: Default::default(),
+                rotation: Default::default(),
+                props: avatar
+                    .internal_properties
+                    .iter()
+                    .map(|p| (p.name.as_str(), identifying(&p.prop_type)))
+                    .collect(),
+                value: &[],
+            }),
+        );
+        builder.push(
+            0x5,
+            PacketType::EntityCreate(EntityCreatePacket {
+                entity_id: vehicle_id,
+                entity_type: "Vehicle",
+                space_id: 1,
+                vehicle_id: 0,
+                position: Default::default(),
+                rotation: Default::default(),
+                state_length: 0,
+                props: vehicle
+                    .properties
+                    .iter()
+                    .map(|p| (p.name.as_str(), identifying(&p.prop_type)))
+                    .collect(),
+            }),
+        );
+        for (entity_id, spec) in [(avatar_id, avatar), (vehicle_id, vehicle)].iter() {
+            for property in spec.properties.iter() {
+                builder.push(
+                    0x7,
+                    PacketType::EntityProperty(EntityPropertyPacket {
+                        entity_id: *entity_id,
+                        property: &property.name,
+                        value: identifying(&property.prop_type),
+                    }),
+                );
+            }
+            for method in spec.client_methods.iter() {
+                builder.push(
+                    0x8,
+                    PacketType::EntityMethod(EntityMethodPacket {
+                        entity_id: *entity_id,
+                        method: &method.name,
+                        component: method.component.as_deref(),
+                        direction: MethodDirection::ToClient,
+                        args: method.args.iter().map(identifying).collect(),
+                    }),
+                );
+            }
+        }
+        // Nested updates which set a key of a dict, and an element of an array
+        let mut nested = 0;
+        for property in vehicle.properties.iter() {
+            let action = match &property.prop_type {
+                ArgType::FixedDict((false, entries)) if !entries.is_empty() => {
+                    UpdateAction::SetKey {
+                        key: &entries[0].name,
+                        value: identifying(&entries[0].prop_type),
+                    }
+                }
+                ArgType::Array((None, t)) => UpdateAction::SetElement {
+                    index: 0,
+                    value: identifying(t),
+                },
+                _ => continue,
+            };
+            builder.push(
+                0x22,
+                PacketType::PropertyUpdate(PropertyUpdatePacket {
+                    entity_id: vehicle_id as i32,
+                    property: &property.name,
+                    update_cmd: PropertyNesting {
+                        levels: vec![],
+                        action,
+                    },
+                }),
+            );
+            nested += 1;
+        }
+        assert!(nested > 0);
+
+        let mut anonymizer = Anonymizer::default();
+        anonymizer.add_name(NAME);
+        anonymizer.add_account_id(ID);
+        let original = builder.data;
+        let anonymized = anonymizer.anonymize_packets(&original, &specs).unwrap();
+        assert!(contains(&original, NAME.as_bytes()));
+        assert!(contains(&original, &(ID as i32).to_le_bytes()));
+
+        let mut kinds = std::collections::HashSet::new();
+        for packet in Parser::new(&specs).packets(&anonymized) {
+            let packet = packet.unwrap();
+            assert!(
+                !matches!(packet.payload, PacketType::Invalid(_)),
+                "{:?}",
+                packet.payload
+            );
+            assert!(
+                !contains(packet.raw, NAME.as_bytes()),
+                "{:x}: {:?}",
+                packet.packet_type,
+                packet.payload
+            );
+            assert!(
+                !contains(packet.raw, &(ID as i32).to_le_bytes()),
+                "{:x}: {:?}",
+                packet.packet_type,
+                packet.payload
+            );
+            kinds.insert(packet.packet_type);
+        }
+        assert_eq!(kinds.len(), 6);
+        // Data after the decoded part of the base player's state is kept
+        assert!(contains(&anonymized, b"trailing data"));
+    }
+
+    #[test]
+    fn anonymize_test_replay() {
+        let replay = ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let datafiles = crate::version::Datafiles::new(
+            std::path::PathBuf::from("../versions"),
+            replay.meta.clientVersionFromExe.parse::<Version>().unwrap(),
+        )
+        .unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+
+        let anonymizer = Anonymizer::new(&replay, &specs).unwrap();
+        assert_eq!(anonymizer.names.len(), 24);
+        assert_eq!(anonymizer.names["lkolbly"], "Player20");
+        assert_eq!(anonymizer.clans["URGAY"], "CLAN1");
+        assert_eq!(anonymizer.account_ids[&403703021], 20);
+
+        let written = anonymizer.anonymize(&replay, &specs).unwrap();
+        let data = written.to_bytes().unwrap();
+        let anonymized = ReplayFile::from_bytes(&data).unwrap();
+        assert_eq!(anonymized.meta.playerName, "Player20");
+        assert_eq!(anonymized.meta.vehicles[19].name, "Player20");
+        assert_eq!(anonymized.meta.vehicles[19].id, 20);
+
+        for (name, _) in anonymizer.names.iter() {
+            assert!(!contains(&data, name.as_bytes()), "{}", name);
+            assert!(
+                !contains(&anonymized.packet_data, name.as_bytes()),
+                "{}",
+                name
+            );
+        }
+        for id in anonymizer.account_ids.keys() {
+            let id = *id as i32;
+            assert!(
+                !contains(&anonymized.packet_data, &id.to_le_bytes()),
+                "{}",
+                id
+            );
+        }
+
+        // The anonymized replay still parses, and the players were renamed
+        let collector = {
+            let mut collector = PlayerCollector {
+                version: anonymized
+                    .meta
+                    .clientVersionFromExe
+                    .parse::<Version>()
+                    .unwrap(),
+                players: vec![],
+            };
+            Parser::new(&specs)
+                .parse_packets(&anonymized.packet_data, &mut collector)
+                .unwrap();
+            collector
+        };
+        assert_eq!(collector.players.len(), 24);
+        assert!(collector
+            .players
+            .iter()
+            .all(|(name, _)| name.starts_with("Player")));
+        assert!(collector
+            .players
+            .iter()
+            .all(|(_, clan)| clan.is_empty() || clan.starts_with("CLAN")));
+    }
+}
@@ -0,0 +1,362 @@
+//! Cuts a clip covering a range of the game clock out of a replay.
+//!
+//! Since entities are only created when they enter the player's area of interest, a clip
+//! can't simply start partway through the packet stream. Instead, the parser is run up to
+//! the start of the clip, and then the state of every entity at that point is written out
+//! as freshly created entities.
+
+use nom::bytes::complete::take;
+use nom::number::complete::{le_f32, le_u32};
+
+use crate::error::*;
+use crate::packet2::{
+    EntityCreatePacket, EntityEnterPacket, EntityPropertyPacket, PacketType, Parser,
+};
+use crate::rpc::entitydefs::EntitySpec;
+use crate::world::Entity;
+use crate::{ReplayFile, ReplayWriter};
+
+/// Splits the next packet off the stream, returning its type, clock and payload
+fn split_packet(i: &[u8]) -> IResult<&[u8], (u32, f32, &[u8])> {
+    let (i, packet_size) = le_u32(i)?;
+    let (i, packet_type) = le_u32(i)?;
+    let (i, clock) = le_f32(i)?;
+    let (i, payload) = take(packet_size)(i)?;
+    Ok((i, (packet_type, clock, payload)))
+}
+
+fn write_packet(out: &mut Vec<u8>, packet_type: u32, clock: f32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(&packet_type.to_le_bytes());
+    out.extend_from_slice(&clock.to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// Returns an EntityCreate packet which creates the entity in its current state
+fn entity_create<'argtype>(
+    entity_id: u32,
+    entity: &Entity<'argtype>,
+) -> PacketType<'static, 'argtype> {
+    PacketType::EntityCreate(EntityCreatePacket {
+        entity_id,
+        entity_type: entity.type_name(),
+        space_id: entity.space_id,
+        vehicle_id: entity.vehicle_id,
+        position: entity.position.clone(),
+        rotation: entity.rotation.clone(),
+        state_length: 0,
+        props: entity
+            .properties()
+            .map(|(name, value)| (name, value.clone()))
+            .collect(),
+    })
+}
+
+/// Produces a replay containing only the packets with a clock between `from` and `to`
+/// (in seconds). The clocks in the clip are shifted so that it starts at zero.
+///
+/// The clip starts with the packets which set up the connection (those at the very first
+/// clock tick, e.g. the version, map and arena state), followed by packets recreating every
+/// entity which exists at `from` with its state at that time. Entities which last arrived in
+/// the area of interest with an EntityEnter are entered again after being recreated. Method
+/// calls before `from` are dropped, since they are events rather than state.
+///
+/// The `duration` in the metadata is set to the length of the clip.
+///
+/// Returns `ErrorKind::BadClipRange` unless `from` and `to` are finite and `from < to`.
+pub fn cut(
+    replay: &ReplayFile,
+    specs: &[EntitySpec],
+    from: f32,
+    to: f32,
+) -> Result<ReplayWriter, ErrorKind> {
+    if !from.is_finite() || !to.is_finite() || from >= to {
+        return Err(ErrorKind::BadClipRange { from, to });
+    }
+
+    let mut parser = Parser::new(specs);
+    let mut out = vec![];
+    let mut initial_clock = None;
+    let mut base_player = None;
+    // The entities currently in the area of interest, in the order they were created
+    let mut alive: Vec<u32> = vec![];
+    // The entities which have been created by an EntityCreate since the start
+    let mut created: Vec<u32> = vec![];
+    // The entities which entered the area of interest since they were last created
+    let mut entered: Vec<u32> = vec![];
+
+    let mut i = &replay.packet_data[..];
+    while !i.is_empty() {
+        let (_, (_, clock, _)) = split_packet(i)?;
+        if clock >= from {
+            break;
+        }
+        let (remaining, packet) = parser.parse_packet(i)?;
+        i = remaining;
+
+        let initial = *initial_clock.get_or_insert(packet.clock);
+        let keep = match &packet.payload {
+            PacketType::BasePlayerCreate(p) => {
+                base_player = Some(p.entity_id);
+                true
+            }
+            PacketType::EntityCreate(p) => {
+                alive.retain(|id| *id != p.entity_id);
+                alive.push(p.entity_id);
+                entered.retain(|id| *id != p.entity_id);
+                if !created.contains(&p.entity_id) {
+                    created.push(p.entity_id);
+                }
+                false
+            }
+            PacketType::EntityLeave(p) => {
+                alive.retain(|id| *id != p.entity_id);
+                entered.retain(|id| *id != p.entity_id);
+                false
+            }
+            PacketType::EntityEnter(p) => {
+                // An entity which left keeps its state, and may come back without being
+                // created again
+                if created.contains(&p.entity_id) && !alive.contains(&p.entity_id) {
+                    alive.push(p.entity_id);
+                }
+                entered.retain(|id| *id != p.entity_id);
+                entered.push(p.entity_id);
+                false
+            }
+            PacketType::EntityProperty(_)
+            | PacketType::PropertyUpdate(_)
+            | PacketType::Position(_)
+            | PacketType::PlayerOrientation(_)
+            | PacketType::EntityPosition(_) => false,
+            PacketType::EntityMethod(p) => {
+                packet.clock == initial && Some(p.entity_id) == base_player
+            }
+            _ => packet.clock == initial,
+        };
+        if keep {
+            write_packet(&mut out, packet.packet_type, 0.0, packet.raw);
+        }
+    }
+
+    for entity_id in alive.iter() {
+        let entity = &parser.world.entities[entity_id];
+        let payload = parser.encode_payload(&entity_create(*entity_id, entity))?;
+        write_packet(&mut out, 0x5, 0.0, &payload);
+    }
+    if let Some(entity_id) = base_player {
+        // The base player isn't sent with its state, so set its properties one at a time
+        let entity = &parser.world.entities[&entity_id];
+        let mut indices: Vec<_> = entity.properties.keys().copied().collect();
+        indices.sort_unstable();
+        for idx in indices.iter() {
+            let packet = PacketType::EntityProperty(EntityPropertyPacket {
+                entity_id,
+                property: &entity.spec.properties[*idx].name,
+                value: entity.properties[idx].clone(),
+            });
+            write_packet(&mut out, 0x7, 0.0, &parser.encode_payload(&packet)?);
+        }
+    }
+    for entity_id in entered.iter() {
+        let entity = &parser.world.entities[entity_id];
+        let packet = PacketType::EntityEnter(EntityEnterPacket {
+            entity_id: *entity_id,
+            space_id: entity.space_id,
+            vehicle_id: entity.vehicle_id,
+        });
+        write_packet(&mut out, 0x3, 0.0, &parser.encode_payload(&packet)?);
+    }
+
+    // The clip ends at `to`, or earlier if the replay does
+    let mut end = from;
+    while !i.is_empty() {
+        let (remaining, (packet_type, clock, payload)) = split_packet(i)?;
+        if clock > to {
+            end = to;
+            break;
+        }
+        // Some packets, such as the end of the replay, have a clock of zero
+        write_packet(&mut out, packet_type, (clock - from).max(0.0), payload);
+        end = end.max(clock);
+        i = remaining;
+    }
+
+    let mut blocks = replay.blocks.clone();
+    if let Some(meta) = blocks.get_mut(0).and_then(|meta| meta.as_object_mut()) {
+        meta.insert("duration".to_string(), ((end - from).round() as u32).into());
+    }
+    Ok(ReplayWriter {
+        blocks,
+        packet_data: out,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::version::{Datafiles, Version};
+
+    fn load() -> (ReplayFile, Vec<EntitySpec>) {
+        let replay = ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let datafiles = Datafiles::new(
+            std::path::PathBuf::from("../versions"),
+            replay.meta.clientVersionFromExe.parse::<Version>().unwrap(),
+        )
+        .unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+        (replay, specs)
+    }
+
+    /// Runs the parser over every packet before the given clock
+    fn parse_until<'a>(parser: &mut Parser<'a>, mut i: &[u8], clock: f32) {
+        while !i.is_empty() && split_packet(i).unwrap().1 .1 < clock {
+            let (remaining, packet) = parser.parse_packet(i).unwrap();
+            if let PacketType::Invalid(p) = &packet.payload {
+                panic!("{:?}", p);
+            }
+            i = remaining;
+        }
+    }
+
+    #[test]
+    fn synthesized_create_matches_original() {
+        let (replay, specs) = load();
+        let mut parser = Parser::new(&specs);
+        let mut i = &replay.packet_data[..];
+        let (entity_id, raw) = loop {
+            let (remaining, packet) = parser.parse_packet(i).unwrap();
+            i = remaining;
+            if let PacketType::EntityCreate(p) = &packet.payload {
+                break (p.entity_id, packet.raw);
+            }
+        };
+        let packet = entity_create(entity_id, &parser.world.entities[&entity_id]);
+        assert_eq!(parser.encode_payload(&packet).unwrap(), raw);
+    }
+
+    #[test]
+    fn cut_test_replay() {
+        let (replay, specs) = load();
+        let clip = cut(&replay, &specs, 300.0, 600.0).unwrap();
+        let clip = ReplayFile::from_bytes(&clip.to_bytes().unwrap()).unwrap();
+        assert_eq!(clip.meta.playerName, "lkolbly");
+        assert_eq!(clip.meta.duration, 300);
+
+        let mut i = &clip.packet_data[..];
+        let mut creates = 0;
+        while !i.is_empty() {
+            let (remaining, (packet_type, clock, _)) = split_packet(i).unwrap();
+            assert!((0.0..=300.0).contains(&clock), "{}", clock);
+            if packet_type == 0x5 && clock == 0.0 {
+                creates += 1;
+            }
+            i = remaining;
+        }
+
+        // The recreated entities match the state of the original at the cut point
+        let mut original = Parser::new(&specs);
+        parse_until(&mut original, &replay.packet_data, 300.0);
+        let mut clipped = Parser::new(&specs);
+        parse_until(&mut clipped, &clip.packet_data, 0.001);
+        assert_eq!(clipped.world.entities.len(), creates + 1);
+        for (entity_id, entity) in clipped.world.entities.iter() {
+            let expected = &original.world.entities[entity_id];
+            assert_eq!(entity.entity_type, expected.entity_type);
+            assert_eq!(entity.properties, expected.properties, "{}", entity_id);
+        }
+
+        // The rest of the clip parses cleanly
+        parse_until(&mut clipped, &clip.packet_data, f32::INFINITY);
+    }
+
+    #[test]
+    fn clip_past_the_end() {
+        let (replay, specs) = load();
+        let clip = cut(&replay, &specs, 300.0, 1e6).unwrap();
+        let clip = ReplayFile::from_bytes(&clip.to_bytes().unwrap()).unwrap();
+        let mut last_clock = 0.0f32;
+        let mut i = &clip.packet_data[..];
+        while !i.is_empty() {
+            let (remaining, (_, clock, _)) = split_packet(i).unwrap();
+            // The end of the replay has a clock of zero, which isn't shifted before the clip
+            assert!(clock >= 0.0, "{}", clock);
+            last_clock = last_clock.max(clock);
+            i = remaining;
+        }
+        assert_eq!(clip.meta.duration, last_clock.round() as u32);
+    }
+
+    #[test]
+    fn entity_enter() {
+        let (mut replay, specs) = load();
+        let entity_id = 576270u32;
+
+        // Have a vehicle leave and then come back without being created again
+        let mut packets = vec![];
+        let mut i = &replay.packet_data[..];
+        let mut inserted = false;
+        while !i.is_empty() {
+            let (remaining, (packet_type, clock, payload)) = split_packet(i).unwrap();
+            if clock >= 200.0 && !inserted {
+                write_packet(&mut packets, 0x4, clock, &entity_id.to_le_bytes());
+                let mut enter = entity_id.to_le_bytes().to_vec();
+                enter.extend_from_slice(&1u32.to_le_bytes());
+                enter.extend_from_slice(&0u32.to_le_bytes());
+                write_packet(&mut packets, 0x3, clock, &enter);
+                inserted = true;
+            }
+            write_packet(&mut packets, packet_type, clock, payload);
+            i = remaining;
+        }
+        replay.packet_data = packets;
+
+        let clip = cut(&replay, &specs, 300.0, 600.0).unwrap();
+        let mut parser = Parser::new(&specs);
+        let mut created = false;
+        let mut entered = false;
+        for packet in parser.packets(&clip.packet_data) {
+            let packet = packet.unwrap();
+            match &packet.payload {
+                PacketType::EntityCreate(p) if p.entity_id == entity_id => {
+                    assert!(!entered);
+                    created = true;
+                }
+                PacketType::EntityEnter(p) if p.entity_id == entity_id => {
+                    assert_eq!(packet.clock, 0.0);
+                    assert_eq!((p.space_id, p.vehicle_id), (1, 0));
+                    entered = true;
+                }
+                _ => {}
+            }
+        }
+        assert!(created && entered);
+    }
+
+    #[test]
+    fn bad_range() {
+        let (replay, specs) = load();
+        for (from, to) in [
+            (600.0, 300.0),
+            (300.0, 300.0),
+            (f32::NAN, 300.0),
+            (0.0, f32::INFINITY),
+            (f32::NEG_INFINITY, 300.0),
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(
+                    cut(&replay, &specs, *from, *to),
+                    Err(ErrorKind::BadClipRange { .. })
+                ),
+                "{} {}",
+                from,
+                to
+            );
+        }
+    }
+}
@@ -0,0 +1,685 @@
+//! Places to read game data files from. A [crate::version::Datafiles] reads each file from
+//! the first of its sources which has it.
+
+use rust_embed::RustEmbed;
+use std::borrow::Cow;
+#[cfg(any(feature = "tar", feature = "zip"))]
+use std::collections::HashMap;
+#[cfg(any(feature = "tar", feature = "zip"))]
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::error::ErrorKind;
+use crate::version::Version;
+
+pub trait DatafileSource: Send + Sync {
+    /// The versions this source has data files for. Their build numbers are zero.
+    fn versions(&self) -> Result<Vec<Version>, ErrorKind>;
+
+    fn has_version(&self, version: &Version) -> Result<bool, ErrorKind> {
+        Ok(self
+            .versions()?
+            .iter()
+            .any(|available| available.to_path() == version.to_path()))
+    }
+
+    /// Reads a data file, given its path within the version's directory (e.g.
+    /// "scripts/entities.xml"). Returns None if this source doesn't have the file.
+    fn get(&self, version: &Version, path: &str) -> Result<Option<Cow<'static, [u8]>>, ErrorKind>;
+
+    /// Identifies where the data files come from, so that anything derived from them (such
+    /// as the disk cache of a [crate::SpecCache]) isn't shared with other sources. It must
+    /// be the same between runs.
+    fn source_id(&self) -> String;
+}
+
+/// A 64-bit FNV-1a hash. Unlike `std`'s hashers, it is guaranteed to be the same between
+/// runs and builds, so it can name files on disk.
+pub(crate) fn stable_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Parses a version directory name such as "0.10.9"
+fn version_from_dir_name(name: &str) -> Option<Version> {
+    if name.matches('.').count() != 2 {
+        return None;
+    }
+    name.parse().ok()
+}
+
+/// Every version has an entities.xml, so it tells us whether a version is present
+const VERSION_MARKER: &str = "scripts/entities.xml";
+
+/// A directory containing a subdirectory of data files per version, e.g.
+/// `versions/0.10.9/scripts/entities.xml`
+pub struct DirectorySource {
+    base: PathBuf,
+}
+
+impl DirectorySource {
+    pub fn new(base: PathBuf) -> DirectorySource {
+        DirectorySource { base }
+    }
+
+    fn path(&self, version: &Version, path: &str) -> PathBuf {
+        self.base.join(version.to_path()).join(path)
+    }
+}
+
+/// Appends the path, size and modification time of every file under `dir` to `stamps`
+fn file_stamps(dir: &Path, stamps: &mut Vec<String>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            file_stamps(&path, stamps);
+        } else {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|time| time.as_nanos())
+                .unwrap_or(0);
+            stamps.push(format!(
+                "{} {} {}",
+                path.display(),
+                metadata.len(),
+                modified
+            ));
+        }
+    }
+}
+
+impl DatafileSource for DirectorySource {
+    /// Identified by the directory, and the sizes and modification times of the scripts in
+    /// it, so that updating the data files in place isn't mistaken for the old ones
+    fn source_id(&self) -> String {
+        let mut stamps = vec![];
+        for version in self.versions().unwrap_or_default() {
+            file_stamps(&self.path(&version, "scripts"), &mut stamps);
+        }
+        stamps.sort();
+        format!(
+            "dir:{}:{:016x}",
+            self.base.display(),
+            stable_hash(stamps.join("\n").as_bytes())
+        )
+    }
+
+    fn versions(&self) -> Result<Vec<Version>, ErrorKind> {
+        let entries = match std::fs::read_dir(&self.base) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut versions = vec![];
+        for entry in entries {
+            let name = entry?.file_name();
+            if let Some(version) = name.to_str().and_then(version_from_dir_name) {
+                if self.has_version(&version)? {
+                    versions.push(version);
+                }
+            }
+        }
+        Ok(versions)
+    }
+
+    fn has_version(&self, version: &Version) -> Result<bool, ErrorKind> {
+        Ok(self.path(version, VERSION_MARKER).exists())
+    }
+
+    fn get(&self, version: &Version, path: &str) -> Result<Option<Cow<'static, [u8]>>, ErrorKind> {
+        match std::fs::read(self.path(version, path)) {
+            Ok(data) => Ok(Some(Cow::from(data))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(RustEmbed)]
+#[folder = "../versions/"]
+struct Embedded;
+
+/// The data files compiled into the library
+pub struct EmbeddedSource;
+
+impl DatafileSource for EmbeddedSource {
+    /// Identified by the hashes of the embedded scripts, which change when the library is
+    /// rebuilt with other data files
+    fn source_id(&self) -> String {
+        let mut paths: Vec<_> = Embedded::iter()
+            .filter(|path| path.contains("/scripts/"))
+            .collect();
+        paths.sort();
+        let mut hashes = vec![];
+        for path in paths.iter() {
+            if let Some(file) = Embedded::get(path) {
+                hashes.extend_from_slice(path.as_bytes());
+                hashes.extend_from_slice(&file.metadata.sha256_hash());
+            }
+        }
+        format!("embedded:{:016x}", stable_hash(&hashes))
+    }
+
+    fn versions(&self) -> Result<Vec<Version>, ErrorKind> {
+        let mut names: Vec<String> = Embedded::iter()
+            .filter_map(|path| path.split('/').next().map(|name| name.to_string()))
+            .collect();
+        names.sort();
+        names.dedup();
+        let mut versions = vec![];
+        for version in names.iter().filter_map(|name| version_from_dir_name(name)) {
+            if self.has_version(&version)? {
+                versions.push(version);
+            }
+        }
+        Ok(versions)
+    }
+
+    fn has_version(&self, version: &Version) -> Result<bool, ErrorKind> {
+        Ok(Embedded::get(&format!("{}/{}", version.to_path(), VERSION_MARKER)).is_some())
+    }
+
+    fn get(&self, version: &Version, path: &str) -> Result<Option<Cow<'static, [u8]>>, ErrorKind> {
+        Ok(Embedded::get(&format!("{}/{}", version.to_path(), path)).map(|file| file.data))
+    }
+}
+
+#[cfg(any(feature = "tar", feature = "zip", feature = "idxpkg"))]
+fn bad_archive(reason: String) -> ErrorKind {
+    ErrorKind::BadArchive { reason }
+}
+
+/// The data files for a single version, from a tar archive which may be gzip-compressed.
+/// Paths in the archive may be relative to the version's directory (`scripts/...`) or
+/// include it (`0.10.9/scripts/...`). The whole archive is read into memory when opened.
+#[cfg(feature = "tar")]
+pub struct TarSource {
+    version: Version,
+    files: HashMap<String, Vec<u8>>,
+    /// The hash of the archive, so that a changed archive isn't mistaken for the old one
+    hash: u64,
+}
+
+#[cfg(feature = "tar")]
+impl TarSource {
+    pub fn open(path: &Path, version: Version) -> Result<TarSource, ErrorKind> {
+        Self::from_bytes(&std::fs::read(path)?, version)
+    }
+
+    pub fn from_bytes(data: &[u8], version: Version) -> Result<TarSource, ErrorKind> {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            let mut decompressed = vec![];
+            flate2::read::GzDecoder::new(data).read_to_end(&mut decompressed)?;
+            return Self::from_bytes(&decompressed, version);
+        }
+        let tar_error = |e: std::io::Error| bad_archive(e.to_string());
+
+        let version_prefix = format!("{}/", version.to_path());
+        let mut files = HashMap::new();
+        let mut archive = tar::Archive::new(data);
+        for entry in archive.entries().map_err(tar_error)? {
+            let mut entry = entry.map_err(tar_error)?;
+            // Directories, links and extended headers don't hold data files
+            if !matches!(
+                entry.header().entry_type(),
+                tar::EntryType::Regular | tar::EntryType::Continuous
+            ) {
+                continue;
+            }
+            let path = entry.path().map_err(tar_error)?;
+            let name = path
+                .to_str()
+                .ok_or_else(|| bad_archive(format!("Tar entry {:?} isn't UTF-8", path)))?
+                .replace('\\', "/");
+            let name = name.trim_start_matches("./");
+            let name = name
+                .strip_prefix(&version_prefix)
+                .unwrap_or(name)
+                .to_string();
+            let mut body = vec![];
+            entry
+                .read_to_end(&mut body)
+                .map_err(|e| bad_archive(format!("Tar entry {} is truncated: {}", name, e)))?;
+            files.insert(name, body);
+        }
+        Ok(TarSource {
+            version,
+            files,
+            hash: stable_hash(data),
+        })
+    }
+}
+
+#[cfg(feature = "tar")]
+impl DatafileSource for TarSource {
+    fn source_id(&self) -> String {
+        format!("tar:{:016x}", self.hash)
+    }
+
+    fn versions(&self) -> Result<Vec<Version>, ErrorKind> {
+        let mut version = self.version;
+        version.build = 0;
+        Ok(vec![version])
+    }
+
+    fn get(&self, version: &Version, path: &str) -> Result<Option<Cow<'static, [u8]>>, ErrorKind> {
+        if version.to_path() != self.version.to_path() {
+            return Ok(None);
+        }
+        Ok(self.files.get(path).map(|data| Cow::from(data.clone())))
+    }
+}
+
+/// The data files for a single version, from a zip archive. Paths are handled like those
+/// of a [TarSource], and the whole archive is likewise read into memory when opened.
+#[cfg(feature = "zip")]
+pub struct ZipSource {
+    version: Version,
+    files: HashMap<String, Vec<u8>>,
+    /// The hash of the archive, so that a changed archive isn't mistaken for the old one
+    hash: u64,
+}
+
+#[cfg(feature = "zip")]
+impl ZipSource {
+    pub fn open(path: &Path, version: Version) -> Result<ZipSource, ErrorKind> {
+        Self::from_bytes(&std::fs::read(path)?, version)
+    }
+
+    pub fn from_bytes(data: &[u8], version: Version) -> Result<ZipSource, ErrorKind> {
+        let zip_error = |e: zip::result::ZipError| bad_archive(e.to_string());
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data)).map_err(zip_error)?;
+
+        let version_prefix = format!("{}/", version.to_path());
+        let mut files = HashMap::new();
+        for idx in 0..archive.len() {
+            let mut file = archive.by_index(idx).map_err(zip_error)?;
+            if !file.is_file() {
+                continue;
+            }
+            let name = file.name().replace('\\', "/");
+            let name = name.trim_start_matches("./");
+            let name = name
+                .strip_prefix(&version_prefix)
+                .unwrap_or(name)
+                .to_string();
+            let mut body = vec![];
+            // A corrupt entry fails its CRC check at the end of the read
+            file.read_to_end(&mut body)
+                .map_err(|e| bad_archive(format!("Zip entry {} is corrupt: {}", name, e)))?;
+            files.insert(name, body);
+        }
+        Ok(ZipSource {
+            version,
+            files,
+            hash: stable_hash(data),
+        })
+    }
+}
+
+#[cfg(feature = "zip")]
+impl DatafileSource for ZipSource {
+    fn source_id(&self) -> String {
+        format!("zip:{:016x}", self.hash)
+    }
+
+    fn versions(&self) -> Result<Vec<Version>, ErrorKind> {
+        let mut version = self.version;
+        version.build = 0;
+        Ok(vec![version])
+    }
+
+    fn get(&self, version: &Version, path: &str) -> Result<Option<Cow<'static, [u8]>>, ErrorKind> {
+        if version.to_path() != self.version.to_path() {
+            return Ok(None);
+        }
+        Ok(self.files.get(path).map(|data| Cow::from(data.clone())))
+    }
+}
+
+/// The data files of a game install, read straight from its .idx/.pkg files. An install
+/// only has the data files for the version it is running.
+#[cfg(feature = "idxpkg")]
+pub struct IdxPkgSource {
+    resources: idxpkg::IdxPkgManager,
+    version: Version,
+}
+
+#[cfg(feature = "idxpkg")]
+impl IdxPkgSource {
+    pub fn new(resources: idxpkg::IdxPkgManager, version: Version) -> IdxPkgSource {
+        IdxPkgSource { resources, version }
+    }
+
+    /// Opens the most recent bin/ version of the install at `wows_path`, which must be the
+    /// given game version
+    pub fn open_install(wows_path: &Path, version: Version) -> Result<IdxPkgSource, ErrorKind> {
+        let resources = idxpkg::IdxPkgManager::open_install(wows_path, None)
+            .map_err(|e| bad_archive(e.to_string()))?;
+        Ok(Self::new(resources, version))
+    }
+}
+
+#[cfg(feature = "idxpkg")]
+impl DatafileSource for IdxPkgSource {
+    /// Identified by the checksums of its scripts, since the same install changes contents
+    /// when the game updates
+    fn source_id(&self) -> String {
+        let mut checksums = vec![];
+        for record in self.resources.iter() {
+            if record.path.starts_with("scripts/") {
+                checksums.extend_from_slice(record.path.as_bytes());
+                checksums.extend_from_slice(&record.crc32.to_le_bytes());
+            }
+        }
+        format!("idxpkg:{:016x}", stable_hash(&checksums))
+    }
+
+    fn versions(&self) -> Result<Vec<Version>, ErrorKind> {
+        let mut version = self.version;
+        version.build = 0;
+        Ok(vec![version])
+    }
+
+    fn get(&self, version: &Version, path: &str) -> Result<Option<Cow<'static, [u8]>>, ErrorKind> {
+        if version.to_path() != self.version.to_path() {
+            return Ok(None);
+        }
+        match self.resources.get(path) {
+            Some(record) => Ok(Some(Cow::from(
+                self.resources
+                    .extract(record)
+                    .map_err(|e| bad_archive(e.to_string()))?,
+            ))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(any(feature = "tar", feature = "zip"))]
+    use crate::version::{Datafiles, VersionPolicy};
+    #[cfg(any(feature = "tar", feature = "zip"))]
+    use std::io::Write;
+    #[cfg(any(feature = "tar", feature = "zip"))]
+    use std::sync::Arc;
+
+    /// Writes a tar archive of the given files, using GNU long names where needed
+    #[cfg(feature = "tar")]
+    fn tar(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (name, data) in files.iter() {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, &data[..]).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "tar")]
+    fn tar_archive() {
+        let version = "0,10,9,0".parse::<Version>().unwrap();
+        let long_name = format!("scripts/{}.txt", "x".repeat(120));
+        let archive = tar(&[
+            (
+                "./0.10.9/scripts/entities.xml".to_string(),
+                b"<root/>".to_vec(),
+            ),
+            ("scripts/empty.def".to_string(), vec![]),
+            (long_name.clone(), vec![7; 1000]),
+        ]);
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(&archive).unwrap();
+        let compressed = gz.finish().unwrap();
+
+        for data in [&archive, &compressed].iter() {
+            let source = TarSource::from_bytes(data, version).unwrap();
+            assert!(source.has_version(&version).unwrap());
+            let get = |path: &str| source.get(&version, path).unwrap().map(|d| d.to_vec());
+            assert_eq!(get("scripts/entities.xml"), Some(b"<root/>".to_vec()));
+            assert_eq!(get("scripts/empty.def"), Some(vec![]));
+            assert_eq!(get(&long_name), Some(vec![7; 1000]));
+            assert_eq!(get("scripts/missing.def"), None);
+            let other = "0,10,10,0".parse::<Version>().unwrap();
+            assert!(source
+                .get(&other, "scripts/entities.xml")
+                .unwrap()
+                .is_none());
+        }
+
+        // The source is identified by the archive's contents
+        let id = |data: &[u8]| TarSource::from_bytes(data, version).unwrap().source_id();
+        assert_eq!(id(&archive), id(&compressed));
+        assert_ne!(id(&archive), id(&tar(&[])));
+
+        let mut truncated = tar(&[("scripts/a.def".to_string(), vec![1; 600])]);
+        truncated.truncate(700);
+        assert!(matches!(
+            TarSource::from_bytes(&truncated, version),
+            Err(ErrorKind::BadArchive { .. })
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "tar")]
+    fn tar_extensions() {
+        let version = "0,10,9,0".parse::<Version>().unwrap();
+        let mut builder = tar::Builder::new(vec![]);
+
+        // A PAX extended header overrides the name of the next entry
+        let record = "path=scripts/pax.def\n";
+        let record = format!("{} {}", record.len() + 3, record);
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XHeader);
+        header.set_size(record.len() as u64);
+        header.set_cksum();
+        builder.append(&header, record.as_bytes()).unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_path("truncated").unwrap();
+        header.set_size(3);
+        header.set_cksum();
+        builder.append(&header, &b"pax"[..]).unwrap();
+
+        // GNU base-256 sizes
+        let mut header = tar::Header::new_gnu();
+        header.set_path("scripts/base256.def").unwrap();
+        let mut size = [0; 12];
+        size[0] = 0x80;
+        size[11] = 4;
+        header.as_gnu_mut().unwrap().size = size;
+        header.set_cksum();
+        builder.append(&header, &b"b256"[..]).unwrap();
+
+        // Directories and links are skipped
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "scripts/dir", &[][..])
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "scripts/link.def", "pax.def")
+            .unwrap();
+
+        let source = TarSource::from_bytes(&builder.into_inner().unwrap(), version).unwrap();
+        let get = |path: &str| source.get(&version, path).unwrap().map(|d| d.to_vec());
+        assert_eq!(get("scripts/pax.def"), Some(b"pax".to_vec()));
+        assert_eq!(get("truncated"), None);
+        assert_eq!(get("scripts/base256.def"), Some(b"b256".to_vec()));
+        assert_eq!(get("scripts/dir"), None);
+        assert_eq!(get("scripts/link.def"), None);
+    }
+
+    /// Writes a zip archive of the given files, compressing every other one
+    #[cfg(feature = "zip")]
+    fn zip(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        for (idx, (name, data)) in files.iter().enumerate() {
+            let method = if idx % 2 == 0 {
+                zip::CompressionMethod::Deflated
+            } else {
+                zip::CompressionMethod::Stored
+            };
+            let options = zip::write::FileOptions::default().compression_method(method);
+            writer.start_file(name.as_str(), options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn zip_archive() {
+        let version = "0,10,9,0".parse::<Version>().unwrap();
+        let files = vec![
+            (
+                "./0.10.9/scripts/entities.xml".to_string(),
+                b"<root/>".to_vec(),
+            ),
+            ("scripts/empty.def".to_string(), vec![]),
+            ("scripts/big.def".to_string(), vec![7; 1000]),
+        ];
+        let archive = zip(&files);
+        let source = ZipSource::from_bytes(&archive, version).unwrap();
+        assert!(source.has_version(&version).unwrap());
+        let get = |path: &str| source.get(&version, path).unwrap().map(|d| d.to_vec());
+        assert_eq!(get("scripts/entities.xml"), Some(b"<root/>".to_vec()));
+        assert_eq!(get("scripts/empty.def"), Some(vec![]));
+        assert_eq!(get("scripts/big.def"), Some(vec![7; 1000]));
+        assert_eq!(get("scripts/missing.def"), None);
+        let other = "0,10,10,0".parse::<Version>().unwrap();
+        assert!(source
+            .get(&other, "scripts/entities.xml")
+            .unwrap()
+            .is_none());
+
+        // The source is identified by the archive's contents
+        assert!(source.source_id().starts_with("zip:"));
+        assert_ne!(
+            source.source_id(),
+            ZipSource::from_bytes(&zip(&files[..1]), version)
+                .unwrap()
+                .source_id()
+        );
+
+        let mut truncated = archive.clone();
+        truncated.truncate(archive.len() / 2);
+        assert!(matches!(
+            ZipSource::from_bytes(&truncated, version),
+            Err(ErrorKind::BadArchive { .. })
+        ));
+        assert!(matches!(
+            ZipSource::from_bytes(b"not a zip", version),
+            Err(ErrorKind::BadArchive { .. })
+        ));
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn parse_scripts_from_zip() {
+        let version = "0,10,9,0".parse::<Version>().unwrap();
+        let root = PathBuf::from("../versions/0.10.9");
+        let mut files = vec![];
+        for entry in walkdir(&root) {
+            let name = entry.strip_prefix(&root).unwrap().to_str().unwrap();
+            files.push((name.replace('\\', "/"), std::fs::read(&entry).unwrap()));
+        }
+        let source = ZipSource::from_bytes(&zip(&files), version).unwrap();
+        let datafiles =
+            Datafiles::from_sources(vec![Arc::new(source)], version, VersionPolicy::Exact).unwrap();
+        let from_zip = crate::parse_scripts(&datafiles).unwrap();
+
+        let datafiles = Datafiles::new(PathBuf::from("../versions"), version).unwrap();
+        let from_dir = crate::parse_scripts(&datafiles).unwrap();
+        assert_eq!(
+            serde_json::to_value(&from_zip).unwrap(),
+            serde_json::to_value(&from_dir).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "tar")]
+    fn parse_scripts_from_tar() {
+        let version = "0,10,9,0".parse::<Version>().unwrap();
+        let root = PathBuf::from("../versions/0.10.9");
+        let mut files = vec![];
+        for entry in walkdir(&root) {
+            let name = entry.strip_prefix(&root).unwrap().to_str().unwrap();
+            files.push((name.replace('\\', "/"), std::fs::read(&entry).unwrap()));
+        }
+        let source = TarSource::from_bytes(&tar(&files), version).unwrap();
+        let datafiles =
+            Datafiles::from_sources(vec![Arc::new(source)], version, VersionPolicy::Exact).unwrap();
+        let from_tar = crate::parse_scripts(&datafiles).unwrap();
+
+        let datafiles = Datafiles::new(PathBuf::from("../versions"), version).unwrap();
+        let from_dir = crate::parse_scripts(&datafiles).unwrap();
+        assert_eq!(
+            serde_json::to_value(&from_tar).unwrap(),
+            serde_json::to_value(&from_dir).unwrap()
+        );
+    }
+
+    #[cfg(any(feature = "tar", feature = "zip"))]
+    fn walkdir(dir: &Path) -> Vec<PathBuf> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(walkdir(&path));
+            } else {
+                files.push(path);
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn directory_source_id() {
+        let base =
+            std::env::temp_dir().join(format!("wows-replays-source-id-{}", std::process::id()));
+        let scripts = base.join("0.10.9").join("scripts");
+        std::fs::create_dir_all(scripts.join("entity_defs")).unwrap();
+        std::fs::write(scripts.join("entities.xml"), "<root/>").unwrap();
+        std::fs::write(scripts.join("entity_defs/Avatar.def"), "<root/>").unwrap();
+
+        let source = DirectorySource::new(base.clone());
+        let before = source.source_id();
+        assert_eq!(before, source.source_id());
+
+        // Updating any of the scripts in place changes the ID
+        std::fs::write(scripts.join("entity_defs/Avatar.def"), "<root></root>").unwrap();
+        let after = source.source_id();
+        std::fs::remove_dir_all(&base).unwrap();
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn directory_versions() {
+        let source = DirectorySource::new(PathBuf::from("../versions"));
+        let versions = source.versions().unwrap();
+        assert!(versions.iter().any(|v| v.to_path() == "0.10.9"));
+        assert!(DirectorySource::new(PathBuf::from("does-not-exist"))
+            .versions()
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -23,6 +23,10 @@ pub enum ErrorKind {
         #[from]
         err: std::str::Utf8Error,
     },
+    #[error("Unable to read data file archive")]
+    BadArchive { reason: String },
+    #[error("Unable to parse version string")]
+    BadVersion { version: String },
     #[error("Unsupported replay file version found")]
     UnsupportedReplayVersion(String),
     #[error("Unable to process packet")]
@@ -40,6 +44,8 @@ pub enum ErrorKind {
         packet: Vec<u8>,
         error: String,
     },
+    #[error("Could not encode RPC value")]
+    UnableToEncodeRpcValue { argtype: String, error: String },
     #[error("Unknown FixedDict flag")]
     UnknownFixedDictFlag { flag: u8, packet: Vec<u8> },
     #[error("Internal prop set on unsupported entity")]
@@ -53,10 +59,64 @@ pub enum ErrorKind {
         version: crate::version::Version,
         path: String,
     },
+    #[error("Entity definition interfaces implement each other")]
+    InterfaceCycle {
+        /// The chain of interfaces, starting and ending with the same interface
+        interfaces: Vec<String>,
+    },
+    #[error("Malformed entity definition file")]
+    InvalidEntityDef { path: String, reason: String },
+    #[error("Unable to parse user data object")]
+    InvalidUserDataObject { reason: String },
     #[error("Decoder ring failure")]
     DecoderRingFailure(String),
     #[error("Unable to process packet")]
     ParsingFailure(String),
+    #[error("Unexpected end of input")]
+    Incomplete,
+    #[error("I/O error reading replay")]
+    Io {
+        #[from]
+        err: std::io::Error,
+    },
+    #[error("Replay file has an unrecognized magic number")]
+    BadMagic { magic: u32 },
+    #[error("Replay file header is truncated")]
+    TruncatedHeader,
+    #[error("Unable to parse replay metadata")]
+    BadMeta { err: serde_json::Error },
+    #[error("Encrypted replay data is not a multiple of the block size")]
+    BadEncryptedLength { length: usize },
+    #[error("Unable to decompress replay data")]
+    Zlib { err: std::io::Error },
+    #[error("Packet refers to an entity which was never created")]
+    UnknownEntity { entity_id: u32 },
+    #[error("Packet refers to an unknown entity type")]
+    UnknownEntityType { entity_type: u16 },
+    #[error("Packet refers to an unknown entity property")]
+    UnknownProperty { entity_type: String, index: usize },
+    #[error("Packet refers to an unknown entity method")]
+    UnknownMethod { entity_type: String, index: usize },
+    #[error("Packet contains a field with an unexpected value")]
+    UnexpectedPacketValue { field: &'static str, value: u64 },
+    #[error("Packet has an unexpected length")]
+    UnexpectedPacketLength { expected: usize, actual: usize },
+    #[error("Unable to encode packet")]
+    UnableToEncodePacket { reason: String },
+    #[error("Unable to apply nested property update")]
+    BadNestedPropertyUpdate { reason: String },
+    #[error("Unable to parse packet")]
+    BadPacket {
+        /// Offset of the packet within the packet stream
+        offset: usize,
+        /// The clock of the packet, if the packet header could be read
+        clock: Option<f32>,
+        err: Box<ErrorKind>,
+    },
+    #[error("Clip range must be finite and end after it starts")]
+    BadClipRange { from: f32, to: f32 },
+    #[error("Packet has a clock which isn't a finite number")]
+    NonFiniteClock { offset: usize, clock: f32 },
 }
 
 impl nom::error::ParseError<&[u8]> for Error {
@@ -81,9 +141,7 @@ impl nom::error::ParseError<&[u8]> for Error {
 impl std::convert::From<nom::Err<Error>> for ErrorKind {
     fn from(x: nom::Err<Error>) -> ErrorKind {
         match x {
-            nom::Err::<Error>::Incomplete(_) => {
-                panic!("We can't handle incomplete replay files");
-            }
+            nom::Err::<Error>::Incomplete(_) => ErrorKind::Incomplete,
             nom::Err::<Error>::Error(e) => e.kind,
             nom::Err::<Error>::Failure(e) => e.kind,
         }
@@ -0,0 +1,216 @@
+//! Random access into a replay's packet stream.
+//!
+//! Reconstructing the state of the game at some clock requires parsing every packet before it,
+//! since entities are only sent in full when they're created. A [ReplayIndex] parses the replay
+//! once and keeps periodic snapshots of the [World], so that seeking only has to parse the
+//! packets since the nearest snapshot.
+
+use std::convert::TryInto;
+
+use crate::error::*;
+use crate::packet2::Parser;
+use crate::rpc::entitydefs::EntitySpec;
+use crate::world::World;
+
+/// The state of the world at a point in the packet stream
+pub struct Snapshot<'argtype> {
+    /// Every packet with a clock before this one has been applied to the world
+    pub clock: f32,
+    /// Offset of the first packet not applied to the world
+    pub offset: usize,
+    pub world: World<'argtype>,
+}
+
+/// Snapshots of the world at regular intervals of the game clock
+pub struct ReplayIndex<'replay, 'argtype> {
+    specs: &'argtype [EntitySpec],
+    packet_data: &'replay [u8],
+    snapshots: Vec<Snapshot<'argtype>>,
+}
+
+/// Past this many intervals, f64 can no longer tell consecutive snapshot clocks apart
+const MAX_INTERVALS: f64 = (1u64 << 52) as f64;
+
+/// Returns the clock of the next packet in the stream, without parsing it
+fn peek_clock(i: &[u8]) -> Option<f32> {
+    let clock = i.get(8..12)?;
+    Some(f32::from_le_bytes(clock.try_into().unwrap()))
+}
+
+impl<'replay, 'argtype> ReplayIndex<'replay, 'argtype> {
+    /// Parses the whole packet stream, taking a snapshot every `interval` seconds of clock.
+    /// If no packets fall within an interval, the snapshots for it are skipped rather than
+    /// repeated. Packets with an infinite or NaN clock are rejected.
+    pub fn build(
+        specs: &'argtype [EntitySpec],
+        packet_data: &'replay [u8],
+        interval: f32,
+    ) -> Result<Self, ErrorKind> {
+        let mut parser = Parser::new(specs);
+        let mut snapshots = vec![Snapshot {
+            clock: 0.0,
+            offset: 0,
+            world: World::default(),
+        }];
+        // Without a positive interval, the only snapshot is the one at the start
+        let interval = interval as f64;
+        let mut next_snapshot = if interval > 0.0 {
+            interval
+        } else {
+            f64::INFINITY
+        };
+        let mut i = packet_data;
+        while let Some(clock) = peek_clock(i) {
+            let offset = packet_data.len() - i.len();
+            if !clock.is_finite() {
+                return Err(ErrorKind::NonFiniteClock { offset, clock });
+            }
+            if clock as f64 >= next_snapshot {
+                // Take one snapshot at the last interval boundary before this packet, since
+                // the world is the same at every boundary since the previous packet
+                let n = (clock as f64 / interval).floor().min(MAX_INTERVALS);
+                snapshots.push(Snapshot {
+                    clock: (n * interval) as f32,
+                    offset,
+                    world: parser.world().clone(),
+                });
+                next_snapshot = if n < MAX_INTERVALS {
+                    (n + 1.0) * interval
+                } else {
+                    f64::INFINITY
+                };
+            }
+            let (remaining, _) = parser.parse_packet(i)?;
+            i = remaining;
+        }
+        if !i.is_empty() {
+            // Let the parser report the truncated packet
+            parser.parse_packet(i)?;
+        }
+        Ok(Self {
+            specs,
+            packet_data,
+            snapshots,
+        })
+    }
+
+    pub fn snapshots(&self) -> &[Snapshot<'argtype>] {
+        &self.snapshots
+    }
+
+    /// Returns a parser which has parsed every packet with a clock up to and including
+    /// `clock`, and the rest of the packet stream for it to continue parsing.
+    pub fn seek(&self, clock: f32) -> Result<(Parser<'argtype>, &'replay [u8]), ErrorKind> {
+        let idx = self
+            .snapshots
+            .partition_point(|snapshot| snapshot.clock <= clock)
+            .max(1);
+        let snapshot = &self.snapshots[idx - 1];
+        let mut parser = Parser::resume(self.specs, snapshot.world.clone(), snapshot.offset);
+        let mut i = &self.packet_data[snapshot.offset..];
+        while peek_clock(i).map(|c| c <= clock).unwrap_or(false) {
+            let (remaining, _) = parser.parse_packet(i)?;
+            i = remaining;
+        }
+        Ok((parser, i))
+    }
+
+    /// Returns the state of the world after every packet up to and including `clock`
+    pub fn state_at(&self, clock: f32) -> Result<World<'argtype>, ErrorKind> {
+        let (parser, _) = self.seek(clock)?;
+        Ok(parser.world)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::version::{Datafiles, Version};
+
+    fn assert_same_world(a: &World, b: &World) {
+        assert_eq!(a.entities().count(), b.entities().count());
+        for (entity_id, entity) in a.entities() {
+            let expected = b.entity(entity_id).unwrap();
+            assert_eq!(entity.type_name(), expected.type_name());
+            assert_eq!(entity.properties, expected.properties);
+            assert_eq!(entity.position().x, expected.position().x);
+            assert_eq!(entity.is_present(), expected.is_present());
+        }
+    }
+
+    #[test]
+    fn seek_test_replay() {
+        let replay = crate::ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let datafiles = Datafiles::new(
+            std::path::PathBuf::from("../versions"),
+            replay.meta.clientVersionFromExe.parse::<Version>().unwrap(),
+        )
+        .unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+
+        let index = ReplayIndex::build(&specs, &replay.packet_data, 10.0).unwrap();
+        assert!(index.snapshots().len() > 10);
+        for pair in index.snapshots().windows(2) {
+            assert_eq!(pair[1].clock - pair[0].clock, 10.0);
+            assert!(pair[1].offset >= pair[0].offset);
+        }
+
+        for clock in [0.0, 5.0, 123.4, 300.0, 605.5, 10000.0] {
+            let (parser, remaining) = index.seek(clock).unwrap();
+
+            // Compare against parsing from the start
+            let mut expected = Parser::new(&specs);
+            let mut i = &replay.packet_data[..];
+            while peek_clock(i).map(|c| c <= clock).unwrap_or(false) {
+                i = expected.parse_packet(i).unwrap().0;
+            }
+            assert_eq!(remaining.len(), i.len());
+            assert_same_world(parser.world(), expected.world());
+            assert_same_world(&index.state_at(clock).unwrap(), expected.world());
+
+            // Parsing can continue from where the seek left off
+            let mut parser = parser;
+            assert_eq!(
+                parser.packets(remaining).count(),
+                expected.packets(i).count()
+            );
+        }
+    }
+
+    /// Returns a packet stream of empty packets with the given clocks
+    fn packets_at(clocks: &[f32]) -> Vec<u8> {
+        let mut data = vec![];
+        for clock in clocks.iter() {
+            data.extend_from_slice(&[0, 0, 0, 0, 0xff, 0, 0, 0]);
+            data.extend_from_slice(&clock.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn large_clocks() {
+        let specs = vec![];
+
+        // A long gap between packets only takes one snapshot
+        let data = packets_at(&[0.5, 1e9, 1e9, 2e9]);
+        let index = ReplayIndex::build(&specs, &data, 1.0).unwrap();
+        let clocks: Vec<_> = index.snapshots().iter().map(|s| s.clock).collect();
+        assert_eq!(clocks, vec![0.0, 1e9, 2e9]);
+        assert_eq!(index.snapshots()[1].offset, 12);
+
+        // Clocks too large for the interval to make progress stop taking snapshots
+        let index = ReplayIndex::build(&specs, &data, 1e-30).unwrap();
+        assert_eq!(index.snapshots().len(), 2);
+
+        for clock in [f32::INFINITY, f32::NEG_INFINITY, f32::NAN].iter() {
+            let data = packets_at(&[0.5, *clock]);
+            assert!(matches!(
+                ReplayIndex::build(&specs, &data, 1.0),
+                Err(ErrorKind::NonFiniteClock { offset: 12, .. })
+            ));
+        }
+    }
+}
@@ -1,11 +1,18 @@
 pub mod analyzer;
+pub mod anonymizer;
+pub mod cut;
+pub mod datafile_source;
 mod error;
-mod nested_property_path;
+pub mod index;
+pub mod nested_property_path;
 pub mod packet2;
 pub mod rpc;
+pub mod spec_cache;
 pub mod version;
+pub mod world;
 mod wowsreplay;
 
 pub use error::*;
 pub use rpc::entitydefs::parse_scripts;
+pub use spec_cache::SpecCache;
 pub use wowsreplay::*;
@@ -1,14 +1,15 @@
-use crate::rpc::typedefs::{ArgType, ArgValue};
+use crate::error::ErrorKind;
+use crate::rpc::typedefs::{ArgType, ArgValue, OwnedArgValue};
 use bitreader::BitReader;
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub enum PropertyNestLevel<'argtype> {
     ArrayIndex(usize),
     DictKey(&'argtype str),
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub enum UpdateAction<'argtype> {
     SetKey {
         key: &'argtype str,
@@ -29,12 +30,98 @@ pub enum UpdateAction<'argtype> {
     },
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct PropertyNesting<'argtype> {
     pub levels: Vec<PropertyNestLevel<'argtype>>,
     pub action: UpdateAction<'argtype>,
 }
 
+/// An owned version of [PropertyNestLevel]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OwnedPropertyNestLevel {
+    ArrayIndex(usize),
+    DictKey(String),
+}
+
+/// An owned version of [UpdateAction]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OwnedUpdateAction {
+    SetKey {
+        key: String,
+        value: OwnedArgValue,
+    },
+    SetRange {
+        start: usize,
+        stop: usize,
+        values: Vec<OwnedArgValue>,
+    },
+    SetElement {
+        index: usize,
+        value: OwnedArgValue,
+    },
+    RemoveRange {
+        start: usize,
+        stop: usize,
+    },
+}
+
+/// An owned version of [PropertyNesting]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedPropertyNesting {
+    pub levels: Vec<OwnedPropertyNestLevel>,
+    pub action: OwnedUpdateAction,
+}
+
+impl<'argtype> From<&PropertyNesting<'argtype>> for OwnedPropertyNesting {
+    fn from(nesting: &PropertyNesting<'argtype>) -> Self {
+        Self {
+            levels: nesting
+                .levels
+                .iter()
+                .map(|level| match level {
+                    PropertyNestLevel::ArrayIndex(idx) => OwnedPropertyNestLevel::ArrayIndex(*idx),
+                    PropertyNestLevel::DictKey(key) => {
+                        OwnedPropertyNestLevel::DictKey(key.to_string())
+                    }
+                })
+                .collect(),
+            action: match &nesting.action {
+                UpdateAction::SetKey { key, value } => OwnedUpdateAction::SetKey {
+                    key: key.to_string(),
+                    value: value.into(),
+                },
+                UpdateAction::SetRange {
+                    start,
+                    stop,
+                    values,
+                } => OwnedUpdateAction::SetRange {
+                    start: *start,
+                    stop: *stop,
+                    values: values.iter().map(|v| v.into()).collect(),
+                },
+                UpdateAction::SetElement { index, value } => OwnedUpdateAction::SetElement {
+                    index: *index,
+                    value: value.into(),
+                },
+                UpdateAction::RemoveRange { start, stop } => OwnedUpdateAction::RemoveRange {
+                    start: *start,
+                    stop: *stop,
+                },
+            },
+        }
+    }
+}
+
+impl<'argtype> UpdateAction<'argtype> {
+    /// Whether the update is sent as a slice update, which replaces a range of elements
+    pub fn is_slice(&self) -> bool {
+        matches!(
+            self,
+            UpdateAction::SetRange { .. } | UpdateAction::RemoveRange { .. }
+        )
+    }
+}
+
 /// This function emulates Python's slice semantics
 fn slice_insert<T>(idx1: usize, idx2: usize, target: &mut Vec<T>, mut source: Vec<T>) {
     // First we delete target[idx1..idx2]
@@ -51,46 +138,112 @@ fn slice_insert<T>(idx1: usize, idx2: usize, target: &mut Vec<T>, mut source: Ve
     }
 }
 
+fn bad_update(reason: String) -> ErrorKind {
+    ErrorKind::BadNestedPropertyUpdate { reason }
+}
+
+fn read_bits(reader: &mut BitReader, bits: u8) -> Result<u8, ErrorKind> {
+    reader
+        .read_u8(bits)
+        .map_err(|e| bad_update(format!("{:?}", e)))
+}
+
+/// Skips to the next byte boundary and returns the rest of the payload
+fn read_remaining(reader: &mut BitReader) -> Result<Vec<u8>, ErrorKind> {
+    while reader.remaining() % 8 != 0 {
+        read_bits(reader, 1)?;
+    }
+    let mut remaining = vec![0; reader.remaining() as usize / 8];
+    reader
+        .read_u8_slice(&mut remaining[..])
+        .map_err(|e| bad_update(format!("{:?}", e)))?;
+    Ok(remaining)
+}
+
+/// Writes bits most significant bit first, the same order that [BitReader] reads them in
+pub(crate) struct BitWriter {
+    data: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    pub(crate) fn new() -> Self {
+        Self {
+            data: vec![],
+            bits: 0,
+        }
+    }
+
+    pub(crate) fn write(&mut self, value: usize, bits: u32) {
+        for bit in (0..bits).rev() {
+            if self.data.len() * 8 == self.bits {
+                self.data.push(0);
+            }
+            if (value >> bit) & 1 == 1 {
+                let last = self.data.len() - 1;
+                self.data[last] |= 0x80 >> (self.bits % 8);
+            }
+            self.bits += 1;
+        }
+    }
+
+    /// Pads to the next byte boundary with zeros and returns the bytes written
+    fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// The number of bits used to index into a container with `len` entries
+pub(crate) fn index_bits(len: usize) -> u32 {
+    len.next_power_of_two().trailing_zeros()
+}
+
+fn parse_value<'argtype>(t: &'argtype ArgType, i: &[u8]) -> Result<ArgValue<'argtype>, ErrorKind> {
+    let (_, value) = t.parse_value(i).map_err(ErrorKind::from)?;
+    Ok(value)
+}
+
 fn nested_update_command<'argtype>(
     is_slice: bool,
     t: &'argtype ArgType,
     mut prop_value: &mut ArgValue<'argtype>,
     mut reader: BitReader,
-) -> PropertyNesting<'argtype> {
+) -> Result<PropertyNesting<'argtype>, ErrorKind> {
     match (t, &mut prop_value) {
         (ArgType::FixedDict((_, entries)), _) => {
-            let entry_idx = reader
-                .read_u8(entries.len().next_power_of_two().trailing_zeros() as u8)
-                .unwrap();
-            while reader.remaining() % 8 != 0 {
-                reader.read_u8(1).unwrap();
-            }
-            let mut remaining = vec![0; reader.remaining() as usize / 8];
-            reader.read_u8_slice(&mut remaining[..]).unwrap();
-            assert!(reader.remaining() == 0);
-            let (_, value) = entries[entry_idx as usize]
-                .prop_type
-                .parse_value(&remaining[..])
-                .unwrap();
+            let entry_idx = read_bits(
+                &mut reader,
+                entries.len().next_power_of_two().trailing_zeros() as u8,
+            )?;
+            let entry = entries
+                .get(entry_idx as usize)
+                .ok_or_else(|| bad_update(format!("FixedDict has no entry {}", entry_idx)))?;
+            let remaining = read_remaining(&mut reader)?;
+            let value = parse_value(&entry.prop_type, &remaining[..])?;
             match prop_value {
                 ArgValue::FixedDict(d) => {
-                    d.insert(&entries[entry_idx as usize].name, value.clone());
+                    d.insert(&entry.name, value.clone());
                 }
                 ArgValue::NullableFixedDict(Some(d)) => {
-                    d.insert(&entries[entry_idx as usize].name, value.clone());
+                    d.insert(&entry.name, value.clone());
+                }
+                _ => {
+                    return Err(bad_update(format!(
+                        "FixedDict type caused unexpected value {:?}",
+                        prop_value
+                    )));
                 }
-                ArgValue::NullableFixedDict(None) => unimplemented!(),
-                _ => panic!("FixedDict type caused unexpected value {:?}", prop_value),
             }
-            return PropertyNesting {
+            Ok(PropertyNesting {
                 levels: vec![],
                 action: UpdateAction::SetKey {
-                    key: &entries[entry_idx as usize].name,
+                    key: &entry.name,
                     value: value,
                 },
-            };
+            })
         }
-        (ArgType::Array((_size, element_type)), ArgValue::Array(ref mut elements)) => {
+        (ArgType::Array((_size, element_type)), ArgValue::Array(ref mut elements))
+        | (ArgType::Tuple((element_type, _size)), ArgValue::Tuple(ref mut elements)) => {
             let idx_bits = if is_slice {
                 elements.len() + 1
             } else {
@@ -98,154 +251,304 @@ fn nested_update_command<'argtype>(
             }
             .next_power_of_two()
             .trailing_zeros();
-            let idx1 = reader.read_u8(idx_bits as u8).unwrap();
+            let idx1 = read_bits(&mut reader, idx_bits as u8)? as usize;
             let idx2 = if is_slice {
-                Some(reader.read_u8(idx_bits as u8).unwrap())
+                read_bits(&mut reader, idx_bits as u8)? as usize
             } else {
-                None
+                idx1
             };
 
-            while reader.remaining() % 8 != 0 {
-                reader.read_u8(1).unwrap();
-            }
-            let mut remaining = vec![0; reader.remaining() as usize / 8];
-            reader.read_u8_slice(&mut remaining[..]).unwrap();
+            let remaining = read_remaining(&mut reader)?;
 
-            if remaining.len() == 0 {
+            if remaining.is_empty() {
                 // Remove elements
-                if is_slice {
-                    slice_insert(idx1 as usize, idx2.unwrap() as usize, elements, vec![]);
-                    return PropertyNesting {
-                        levels: vec![],
-                        action: UpdateAction::RemoveRange {
-                            start: idx1 as usize,
-                            stop: idx2.unwrap() as usize,
-                        },
-                    };
-                } else {
-                    unimplemented!();
+                if !is_slice {
+                    return Err(bad_update(format!(
+                        "Array element {} update has no value",
+                        idx1
+                    )));
                 }
+                slice_insert(idx1, idx2, elements, vec![]);
+                return Ok(PropertyNesting {
+                    levels: vec![],
+                    action: UpdateAction::RemoveRange {
+                        start: idx1,
+                        stop: idx2,
+                    },
+                });
             }
 
             let mut new_elements = vec![];
             let mut i = &remaining[..];
-            while i.len() > 0 {
-                let (new_i, element) = element_type.parse_value(i).unwrap();
+            while !i.is_empty() {
+                let (new_i, element) = element_type.parse_value(i).map_err(ErrorKind::from)?;
                 i = new_i;
                 new_elements.push(element);
             }
 
             if is_slice {
-                slice_insert(
-                    idx1 as usize,
-                    idx2.unwrap() as usize,
-                    elements,
-                    new_elements.clone(),
-                );
-                return PropertyNesting {
+                slice_insert(idx1, idx2, elements, new_elements.clone());
+                Ok(PropertyNesting {
                     levels: vec![],
                     action: UpdateAction::SetRange {
-                        start: idx1 as usize,
-                        stop: idx2.unwrap() as usize,
+                        start: idx1,
+                        stop: idx2,
                         values: new_elements,
                     },
-                };
+                })
             } else {
-                elements[idx1 as usize] = new_elements.remove(0);
-                return PropertyNesting {
+                let element = elements
+                    .get_mut(idx1)
+                    .ok_or_else(|| bad_update(format!("Array has no element {}", idx1)))?;
+                *element = new_elements.remove(0);
+                Ok(PropertyNesting {
                     levels: vec![],
                     action: UpdateAction::SetElement {
-                        index: idx1 as usize,
-                        value: elements[idx1 as usize].clone(),
+                        index: idx1,
+                        value: element.clone(),
                     },
-                };
+                })
             }
         }
-        x => {
-            println!("{:#?}", x);
-            panic!();
-        }
+        (t, value) => Err(bad_update(format!(
+            "Cannot update {:?} with type {:?}",
+            value, t
+        ))),
     }
 }
 
-pub fn get_nested_prop_path_helper<'argtype>(
+/// Follows the path in the nested property update to the property being updated, and applies
+/// the update to it. Returns an error if the update doesn't match the property's type or value.
+pub(crate) fn get_nested_prop_path_helper<'argtype>(
     is_slice: bool,
     t: &'argtype ArgType,
     prop_value: &mut ArgValue<'argtype>,
     mut reader: BitReader,
-) -> PropertyNesting<'argtype> {
-    let cont = reader.read_u8(1).unwrap();
+) -> Result<PropertyNesting<'argtype>, ErrorKind> {
+    let cont = read_bits(&mut reader, 1)?;
     if cont == 0 {
         return nested_update_command(is_slice, t, prop_value, reader);
     }
-    //println!("{} {} {}", cont, prop_idx, cont2);
     match (t, prop_value) {
         (
             crate::rpc::typedefs::ArgType::FixedDict((_, propspec)),
             ArgValue::FixedDict(propvalue),
-        ) => {
-            let prop_idx = reader
-                .read_u8(propspec.len().next_power_of_two().trailing_zeros() as u8)
-                .unwrap();
-            let prop_id = &propspec[prop_idx as usize].name;
-            //let cont = reader.read_u8(1).unwrap();
-            //println!("{:#?}", propspec[prop_idx as usize]);
-            let mut nesting = get_nested_prop_path_helper(
-                is_slice,
-                &propspec[prop_idx as usize].prop_type,
-                propvalue.get_mut(prop_id.as_str()).unwrap(),
-                reader,
-            );
-            nesting.levels.insert(
-                0,
-                PropertyNestLevel::DictKey(&propspec[prop_idx as usize].name),
-            );
-            return nesting;
-        }
-        (
+        )
+        | (
             crate::rpc::typedefs::ArgType::FixedDict((_, propspec)),
             ArgValue::NullableFixedDict(Some(propvalue)),
         ) => {
-            let prop_idx = reader
-                .read_u8(propspec.len().next_power_of_two().trailing_zeros() as u8)
-                .unwrap();
-            let prop_id = &propspec[prop_idx as usize].name;
-            //let cont = reader.read_u8(1).unwrap();
-            //println!("{:#?}", propspec[prop_idx as usize]);
-            let mut nesting = get_nested_prop_path_helper(
-                is_slice,
-                &propspec[prop_idx as usize].prop_type,
-                propvalue.get_mut(prop_id.as_str()).unwrap(),
-                reader,
-            );
-            nesting.levels.insert(
-                0,
-                PropertyNestLevel::DictKey(&propspec[prop_idx as usize].name),
-            );
-            return nesting;
-        }
-        (crate::rpc::typedefs::ArgType::Array((_size, element_type)), ArgValue::Array(arr)) => {
-            let idx = reader
-                .read_u8(arr.len().next_power_of_two().trailing_zeros() as u8)
-                .unwrap();
+            let prop_idx = read_bits(
+                &mut reader,
+                propspec.len().next_power_of_two().trailing_zeros() as u8,
+            )?;
+            let prop = propspec
+                .get(prop_idx as usize)
+                .ok_or_else(|| bad_update(format!("FixedDict has no entry {}", prop_idx)))?;
+            let value = propvalue
+                .get_mut(prop.name.as_str())
+                .ok_or_else(|| bad_update(format!("FixedDict value has no key {}", prop.name)))?;
             let mut nesting =
-                get_nested_prop_path_helper(is_slice, element_type, &mut arr[idx as usize], reader);
+                get_nested_prop_path_helper(is_slice, &prop.prop_type, value, reader)?;
+            nesting
+                .levels
+                .insert(0, PropertyNestLevel::DictKey(&prop.name));
+            Ok(nesting)
+        }
+        (crate::rpc::typedefs::ArgType::Array((_size, element_type)), ArgValue::Array(arr))
+        | (crate::rpc::typedefs::ArgType::Tuple((element_type, _size)), ArgValue::Tuple(arr)) => {
+            let idx = read_bits(
+                &mut reader,
+                arr.len().next_power_of_two().trailing_zeros() as u8,
+            )?;
+            let element = arr
+                .get_mut(idx as usize)
+                .ok_or_else(|| bad_update(format!("Array has no element {}", idx)))?;
+            let mut nesting = get_nested_prop_path_helper(is_slice, element_type, element, reader)?;
             nesting
                 .levels
                 .insert(0, PropertyNestLevel::ArrayIndex(idx as usize));
-            return nesting;
+            Ok(nesting)
         }
-        x => {
-            println!("{:#?}", x);
-            panic!()
+        (t, value) => Err(bad_update(format!(
+            "Cannot follow path into {:?} with type {:?}",
+            value, t
+        ))),
+    }
+}
+
+fn encode_index(
+    writer: &mut BitWriter,
+    index: usize,
+    len: usize,
+    what: &str,
+) -> Result<(), ErrorKind> {
+    if index >= len {
+        return Err(bad_update(format!("{} has no element {}", what, index)));
+    }
+    writer.write(index, index_bits(len));
+    Ok(())
+}
+
+/// Encodes the path and action of a nested property update, the inverse of
+/// [get_nested_prop_path_helper]. `prop_value` is the value of the property before the update
+/// is applied, since it determines how many bits are used for each index.
+pub(crate) fn encode_nested_prop_path<'argtype>(
+    t: &'argtype ArgType,
+    prop_value: &ArgValue<'argtype>,
+    nesting: &PropertyNesting<'argtype>,
+    mut writer: BitWriter,
+) -> Result<Vec<u8>, ErrorKind> {
+    let mut t = t;
+    let mut prop_value = prop_value;
+    for level in nesting.levels.iter() {
+        writer.write(1, 1);
+        match (t, prop_value, level) {
+            (
+                ArgType::FixedDict((_, entries)),
+                ArgValue::FixedDict(d),
+                PropertyNestLevel::DictKey(key),
+            )
+            | (
+                ArgType::FixedDict((_, entries)),
+                ArgValue::NullableFixedDict(Some(d)),
+                PropertyNestLevel::DictKey(key),
+            ) => {
+                let idx = entries
+                    .iter()
+                    .position(|entry| entry.name == *key)
+                    .ok_or_else(|| bad_update(format!("FixedDict has no entry {}", key)))?;
+                writer.write(idx, index_bits(entries.len()));
+                t = &entries[idx].prop_type;
+                prop_value = d
+                    .get(*key)
+                    .ok_or_else(|| bad_update(format!("FixedDict value has no key {}", key)))?;
+            }
+            (
+                ArgType::Array((_size, element_type)),
+                ArgValue::Array(elements),
+                PropertyNestLevel::ArrayIndex(idx),
+            )
+            | (
+                ArgType::Tuple((element_type, _size)),
+                ArgValue::Tuple(elements),
+                PropertyNestLevel::ArrayIndex(idx),
+            ) => {
+                encode_index(&mut writer, *idx, elements.len(), "Array")?;
+                t = element_type;
+                prop_value = &elements[*idx];
+            }
+            (t, value, level) => {
+                return Err(bad_update(format!(
+                    "Cannot follow {:?} into {:?} with type {:?}",
+                    level, value, t
+                )));
+            }
+        }
+    }
+    writer.write(0, 1);
+
+    let mut values = vec![];
+    match (t, prop_value, &nesting.action) {
+        (ArgType::FixedDict((_, entries)), _, UpdateAction::SetKey { key, value }) => {
+            let idx = entries
+                .iter()
+                .position(|entry| entry.name == *key)
+                .ok_or_else(|| bad_update(format!("FixedDict has no entry {}", key)))?;
+            writer.write(idx, index_bits(entries.len()));
+            values.extend(entries[idx].prop_type.encode_value(value)?);
+        }
+        (ArgType::Array((_size, element_type)), ArgValue::Array(elements), action)
+        | (ArgType::Tuple((element_type, _size)), ArgValue::Tuple(elements), action) => {
+            match action {
+                UpdateAction::SetElement { index, value } => {
+                    encode_index(&mut writer, *index, elements.len(), "Array")?;
+                    values.extend(element_type.encode_value(value)?);
+                }
+                UpdateAction::SetRange {
+                    start,
+                    stop,
+                    values: new_elements,
+                } => {
+                    let bits = index_bits(elements.len() + 1);
+                    writer.write(*start, bits);
+                    writer.write(*stop, bits);
+                    for element in new_elements.iter() {
+                        values.extend(element_type.encode_value(element)?);
+                    }
+                }
+                UpdateAction::RemoveRange { start, stop } => {
+                    let bits = index_bits(elements.len() + 1);
+                    writer.write(*start, bits);
+                    writer.write(*stop, bits);
+                }
+                UpdateAction::SetKey { .. } => {
+                    return Err(bad_update(format!("Cannot set a key on {:?}", prop_value)));
+                }
+            }
+        }
+        (t, value, action) => {
+            return Err(bad_update(format!(
+                "Cannot apply {:?} to {:?} with type {:?}",
+                action, value, t
+            )));
         }
     }
+
+    let mut payload = writer.finish();
+    payload.extend(values);
+    Ok(payload)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn mismatched_update() {
+        let t = ArgType::Primitive(crate::rpc::typedefs::PrimitiveType::Uint8);
+        let mut value = ArgValue::Uint8(1);
+        let reader = BitReader::new(&[0x80]);
+        assert!(matches!(
+            get_nested_prop_path_helper(false, &t, &mut value, reader),
+            Err(ErrorKind::BadNestedPropertyUpdate { .. })
+        ));
+    }
+
+    #[test]
+    fn tuple_element_update() {
+        let element = ArgType::Primitive(crate::rpc::typedefs::PrimitiveType::Uint8);
+        let t = ArgType::FixedDict((
+            false,
+            vec![crate::rpc::typedefs::FixedDictProperty {
+                name: "pos".to_string(),
+                prop_type: ArgType::Tuple((Box::new(element), Some(2))),
+            }],
+        ));
+        let mut value = t.default_value();
+        // Follow the path into "pos", then set element 1
+        let reader = BitReader::new(&[0b1010_0000, 7]);
+        let nesting = get_nested_prop_path_helper(false, &t, &mut value, reader).unwrap();
+        assert!(matches!(
+            nesting.levels[..],
+            [PropertyNestLevel::DictKey("pos")]
+        ));
+        assert!(matches!(
+            nesting.action,
+            UpdateAction::SetElement {
+                index: 1,
+                value: ArgValue::Uint8(7)
+            }
+        ));
+        match value {
+            ArgValue::FixedDict(d) => assert_eq!(
+                d["pos"],
+                ArgValue::Tuple(vec![ArgValue::Uint8(0), ArgValue::Uint8(7)])
+            ),
+            _ => panic!(),
+        }
+    }
+
     #[test]
     fn slice_insert_single_into_empty() {
         let mut v: Vec<u32> = vec![];
@@ -1,18 +1,19 @@
 use nom::{
-    bytes::complete::take, number::complete::le_f32, number::complete::le_i32,
-    number::complete::le_i64, number::complete::le_u16, number::complete::le_u32,
-    number::complete::le_u8,
+    bytes::complete::take, number::complete::le_f32, number::complete::le_f64,
+    number::complete::le_i32, number::complete::le_i64, number::complete::le_u16,
+    number::complete::le_u32, number::complete::le_u8,
 };
 
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::convert::TryInto;
 
 use crate::error::*;
+use crate::nested_property_path::OwnedPropertyNesting;
 use crate::rpc::entitydefs::*;
-use crate::rpc::typedefs::ArgValue;
+use crate::rpc::typedefs::{ArgValue, OwnedArgValue};
+use crate::world::{Entity, World};
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Default, Serialize, Clone, Deserialize)]
 pub struct Vec3 {
     pub x: f32,
     pub y: f32,
@@ -26,9 +27,15 @@ impl Vec3 {
         let (i, z) = le_f32(i)?;
         Ok((i, Vec3 { x, y, z }))
     }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        for f in [self.x, self.y, self.z] {
+            out.extend_from_slice(&f.to_le_bytes());
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Default, Serialize, Clone, Deserialize)]
 pub struct Rot3 {
     pub roll: f32,
     pub pitch: f32,
@@ -42,9 +49,15 @@ impl Rot3 {
         let (i, yaw) = le_f32(i)?;
         Ok((i, Rot3 { roll, pitch, yaw }))
     }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        for f in [self.roll, self.pitch, self.yaw] {
+            out.extend_from_slice(&f.to_le_bytes());
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, Deserialize)]
 pub struct PositionPacket {
     pub pid: u32,
     pub position: Vec3,
@@ -72,6 +85,10 @@ pub struct EntityPropertyPacket<'argtype> {
 pub struct EntityMethodPacket<'argtype> {
     pub entity_id: u32,
     pub method: &'argtype str,
+    /// The component which the method belongs to, if it isn't one of the entity's own
+    pub component: Option<&'argtype str>,
+    /// Whether this is a call from the server to the client, or from the client to the server
+    pub direction: MethodDirection,
     pub args: Vec<ArgValue<'argtype>>,
 }
 
@@ -91,7 +108,7 @@ pub struct EntityCreatePacket<'argtype> {
 /// describes both the player's boat location/orientation as well as the
 /// camera orientation. When the camera is attached to an object, the ID of
 /// that object will be given in the parent_id field.
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, Deserialize)]
 pub struct PlayerOrientationPacket {
     pub pid: u32,
     pub parent_id: u32,
@@ -99,36 +116,67 @@ pub struct PlayerOrientationPacket {
     pub rotation: Rot3,
 }
 
+/// A packet which we were unable to parse
 #[derive(Debug, Serialize)]
 pub struct InvalidPacket<'a> {
-    message: String,
-    raw: &'a [u8],
+    /// Offset of the packet within the packet stream
+    pub offset: usize,
+    /// Why the packet couldn't be parsed. This is serialized as its description.
+    #[serde(serialize_with = "serialize_debug")]
+    pub error: Box<ErrorKind>,
+    pub raw: &'a [u8],
+}
+
+fn serialize_debug<T: std::fmt::Debug, S: serde::Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:?}", value))
+}
+
+fn serialize_debug_opt<T: std::fmt::Debug, S: serde::Serializer>(
+    value: &Option<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serialize_debug(value, serializer),
+        None => serializer.serialize_none(),
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct BasePlayerCreatePacket<'replay, 'argtype> {
     pub entity_id: u32,
     pub entity_type: &'argtype str,
+    /// The raw state, which contains the values of the `BASE_AND_CLIENT` properties
     pub state: &'replay [u8],
+    /// The properties decoded from the state
+    pub props: HashMap<&'argtype str, ArgValue<'argtype>>,
+    /// Why the state couldn't be fully decoded, in which case `props` only holds the
+    /// properties before the one which failed. This is serialized as its description.
+    #[serde(serialize_with = "serialize_debug_opt")]
+    pub error: Option<Box<ErrorKind>>,
 }
 
 #[derive(Debug, Serialize)]
-pub struct CellPlayerCreatePacket<'replay> {
+pub struct CellPlayerCreatePacket<'replay, 'argtype> {
     pub entity_id: u32,
     pub space_id: u32,
-    pub unknown: u16,
     pub vehicle_id: u32,
     pub position: Vec3,
     pub rotation: Rot3,
+    /// The internal properties of the player's entity, which are only sent to its own client
+    pub props: HashMap<&'argtype str, ArgValue<'argtype>>,
+    /// Any data following the internal properties
     pub value: &'replay [u8],
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EntityLeavePacket {
     pub entity_id: u32,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EntityEnterPacket {
     pub entity_id: u32,
     pub space_id: u32,
@@ -146,7 +194,7 @@ pub struct PropertyUpdatePacket<'argtype> {
     pub update_cmd: crate::nested_property_path::PropertyNesting<'argtype>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CameraPacket {
     pub unknown: Vec3,
     pub unknown2: u32,
@@ -156,12 +204,47 @@ pub struct CameraPacket {
     pub rotation: Rot3,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CruiseState {
     pub key: u32,
     pub value: i32,
 }
 
+/// Gives or takes away the client's control of an entity
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntityControlPacket {
+    pub entity_id: u32,
+    pub is_controlled: bool,
+}
+
+/// Sent once while the connection is being set up, for the player's avatar. The meaning of
+/// the other fields is unknown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AvatarInitPacket {
+    pub entity_id: u32,
+    pub unknown1: u32,
+    pub unknown2: u16,
+}
+
+/// Updates the position of an entity which doesn't send `Position` packets, such as a
+/// smoke screen.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntityPositionPacket {
+    pub entity_id: u32,
+    pub vehicle_id: u32,
+    pub position: Vec3,
+    pub rotation: Rot3,
+}
+
+/// Note: We suspect that this is sent when the player locks onto (or unlocks from) a target,
+/// since `target_id` is always a vehicle or zero.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetLockPacket {
+    pub unknown1: u32,
+    pub unknown2: u32,
+    pub target_id: u32,
+}
+
 #[derive(Debug, Serialize)]
 pub struct MapPacket<'replay> {
     pub space_id: u32,
@@ -180,7 +263,7 @@ pub struct MapPacket<'replay> {
 pub enum PacketType<'replay, 'argtype> {
     Position(PositionPacket),
     BasePlayerCreate(BasePlayerCreatePacket<'replay, 'argtype>),
-    CellPlayerCreate(CellPlayerCreatePacket<'replay>),
+    CellPlayerCreate(CellPlayerCreatePacket<'replay, 'argtype>),
     EntityEnter(EntityEnterPacket),
     EntityLeave(EntityLeavePacket),
     EntityCreate(EntityCreatePacket<'argtype>),
@@ -194,6 +277,25 @@ pub enum PacketType<'replay, 'argtype> {
     CameraMode(u32),
     CameraFreeLook(u8),
     Map(MapPacket<'replay>),
+    EntityControl(EntityControlPacket),
+    /// The player's own vehicle entity
+    OwnShip(u32),
+    /// Sent at the start of the game. This appears to be the server's clock, in seconds.
+    ServerTimestamp(f64),
+    /// Sent once per server tick, with the length of a tick in seconds (1/7th of a second)
+    ServerTick(f64),
+    /// Only seen while the connection is being set up, always with a value of zero
+    InitFlag(u8),
+    /// Sent after the player's own ship, when the connection has been set up
+    InitComplete,
+    AvatarInit(AvatarInitPacket),
+    /// Note: This is sent alongside the `Camera` packets, but so far has always been 10 zeros
+    /// followed by 3 -1s.
+    Camera18([f32; 13]),
+    EntityPosition(EntityPositionPacket),
+    TargetLock(TargetLockPacket),
+    /// The final packet in the stream, whose contents are unknown (perhaps a hash)
+    ReplayEnd([u8; 16]),
     Unknown(&'replay [u8]),
 
     /// These are packets which we thought we understood, but couldn't parse
@@ -209,26 +311,372 @@ pub struct Packet<'replay, 'argtype> {
     pub raw: &'replay [u8],
 }
 
-struct Entity<'argtype> {
-    entity_type: u16,
-    properties: Vec<ArgValue<'argtype>>,
+/// An owned version of [EntityPropertyPacket]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedEntityPropertyPacket {
+    pub entity_id: u32,
+    pub property: String,
+    pub value: OwnedArgValue,
+}
+
+impl<'argtype> From<&EntityPropertyPacket<'argtype>> for OwnedEntityPropertyPacket {
+    fn from(packet: &EntityPropertyPacket<'argtype>) -> Self {
+        Self {
+            entity_id: packet.entity_id,
+            property: packet.property.to_string(),
+            value: (&packet.value).into(),
+        }
+    }
+}
+
+/// An owned version of [EntityMethodPacket]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedEntityMethodPacket {
+    pub entity_id: u32,
+    pub method: String,
+    pub component: Option<String>,
+    pub direction: MethodDirection,
+    pub args: Vec<OwnedArgValue>,
+}
+
+impl<'argtype> From<&EntityMethodPacket<'argtype>> for OwnedEntityMethodPacket {
+    fn from(packet: &EntityMethodPacket<'argtype>) -> Self {
+        Self {
+            entity_id: packet.entity_id,
+            method: packet.method.to_string(),
+            component: packet.component.map(|component| component.to_string()),
+            direction: packet.direction,
+            args: packet.args.iter().map(|arg| arg.into()).collect(),
+        }
+    }
+}
+
+/// An owned version of [EntityCreatePacket]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedEntityCreatePacket {
+    pub entity_id: u32,
+    pub entity_type: String,
+    pub space_id: u32,
+    pub vehicle_id: u32,
+    pub position: Vec3,
+    pub rotation: Rot3,
+    pub state_length: u32,
+    pub props: HashMap<String, OwnedArgValue>,
+}
+
+impl<'argtype> From<&EntityCreatePacket<'argtype>> for OwnedEntityCreatePacket {
+    fn from(packet: &EntityCreatePacket<'argtype>) -> Self {
+        Self {
+            entity_id: packet.entity_id,
+            entity_type: packet.entity_type.to_string(),
+            space_id: packet.space_id,
+            vehicle_id: packet.vehicle_id,
+            position: packet.position.clone(),
+            rotation: packet.rotation.clone(),
+            state_length: packet.state_length,
+            props: packet
+                .props
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.into()))
+                .collect(),
+        }
+    }
+}
+
+/// An owned version of [InvalidPacket]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedInvalidPacket {
+    pub offset: usize,
+    /// The description of [InvalidPacket::error]
+    pub message: String,
+    pub raw: Vec<u8>,
+}
+
+impl<'a> From<&InvalidPacket<'a>> for OwnedInvalidPacket {
+    fn from(packet: &InvalidPacket<'a>) -> Self {
+        Self {
+            offset: packet.offset,
+            message: format!("{:?}", packet.error),
+            raw: packet.raw.to_vec(),
+        }
+    }
+}
+
+/// An owned version of [BasePlayerCreatePacket]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedBasePlayerCreatePacket {
+    pub entity_id: u32,
+    pub entity_type: String,
+    pub state: Vec<u8>,
+    pub props: HashMap<String, OwnedArgValue>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl<'replay, 'argtype> From<&BasePlayerCreatePacket<'replay, 'argtype>>
+    for OwnedBasePlayerCreatePacket
+{
+    fn from(packet: &BasePlayerCreatePacket<'replay, 'argtype>) -> Self {
+        Self {
+            entity_id: packet.entity_id,
+            entity_type: packet.entity_type.to_string(),
+            state: packet.state.to_vec(),
+            props: packet
+                .props
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.into()))
+                .collect(),
+            error: packet.error.as_ref().map(|e| format!("{:?}", e)),
+        }
+    }
+}
+
+/// An owned version of [CellPlayerCreatePacket]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedCellPlayerCreatePacket {
+    pub entity_id: u32,
+    pub space_id: u32,
+    pub vehicle_id: u32,
+    pub position: Vec3,
+    pub rotation: Rot3,
+    pub props: HashMap<String, OwnedArgValue>,
+    pub value: Vec<u8>,
+}
+
+impl<'replay, 'argtype> From<&CellPlayerCreatePacket<'replay, 'argtype>>
+    for OwnedCellPlayerCreatePacket
+{
+    fn from(packet: &CellPlayerCreatePacket<'replay, 'argtype>) -> Self {
+        Self {
+            entity_id: packet.entity_id,
+            space_id: packet.space_id,
+            vehicle_id: packet.vehicle_id,
+            position: packet.position.clone(),
+            rotation: packet.rotation.clone(),
+            props: packet
+                .props
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.into()))
+                .collect(),
+            value: packet.value.to_vec(),
+        }
+    }
+}
+
+/// An owned version of [PropertyUpdatePacket]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedPropertyUpdatePacket {
+    pub entity_id: i32,
+    pub property: String,
+    pub update_cmd: OwnedPropertyNesting,
+}
+
+impl<'argtype> From<&PropertyUpdatePacket<'argtype>> for OwnedPropertyUpdatePacket {
+    fn from(packet: &PropertyUpdatePacket<'argtype>) -> Self {
+        Self {
+            entity_id: packet.entity_id,
+            property: packet.property.to_string(),
+            update_cmd: (&packet.update_cmd).into(),
+        }
+    }
+}
+
+/// An owned version of [MapPacket]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedMapPacket {
+    pub space_id: u32,
+    pub arena_id: i64,
+    pub unknown1: u32,
+    pub unknown2: u32,
+    pub blob: Vec<u8>,
+    pub map_name: String,
+    pub matrix: Vec<u8>,
+    pub unknown: u8,
+}
+
+impl<'replay> From<&MapPacket<'replay>> for OwnedMapPacket {
+    fn from(packet: &MapPacket<'replay>) -> Self {
+        Self {
+            space_id: packet.space_id,
+            arena_id: packet.arena_id,
+            unknown1: packet.unknown1,
+            unknown2: packet.unknown2,
+            blob: packet.blob.to_vec(),
+            map_name: packet.map_name.to_string(),
+            matrix: packet.matrix.to_vec(),
+            unknown: packet.unknown,
+        }
+    }
+}
+
+/// An owned version of [PacketType]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OwnedPacketType {
+    Position(PositionPacket),
+    BasePlayerCreate(OwnedBasePlayerCreatePacket),
+    CellPlayerCreate(OwnedCellPlayerCreatePacket),
+    EntityEnter(EntityEnterPacket),
+    EntityLeave(EntityLeavePacket),
+    EntityCreate(OwnedEntityCreatePacket),
+    EntityProperty(OwnedEntityPropertyPacket),
+    EntityMethod(OwnedEntityMethodPacket),
+    PropertyUpdate(OwnedPropertyUpdatePacket),
+    PlayerOrientation(PlayerOrientationPacket),
+    CruiseState(CruiseState),
+    Version(String),
+    Camera(CameraPacket),
+    CameraMode(u32),
+    CameraFreeLook(u8),
+    Map(OwnedMapPacket),
+    EntityControl(EntityControlPacket),
+    OwnShip(u32),
+    ServerTimestamp(f64),
+    ServerTick(f64),
+    InitFlag(u8),
+    InitComplete,
+    AvatarInit(AvatarInitPacket),
+    Camera18([f32; 13]),
+    EntityPosition(EntityPositionPacket),
+    TargetLock(TargetLockPacket),
+    ReplayEnd([u8; 16]),
+    Unknown(Vec<u8>),
+    Invalid(OwnedInvalidPacket),
+}
+
+impl<'replay, 'argtype> PacketType<'replay, 'argtype> {
+    /// Copies everything borrowed from the replay and the entity specs into an [OwnedPacketType]
+    pub fn into_owned(self) -> OwnedPacketType {
+        match self {
+            Self::Position(p) => OwnedPacketType::Position(p),
+            Self::BasePlayerCreate(p) => OwnedPacketType::BasePlayerCreate((&p).into()),
+            Self::CellPlayerCreate(p) => OwnedPacketType::CellPlayerCreate((&p).into()),
+            Self::EntityEnter(p) => OwnedPacketType::EntityEnter(p),
+            Self::EntityLeave(p) => OwnedPacketType::EntityLeave(p),
+            Self::EntityCreate(p) => OwnedPacketType::EntityCreate((&p).into()),
+            Self::EntityProperty(p) => OwnedPacketType::EntityProperty((&p).into()),
+            Self::EntityMethod(p) => OwnedPacketType::EntityMethod((&p).into()),
+            Self::PropertyUpdate(p) => OwnedPacketType::PropertyUpdate((&p).into()),
+            Self::PlayerOrientation(p) => OwnedPacketType::PlayerOrientation(p),
+            Self::CruiseState(p) => OwnedPacketType::CruiseState(p),
+            Self::Version(p) => OwnedPacketType::Version(p),
+            Self::Camera(p) => OwnedPacketType::Camera(p),
+            Self::CameraMode(p) => OwnedPacketType::CameraMode(p),
+            Self::CameraFreeLook(p) => OwnedPacketType::CameraFreeLook(p),
+            Self::Map(p) => OwnedPacketType::Map((&p).into()),
+            Self::EntityControl(p) => OwnedPacketType::EntityControl(p),
+            Self::OwnShip(p) => OwnedPacketType::OwnShip(p),
+            Self::ServerTimestamp(p) => OwnedPacketType::ServerTimestamp(p),
+            Self::ServerTick(p) => OwnedPacketType::ServerTick(p),
+            Self::InitFlag(p) => OwnedPacketType::InitFlag(p),
+            Self::InitComplete => OwnedPacketType::InitComplete,
+            Self::AvatarInit(p) => OwnedPacketType::AvatarInit(p),
+            Self::Camera18(p) => OwnedPacketType::Camera18(p),
+            Self::EntityPosition(p) => OwnedPacketType::EntityPosition(p),
+            Self::TargetLock(p) => OwnedPacketType::TargetLock(p),
+            Self::ReplayEnd(p) => OwnedPacketType::ReplayEnd(p),
+            Self::Unknown(p) => OwnedPacketType::Unknown(p.to_vec()),
+            Self::Invalid(p) => OwnedPacketType::Invalid((&p).into()),
+        }
+    }
+}
+
+/// An owned version of [Packet], which can be stored, sent between threads and deserialized.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OwnedPacket {
+    pub packet_size: u32,
+    pub packet_type: u32,
+    pub clock: f32,
+    pub payload: OwnedPacketType,
+    pub raw: Vec<u8>,
+}
+
+impl<'replay, 'argtype> Packet<'replay, 'argtype> {
+    /// Copies everything borrowed from the replay and the entity specs into an [OwnedPacket]
+    pub fn into_owned(self) -> OwnedPacket {
+        OwnedPacket {
+            packet_size: self.packet_size,
+            packet_type: self.packet_type,
+            clock: self.clock,
+            payload: self.payload.into_owned(),
+            raw: self.raw.to_vec(),
+        }
+    }
+}
+
+fn property_spec(spec: &EntitySpec, index: usize) -> Result<&Property, nom::Err<Error>> {
+    spec.properties.get(index).ok_or_else(|| {
+        failure_from_kind(ErrorKind::UnknownProperty {
+            entity_type: spec.name.clone(),
+            index,
+        })
+    })
+}
+
+/// The error for a packet which can't be encoded, e.g. because it refers to an unknown entity
+fn unable_to_encode(reason: String) -> ErrorKind {
+    ErrorKind::UnableToEncodePacket { reason }
+}
+
+fn bad_packet(offset: usize, clock: Option<f32>, e: nom::Err<Error>) -> nom::Err<Error> {
+    failure_from_kind(ErrorKind::BadPacket {
+        offset,
+        clock,
+        err: Box::new(e.into()),
+    })
 }
 
 pub struct Parser<'argtype> {
-    specs: &'argtype Vec<EntitySpec>,
-    entities: HashMap<u32, Entity<'argtype>>,
+    pub(crate) specs: &'argtype [EntitySpec],
+    pub(crate) world: World<'argtype>,
+    /// Number of bytes of packet data parsed so far
+    offset: usize,
 }
 
 impl<'argtype> Parser<'argtype> {
-    pub fn new(entities: &'argtype Vec<EntitySpec>) -> Parser {
+    pub fn new(entities: &'argtype [EntitySpec]) -> Parser {
         Parser {
             specs: entities,
-            entities: HashMap::new(),
+            world: World::default(),
+            offset: 0,
         }
     }
 
-    fn parse_entity_property_packet<'a, 'b>(
-        &'b self,
+    /// Creates a parser which continues parsing a packet stream from the given offset, where
+    /// the world was in the given state.
+    pub(crate) fn resume(
+        specs: &'argtype [EntitySpec],
+        world: World<'argtype>,
+        offset: usize,
+    ) -> Parser<'argtype> {
+        Parser {
+            specs,
+            world,
+            offset,
+        }
+    }
+
+    /// The state of the game as of the last parsed packet
+    pub fn world(&self) -> &World<'argtype> {
+        &self.world
+    }
+
+    fn spec(&self, entity_type: u16) -> Result<&'argtype EntitySpec, nom::Err<Error>> {
+        let specs = self.specs;
+        (entity_type as usize)
+            .checked_sub(1)
+            .and_then(|idx| specs.get(idx))
+            .ok_or_else(|| failure_from_kind(ErrorKind::UnknownEntityType { entity_type }))
+    }
+
+    fn entity_type(&self, entity_id: u32) -> Result<u16, nom::Err<Error>> {
+        match self.world.entities.get(&entity_id) {
+            Some(entity) => Ok(entity.entity_type),
+            None => Err(failure_from_kind(ErrorKind::UnknownEntity { entity_id })),
+        }
+    }
+
+    fn parse_entity_property_packet<'a>(
+        &mut self,
         i: &'a [u8],
     ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         let (i, entity_id) = le_u32(i)?;
@@ -236,10 +684,13 @@ impl<'argtype> Parser<'argtype> {
         let (i, payload_length) = le_u32(i)?;
         let (i, payload) = take(payload_length)(i)?;
 
-        let entity_type = self.entities.get(&entity_id).unwrap().entity_type;
-        let spec = &self.specs[entity_type as usize - 1].properties[prop_id as usize];
+        let entity_spec = self.spec(self.entity_type(entity_id)?)?;
+        let spec = property_spec(entity_spec, prop_id as usize)?;
 
-        let (_, pval) = spec.prop_type.parse_value(payload).unwrap();
+        let (_, pval) = spec.prop_type.parse_value(payload)?;
+        if let Some(entity) = self.world.entities.get_mut(&entity_id) {
+            entity.properties.insert(prop_id as usize, pval.clone());
+        }
 
         Ok((
             i,
@@ -251,19 +702,41 @@ impl<'argtype> Parser<'argtype> {
         ))
     }
 
-    fn parse_entity_method_packet<'a, 'b>(
-        &'b self,
+    /// Parses a method call in the layout of an EntityMethod packet. Replays only contain
+    /// calls to the client as packets, but calls to the base or cell use the same layout
+    /// and are numbered by [EntitySpec::method].
+    pub fn parse_entity_method(
+        &self,
+        direction: MethodDirection,
+        i: &[u8],
+    ) -> Result<EntityMethodPacket<'argtype>, ErrorKind> {
+        let (_, packet) = self.parse_entity_method_packet(direction, i)?;
+        Ok(packet)
+    }
+
+    fn parse_entity_method_packet<'a>(
+        &self,
+        direction: MethodDirection,
         i: &'a [u8],
-    ) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    ) -> IResult<&'a [u8], EntityMethodPacket<'argtype>> {
         let (i, entity_id) = le_u32(i)?;
         let (i, method_id) = le_u32(i)?;
         let (i, payload_length) = le_u32(i)?;
         let (i, payload) = take(payload_length)(i)?;
-        assert!(i.len() == 0);
-
-        let entity_type = self.entities.get(&entity_id).unwrap().entity_type;
+        if !i.is_empty() {
+            return Err(failure_from_kind(ErrorKind::UnexpectedPacketLength {
+                expected: payload_length as usize + 12,
+                actual: payload_length as usize + 12 + i.len(),
+            }));
+        }
 
-        let spec = &self.specs[entity_type as usize - 1].client_methods[method_id as usize];
+        let entity_spec = self.spec(self.entity_type(entity_id)?)?;
+        let spec = entity_spec.method(direction, method_id).ok_or_else(|| {
+            failure_from_kind(ErrorKind::UnknownMethod {
+                entity_type: entity_spec.name.clone(),
+                index: method_id as usize,
+            })
+        })?;
 
         let mut i = payload;
         let mut args = vec![];
@@ -286,117 +759,148 @@ impl<'argtype> Parser<'argtype> {
 
         Ok((
             i,
-            PacketType::EntityMethod(EntityMethodPacket {
+            EntityMethodPacket {
                 entity_id,
                 method: &spec.name,
+                component: spec.component.as_deref(),
+                direction,
                 args,
-            }),
+            },
         ))
     }
 
-    fn parse_nested_property_update<'replay, 'b>(
-        &'b mut self,
+    fn parse_nested_property_update<'replay>(
+        &mut self,
         i: &'replay [u8],
     ) -> IResult<&'replay [u8], PacketType<'replay, 'argtype>> {
         let (i, entity_id) = le_u32(i)?;
         let (i, is_slice) = le_u8(i)?;
         let (i, payload_size) = le_u8(i)?;
         let (i, unknown) = take(3usize)(i)?;
-        assert!(unknown == [0, 0, 0]); // Note: This is almost certainly the upper 3 bytes of a u32
+        // Note: This is almost certainly the upper 3 bytes of a u32
+        if unknown != [0, 0, 0] {
+            return Err(failure_from_kind(ErrorKind::UnexpectedPacketValue {
+                field: "NestedProperty payload size high bytes",
+                value: u32::from_le_bytes([0, unknown[0], unknown[1], unknown[2]]) as u64,
+            }));
+        }
         let payload = i;
-        assert!(payload_size as usize == payload.len());
-
-        let entity = self.entities.get_mut(&entity_id).unwrap();
-        let entity_type = entity.entity_type;
-
-        let spec = &self.specs[entity_type as usize - 1];
+        if payload_size as usize != payload.len() {
+            return Err(failure_from_kind(ErrorKind::UnexpectedPacketLength {
+                expected: payload_size as usize,
+                actual: payload.len(),
+            }));
+        }
+        if is_slice & 0xFE != 0 {
+            return Err(failure_from_kind(ErrorKind::UnexpectedPacketValue {
+                field: "NestedProperty is_slice",
+                value: is_slice as u64,
+            }));
+        }
 
-        assert!(is_slice & 0xFE == 0);
+        let spec = self.spec(self.entity_type(entity_id)?)?;
+        let entity = self
+            .world
+            .entities
+            .get_mut(&entity_id)
+            .ok_or_else(|| failure_from_kind(ErrorKind::UnknownEntity { entity_id }))?;
 
         let mut reader = bitreader::BitReader::new(payload);
-        let cont = reader.read_u8(1).unwrap();
-        assert!(cont == 1);
+        let bad_update = |e: bitreader::BitReaderError| {
+            failure_from_kind(ErrorKind::BadNestedPropertyUpdate {
+                reason: format!("{:?}", e),
+            })
+        };
+        let cont = reader.read_u8(1).map_err(bad_update)?;
+        if cont != 1 {
+            return Err(failure_from_kind(ErrorKind::UnexpectedPacketValue {
+                field: "NestedProperty continuation bit",
+                value: cont as u64,
+            }));
+        }
         let prop_idx = reader
             .read_u8(spec.properties.len().next_power_of_two().trailing_zeros() as u8)
-            .unwrap();
-        if prop_idx as usize >= entity.properties.len() {
-            // This is almost certainly a nested property set on the player avatar.
-            // Currently, we assume that all properties are created when the entity is
-            // created. However, apparently the properties can go un-initialized at the
-            // beginning, and then later get created by a nested property update.
-            //
-            // We should do two things:
-            // - Store the entity's properties as a HashMap
-            // - Separate finding the path from updating the property value, and then here
-            //   we can create the entry if the property hasn't been created yet.
-            return Err(failure_from_kind(
-                crate::ErrorKind::UnsupportedInternalPropSet {
-                    entity_id,
-                    entity_type: spec.name.clone(),
-                    payload: payload.to_vec(),
-                },
-            ));
-        }
+            .map_err(bad_update)?;
+        let prop_spec = property_spec(spec, prop_idx as usize)?;
+        // Properties which the entity was created without (e.g. those on the player's avatar,
+        // which aren't all sent by the base) start out with their default value.
+        let property = entity
+            .properties
+            .entry(prop_idx as usize)
+            .or_insert_with(|| prop_spec.prop_type.default_value());
 
         let update_cmd = crate::nested_property_path::get_nested_prop_path_helper(
             is_slice & 0x1 == 1,
-            &spec.properties[prop_idx as usize].prop_type,
-            &mut entity.properties[prop_idx as usize],
+            &prop_spec.prop_type,
+            property,
             reader,
-        );
+        )
+        .map_err(failure_from_kind)?;
 
         Ok((
             i,
             PacketType::PropertyUpdate(PropertyUpdatePacket {
                 entity_id: entity_id as i32,
                 update_cmd,
-                property: &spec.properties[prop_idx as usize].name,
+                property: &prop_spec.name,
             }),
         ))
     }
 
-    fn parse_version_packet<'replay, 'b>(
-        &'b self,
+    fn parse_version_packet<'replay>(
+        &self,
         i: &'replay [u8],
     ) -> IResult<&'replay [u8], PacketType<'replay, 'argtype>> {
         let (i, len) = le_u32(i)?;
         let (i, data) = take(len)(i)?;
         Ok((
             i,
-            PacketType::Version(std::str::from_utf8(data).unwrap().to_string()),
+            PacketType::Version(
+                std::str::from_utf8(data)
+                    .map_err(|e| failure_from_kind(e.into()))?
+                    .to_string(),
+            ),
         ))
     }
 
-    fn parse_camera_mode_packet<'replay, 'b>(
-        &'b self,
+    fn parse_camera_mode_packet<'replay>(
+        &self,
         i: &'replay [u8],
     ) -> IResult<&'replay [u8], PacketType<'replay, 'argtype>> {
         let (i, mode) = le_u32(i)?;
         Ok((i, PacketType::CameraMode(mode)))
     }
 
-    fn parse_camera_freelook_packet<'replay, 'b>(
-        &'b self,
+    fn parse_camera_freelook_packet<'replay>(
+        &self,
         i: &'replay [u8],
     ) -> IResult<&'replay [u8], PacketType<'replay, 'argtype>> {
         let (i, freelook) = le_u8(i)?;
         Ok((i, PacketType::CameraFreeLook(freelook)))
     }
 
-    fn parse_position_packet<'a, 'b>(
-        &'b self,
+    fn parse_position_packet<'a>(
+        &mut self,
         i: &'a [u8],
-    ) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         let (i, pid) = le_u32(i)?;
         let (i, zero) = le_u32(i)?;
         if zero != 0 {
-            panic!("What does this field mean?");
+            // What does this field mean?
+            return Err(failure_from_kind(ErrorKind::UnexpectedPacketValue {
+                field: "Position unknown field",
+                value: zero as u64,
+            }));
         }
         let (i, position) = Vec3::parse(i)?;
         let (i, position_error) = Vec3::parse(i)?;
         let (i, rotation) = Rot3::parse(i)?;
         let (i, is_error_byte) = le_u8(i)?;
         let is_error = is_error_byte != 0;
+        if let Some(entity) = self.world.entities.get_mut(&pid) {
+            entity.position = position.clone();
+            entity.rotation = rotation.clone();
+        }
         Ok((
             i,
             PacketType::Position(PositionPacket {
@@ -409,15 +913,27 @@ impl<'argtype> Parser<'argtype> {
         ))
     }
 
-    fn parse_player_orientation_packet<'a, 'b>(
-        &'b self,
+    fn parse_player_orientation_packet<'a>(
+        &mut self,
         i: &'a [u8],
-    ) -> IResult<&'a [u8], PacketType<'a, 'b>> {
-        assert!(i.len() == 0x20);
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
+        if i.len() != 0x20 {
+            return Err(failure_from_kind(ErrorKind::UnexpectedPacketLength {
+                expected: 0x20,
+                actual: i.len(),
+            }));
+        }
         let (i, pid) = le_u32(i)?;
         let (i, parent_id) = le_u32(i)?;
         let (i, position) = Vec3::parse(i)?;
         let (i, rotation) = Rot3::parse(i)?;
+        // When attached to a parent, the position is relative to it
+        if parent_id == 0 {
+            if let Some(entity) = self.world.entities.get_mut(&pid) {
+                entity.position = position.clone();
+                entity.rotation = rotation.clone();
+            }
+        }
         Ok((
             i,
             PacketType::PlayerOrientation(PlayerOrientationPacket {
@@ -429,7 +945,7 @@ impl<'argtype> Parser<'argtype> {
         ))
     }
 
-    fn parse_camera_packet<'a, 'b>(&'b self, i: &'a [u8]) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    fn parse_camera_packet<'a>(&self, i: &'a [u8]) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         let (i, unknown) = Vec3::parse(i)?;
         let (i, unknown2) = le_u32(i)?;
         let (i, absolute_position) = Vec3::parse(i)?;
@@ -449,45 +965,180 @@ impl<'argtype> Parser<'argtype> {
         ))
     }
 
-    fn parse_unknown_packet<'a, 'b>(
-        &'b self,
+    fn parse_entity_control<'a>(&self, i: &'a [u8]) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
+        let (i, entity_id) = le_u32(i)?;
+        let (i, is_controlled) = le_u8(i)?;
+        Ok((
+            i,
+            PacketType::EntityControl(EntityControlPacket {
+                entity_id,
+                is_controlled: is_controlled != 0,
+            }),
+        ))
+    }
+
+    fn parse_own_ship<'a>(&self, i: &'a [u8]) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
+        let (i, entity_id) = le_u32(i)?;
+        Ok((i, PacketType::OwnShip(entity_id)))
+    }
+
+    fn parse_server_timestamp<'a>(
+        &self,
         i: &'a [u8],
-        payload_size: u32,
-    ) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
+        let (i, timestamp) = le_f64(i)?;
+        Ok((i, PacketType::ServerTimestamp(timestamp)))
+    }
+
+    fn parse_server_tick<'a>(&self, i: &'a [u8]) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
+        let (i, period) = le_f64(i)?;
+        Ok((i, PacketType::ServerTick(period)))
+    }
+
+    fn parse_init_flag<'a>(&self, i: &'a [u8]) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
+        let (i, flag) = le_u8(i)?;
+        Ok((i, PacketType::InitFlag(flag)))
+    }
+
+    fn parse_avatar_init<'a>(&self, i: &'a [u8]) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
+        let (i, entity_id) = le_u32(i)?;
+        let (i, unknown1) = le_u32(i)?;
+        let (i, unknown2) = le_u16(i)?;
+        Ok((
+            i,
+            PacketType::AvatarInit(AvatarInitPacket {
+                entity_id,
+                unknown1,
+                unknown2,
+            }),
+        ))
+    }
+
+    fn parse_camera18_packet<'a>(
+        &self,
+        i: &'a [u8],
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
+        let mut values = [0.0; 13];
+        let mut i = i;
+        for value in values.iter_mut() {
+            let (remaining, v) = le_f32(i)?;
+            *value = v;
+            i = remaining;
+        }
+        Ok((i, PacketType::Camera18(values)))
+    }
+
+    fn parse_entity_position<'a>(
+        &mut self,
+        i: &'a [u8],
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
+        let (i, entity_id) = le_u32(i)?;
+        let (i, vehicle_id) = le_u32(i)?;
+        let (i, position) = Vec3::parse(i)?;
+        let (i, rotation) = Rot3::parse(i)?;
+        if vehicle_id == 0 {
+            if let Some(entity) = self.world.entities.get_mut(&entity_id) {
+                entity.position = position.clone();
+                entity.rotation = rotation.clone();
+            }
+        }
+        Ok((
+            i,
+            PacketType::EntityPosition(EntityPositionPacket {
+                entity_id,
+                vehicle_id,
+                position,
+                rotation,
+            }),
+        ))
+    }
+
+    fn parse_target_lock<'a>(&self, i: &'a [u8]) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
+        let (i, unknown1) = le_u32(i)?;
+        let (i, unknown2) = le_u32(i)?;
+        let (i, target_id) = le_u32(i)?;
+        Ok((
+            i,
+            PacketType::TargetLock(TargetLockPacket {
+                unknown1,
+                unknown2,
+                target_id,
+            }),
+        ))
+    }
+
+    fn parse_replay_end<'a>(&self, i: &'a [u8]) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
+        let (i, data) = take(16usize)(i)?;
+        let mut contents = [0; 16];
+        contents.copy_from_slice(data);
+        Ok((i, PacketType::ReplayEnd(contents)))
+    }
+
+    fn parse_unknown_packet<'a>(
+        &self,
+        i: &'a [u8],
+        payload_size: usize,
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         let (i, contents) = take(payload_size)(i)?;
         Ok((i, PacketType::Unknown(contents)))
     }
 
-    fn parse_base_player_create<'a, 'b>(
-        &'b mut self,
+    fn parse_base_player_create<'a>(
+        &mut self,
         i: &'a [u8],
-    ) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         let (i, entity_id) = le_u32(i)?;
         let (i, entity_type) = le_u16(i)?;
         let (i, state) = take(i.len())(i)?;
-        let spec = &self.specs[entity_type as usize - 1];
-        self.entities.insert(
-            entity_id,
-            Entity {
-                entity_type,
-                // TODO: Parse the state
-                properties: vec![],
-            },
-        );
+        let spec = self.spec(entity_type)?;
+
+        // The state starts with the properties which the base sends to the client, in the same
+        // order as the client's properties. Anything after them is not understood.
+        // The entity is created even if the state can't be decoded, so that the packets
+        // which refer to it can still be parsed.
+        let mut entity = Entity::new(spec, entity_type);
+        let mut props = HashMap::new();
+        let mut error = None;
+        let mut remaining = state;
+        for (idx, property) in spec.properties.iter().enumerate() {
+            if property.flags != Flags::BaseAndClient {
+                continue;
+            }
+            let (new_remaining, value) = match property.prop_type.parse_value(remaining) {
+                Ok(x) => x,
+                Err(e) => {
+                    error = Some(Box::new(crate::ErrorKind::UnableToParseRpcValue {
+                        method: format!("BasePlayerCreate::{}", property.name),
+                        argnum: idx,
+                        argtype: format!("{:?}", property.prop_type),
+                        packet: state.to_vec(),
+                        error: format!("{:?}", e),
+                    }));
+                    break;
+                }
+            };
+            remaining = new_remaining;
+            entity.properties.insert(idx, value.clone());
+            props.insert(property.name.as_str(), value);
+        }
+        self.world.entities.insert(entity_id, entity);
+
         Ok((
             i,
             PacketType::BasePlayerCreate(BasePlayerCreatePacket {
                 entity_id,
                 entity_type: &spec.name,
                 state,
+                props,
+                error,
             }),
         ))
     }
 
-    fn parse_entity_create<'a, 'b>(
-        &'b mut self,
+    fn parse_entity_create<'a>(
+        &mut self,
         i: &'a [u8],
-    ) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         let (i, entity_id) = le_u32(i)?;
         let (i, entity_type) = le_u16(i)?;
         let (i, vehicle_id) = le_u32(i)?;
@@ -496,17 +1147,18 @@ impl<'argtype> Parser<'argtype> {
         let (i, rotation) = Rot3::parse(i)?;
         let (i, state_length) = le_u32(i)?;
         let (_, state) = take(i.len())(i)?;
-        if self.entities.contains_key(&entity_id) {
+        if self.world.entities.contains_key(&entity_id) {
             //println!("DBG: Entity {} got created twice!", entity_id);
         }
 
+        let entity_spec = self.spec(entity_type)?;
         let (i, num_props) = le_u8(state)?;
         let mut i = i;
         let mut props: HashMap<&str, _> = HashMap::new();
-        let mut stored_props: Vec<_> = vec![];
+        let mut stored_props = HashMap::new();
         for _ in 0..num_props {
             let (new_i, prop_id) = le_u8(i)?;
-            let spec = &self.specs[entity_type as usize - 1].properties[prop_id as usize];
+            let spec = property_spec(entity_spec, prop_id as usize)?;
             let (new_i, value) = match spec.prop_type.parse_value(new_i) {
                 Ok(x) => x,
                 Err(e) => {
@@ -520,15 +1172,19 @@ impl<'argtype> Parser<'argtype> {
                 }
             };
             i = new_i;
-            stored_props.push(value.clone());
+            stored_props.insert(prop_id as usize, value.clone());
             props.insert(&spec.name, value);
         }
 
-        self.entities.insert(
+        self.world.entities.insert(
             entity_id,
             Entity {
-                entity_type,
                 properties: stored_props,
+                space_id,
+                vehicle_id,
+                position: position.clone(),
+                rotation: rotation.clone(),
+                ..Entity::new(entity_spec, entity_type)
             },
         );
 
@@ -536,7 +1192,7 @@ impl<'argtype> Parser<'argtype> {
             i,
             PacketType::EntityCreate(EntityCreatePacket {
                 entity_id,
-                entity_type: &self.specs[entity_type as usize - 1].name,
+                entity_type: &entity_spec.name,
                 space_id,
                 vehicle_id,
                 position,
@@ -547,25 +1203,26 @@ impl<'argtype> Parser<'argtype> {
         ))
     }
 
-    fn parse_cell_player_create<'a, 'b>(
-        &'b mut self,
+    fn parse_cell_player_create<'a>(
+        &mut self,
         i: &'a [u8],
-    ) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         let (i, entity_id) = le_u32(i)?;
         let (i, space_id) = le_u32(i)?;
-        //let (i, unknown) = le_u16(i)?;
+        // The vehicle ID directly follows the space ID, there's no other field between them
         let (i, vehicle_id) = le_u32(i)?;
         let (i, position) = Vec3::parse(i)?;
         let (i, rotation) = Rot3::parse(i)?;
         let (i, vlen) = le_u32(i)?;
         let (i, value) = take(vlen)(i)?;
 
-        if !self.entities.contains_key(&entity_id) {
-            panic!(
-                "Cell player, entity id {}, was created before base player!",
-                entity_id
-            );
-        }
+        // The base player must have been created before the cell player
+        let spec = self.spec(self.entity_type(entity_id)?)?;
+        let entity = self
+            .world
+            .entities
+            .get_mut(&entity_id)
+            .ok_or_else(|| failure_from_kind(ErrorKind::UnknownEntity { entity_id }))?;
 
         // The value can be parsed into all internal properties
         /*println!(
@@ -582,20 +1239,33 @@ impl<'argtype> Parser<'argtype> {
             dirz,
             value.len()
         );*/
-        let entity_type = self.entities.get(&entity_id).unwrap().entity_type;
-        let spec = &self.specs[entity_type as usize - 1];
+        entity.space_id = space_id;
+        entity.vehicle_id = vehicle_id;
+        entity.position = position.clone();
+        entity.rotation = rotation.clone();
         let mut value = value;
-        let mut prop_values = vec![];
-        for property in spec.internal_properties.iter() {
-            //println!("{}: {}", idx, property.name);
-            //println!("{:#?}", property.prop_type);
-            //println!("{:?}", value);
-            let (new_value, prop_value) = property.prop_type.parse_value(value).unwrap();
-            //println!("{:?}", prop_value);
+        let mut props = HashMap::new();
+        for (idx, property) in spec.internal_properties.iter().enumerate() {
+            let (new_value, prop_value) = match property.prop_type.parse_value(value) {
+                Ok(x) => x,
+                Err(e) => {
+                    return Err(failure_from_kind(crate::ErrorKind::UnableToParseRpcValue {
+                        method: format!("CellPlayerCreate::{}", property.name),
+                        argnum: idx,
+                        argtype: format!("{:?}", property.prop_type),
+                        packet: value.to_vec(),
+                        error: format!("{:?}", e),
+                    }));
+                }
+            };
             value = new_value;
-            prop_values.push(prop_value);
+            // The internal properties are also in the entity's properties, which is how
+            // later updates refer to them
+            if let Some(prop_idx) = spec.properties.iter().position(|p| p.name == property.name) {
+                entity.properties.insert(prop_idx, prop_value.clone());
+            }
+            props.insert(property.name.as_str(), prop_value);
         }
-        //println!("CellPlayerCreate properties: {:?}", prop_values);
 
         Ok((
             i,
@@ -605,24 +1275,35 @@ impl<'argtype> Parser<'argtype> {
                 space_id,
                 position,
                 rotation,
-                unknown: 5,
+                props,
                 value,
             }),
         ))
     }
 
-    fn parse_entity_leave<'a, 'b>(&'b self, i: &'a [u8]) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    fn parse_entity_leave<'a>(
+        &mut self,
+        i: &'a [u8],
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         let (i, entity_id) = le_u32(i)?;
+        if let Some(entity) = self.world.entities.get_mut(&entity_id) {
+            entity.present = false;
+        }
         Ok((i, PacketType::EntityLeave(EntityLeavePacket { entity_id })))
     }
 
-    fn parse_entity_enter<'a, 'b>(
-        &'b mut self,
+    fn parse_entity_enter<'a>(
+        &mut self,
         i: &'a [u8],
-    ) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         let (i, entity_id) = le_u32(i)?;
         let (i, space_id) = le_u32(i)?;
         let (i, vehicle_id) = le_u32(i)?;
+        if let Some(entity) = self.world.entities.get_mut(&entity_id) {
+            entity.present = true;
+            entity.space_id = space_id;
+            entity.vehicle_id = vehicle_id;
+        }
         Ok((
             i,
             PacketType::EntityEnter(EntityEnterPacket {
@@ -633,19 +1314,16 @@ impl<'argtype> Parser<'argtype> {
         ))
     }
 
-    fn parse_cruise_state<'a, 'b>(
-        &'b mut self,
+    fn parse_cruise_state<'a>(
+        &mut self,
         i: &'a [u8],
-    ) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         let (i, key) = le_u32(i)?;
         let (i, value) = le_i32(i)?;
         Ok((i, PacketType::CruiseState(CruiseState { key, value })))
     }
 
-    fn parse_map_packet<'a, 'b>(
-        &'b mut self,
-        i: &'a [u8],
-    ) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    fn parse_map_packet<'a>(&mut self, i: &'a [u8]) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         let (i, space_id) = le_u32(i)?;
         let (i, arena_id) = le_i64(i)?;
         let (i, unknown1) = le_u32(i)?;
@@ -661,19 +1339,18 @@ impl<'argtype> Parser<'argtype> {
             unknown1,
             unknown2,
             blob,
-            // TODO: Use a nom combinator for this (for error handling)
-            map_name: std::str::from_utf8(map_name).unwrap(),
+            map_name: std::str::from_utf8(map_name).map_err(|e| failure_from_kind(e.into()))?,
             matrix,
             unknown,
         };
         Ok((i, PacketType::Map(packet)))
     }
 
-    fn parse_naked_packet<'a, 'b>(
-        &'b mut self,
+    fn parse_naked_packet<'a>(
+        &mut self,
         packet_type: u32,
         i: &'a [u8],
-    ) -> IResult<&'a [u8], PacketType<'a, 'b>> {
+    ) -> IResult<&'a [u8], PacketType<'a, 'argtype>> {
         /*
         PACKETS_MAPPING = {
             0x0: BasePlayerCreate,
@@ -694,44 +1371,68 @@ impl<'argtype> Parser<'argtype> {
             //0x7 | 0x8 => self.parse_entity_packet(version, packet_type, i)?,
             0x0 => self.parse_base_player_create(i)?,
             0x1 => self.parse_cell_player_create(i)?,
+            0x2 => self.parse_entity_control(i)?,
             0x3 => self.parse_entity_enter(i)?,
             0x4 => self.parse_entity_leave(i)?,
             0x5 => self.parse_entity_create(i)?,
             0x7 => self.parse_entity_property_packet(i)?,
-            0x8 => self.parse_entity_method_packet(i)?,
+            0x8 => {
+                let (i, packet) = self.parse_entity_method_packet(MethodDirection::ToClient, i)?;
+                (i, PacketType::EntityMethod(packet))
+            }
             0xA => self.parse_position_packet(i)?,
+            0xE => self.parse_server_tick(i)?,
+            0xF => self.parse_server_timestamp(i)?,
+            0x10 => self.parse_init_flag(i)?,
+            0x13 => (i, PacketType::InitComplete),
             0x16 => self.parse_version_packet(i)?,
+            0x18 => self.parse_camera18_packet(i)?,
+            0x20 => self.parse_own_ship(i)?,
             0x22 => self.parse_nested_property_update(i)?,
-            0x24 => self.parse_camera_packet(i)?, // Note: We suspect that 0x18 is this also
+            0x24 => self.parse_camera_packet(i)?,
+            0x25 => self.parse_avatar_init(i)?,
             0x26 => self.parse_camera_mode_packet(i)?,
             0x27 => self.parse_map_packet(i)?,
+            0x29 => self.parse_entity_position(i)?,
             0x2b => self.parse_player_orientation_packet(i)?,
             0x2e => self.parse_camera_freelook_packet(i)?,
+            0x2f => self.parse_target_lock(i)?,
             0x31 => self.parse_cruise_state(i)?,
-            _ => self.parse_unknown_packet(i, i.len().try_into().unwrap())?,
+            0xffffffff => self.parse_replay_end(i)?,
+            _ => self.parse_unknown_packet(i, i.len())?,
         };
         Ok((i, payload))
     }
 
-    fn parse_packet<'a, 'b>(&'b mut self, i: &'a [u8]) -> IResult<&'a [u8], Packet<'a, 'b>> {
-        let (i, packet_size) = le_u32(i)?;
-        let (i, packet_type) = le_u32(i)?;
-        let (i, clock) = le_f32(i)?;
-        let (remaining, i) = take(packet_size)(i)?;
+    pub(crate) fn parse_packet<'a>(
+        &mut self,
+        i: &'a [u8],
+    ) -> IResult<&'a [u8], Packet<'a, 'argtype>> {
+        let offset = self.offset;
+        let (i, packet_size) = le_u32(i).map_err(|e| bad_packet(offset, None, e))?;
+        let (i, packet_type) = le_u32(i).map_err(|e| bad_packet(offset, None, e))?;
+        let (i, clock) = le_f32(i).map_err(|e| bad_packet(offset, None, e))?;
+        let (remaining, i) =
+            take(packet_size)(i).map_err(|e| bad_packet(offset, Some(clock), e))?;
+        self.offset += 12 + packet_size as usize;
+        self.world.clock = clock;
         let raw = i;
         let (_i, payload) = match self.parse_naked_packet(packet_type, i) {
             Ok(x) => x,
-            Err(nom::Err::Failure(Error {
-                kind: ErrorKind::UnsupportedReplayVersion(n),
-                ..
-            })) => {
-                return Err(failure_from_kind(ErrorKind::UnsupportedReplayVersion(n)));
+            Err(
+                e @ nom::Err::Failure(Error {
+                    kind: ErrorKind::UnsupportedReplayVersion(_),
+                    ..
+                }),
+            ) => {
+                return Err(bad_packet(offset, Some(clock), e));
             }
             Err(e) => {
                 (
                     &i[0..0], // Empty reference
                     PacketType::Invalid(InvalidPacket {
-                        message: format!("{:?}", e),
+                        offset,
+                        error: Box::new(e.into()),
                         raw: i,
                     }),
                 )
@@ -751,21 +1452,886 @@ impl<'argtype> Parser<'argtype> {
         ))
     }
 
-    pub fn parse_packets<'a, 'b, P: PacketProcessor>(
-        &'b mut self,
-        i: &'a [u8],
+    /// Returns the entity type of the named spec, which is its index plus one
+    fn entity_type_of(&self, name: &str) -> Result<u16, ErrorKind> {
+        self.specs
+            .iter()
+            .position(|spec| spec.name == name)
+            .map(|idx| idx as u16 + 1)
+            .ok_or_else(|| unable_to_encode(format!("Unknown entity type {}", name)))
+    }
+
+    /// Encodes a packet into the wire format, which is the inverse of parsing it. Since packets
+    /// refer to entities by their type's property and method names, and nested property updates
+    /// depend on the current value of the property, the world must be in the state it was in
+    /// before the packet was parsed.
+    pub fn encode_packet(&self, packet: &Packet) -> Result<Vec<u8>, ErrorKind> {
+        let payload = self.encode_payload(&packet.payload)?;
+        let mut out = Vec::with_capacity(12 + payload.len());
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&packet.packet_type.to_le_bytes());
+        out.extend_from_slice(&packet.clock.to_le_bytes());
+        out.extend(payload);
+        Ok(out)
+    }
+
+    /// Encodes the payload of a packet, without the packet header. See [Parser::encode_packet].
+    pub fn encode_payload(&self, payload: &PacketType) -> Result<Vec<u8>, ErrorKind> {
+        let mut out = vec![];
+        match payload {
+            PacketType::Position(p) => {
+                out.extend_from_slice(&p.pid.to_le_bytes());
+                out.extend_from_slice(&0u32.to_le_bytes());
+                p.position.encode(&mut out);
+                p.position_error.encode(&mut out);
+                p.rotation.encode(&mut out);
+                out.push(p.is_error as u8);
+            }
+            PacketType::BasePlayerCreate(p) => {
+                out.extend_from_slice(&p.entity_id.to_le_bytes());
+                out.extend_from_slice(&self.entity_type_of(p.entity_type)?.to_le_bytes());
+                out.extend_from_slice(p.state);
+            }
+            PacketType::CellPlayerCreate(p) => {
+                let spec = self.spec(self.entity_type(p.entity_id)?)?;
+                let mut value = vec![];
+                for property in spec.internal_properties.iter() {
+                    let prop_value = p.props.get(property.name.as_str()).ok_or_else(|| {
+                        unable_to_encode(format!("Missing internal property {}", property.name))
+                    })?;
+                    value.extend(property.prop_type.encode_value(prop_value)?);
+                }
+                value.extend_from_slice(p.value);
+
+                out.extend_from_slice(&p.entity_id.to_le_bytes());
+                out.extend_from_slice(&p.space_id.to_le_bytes());
+                out.extend_from_slice(&p.vehicle_id.to_le_bytes());
+                p.position.encode(&mut out);
+                p.rotation.encode(&mut out);
+                out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                out.extend_from_slice(&value);
+            }
+            PacketType::EntityEnter(p) => {
+                out.extend_from_slice(&p.entity_id.to_le_bytes());
+                out.extend_from_slice(&p.space_id.to_le_bytes());
+                out.extend_from_slice(&p.vehicle_id.to_le_bytes());
+            }
+            PacketType::EntityLeave(p) => {
+                out.extend_from_slice(&p.entity_id.to_le_bytes());
+            }
+            PacketType::EntityCreate(p) => {
+                let entity_type = self.entity_type_of(p.entity_type)?;
+                let spec = self.spec(entity_type)?;
+                // Properties are sent in the order of the spec
+                let mut props = vec![];
+                for (name, value) in p.props.iter() {
+                    let idx = spec
+                        .properties
+                        .iter()
+                        .position(|property| property.name == *name)
+                        .ok_or_else(|| unable_to_encode(format!("Unknown property {}", name)))?;
+                    props.push((idx, value));
+                }
+                props.sort_unstable_by_key(|(idx, _)| *idx);
+
+                let mut state = vec![props.len() as u8];
+                for (idx, value) in props.into_iter() {
+                    state.push(idx as u8);
+                    state.extend(spec.properties[idx].prop_type.encode_value(value)?);
+                }
+
+                out.extend_from_slice(&p.entity_id.to_le_bytes());
+                out.extend_from_slice(&entity_type.to_le_bytes());
+                out.extend_from_slice(&p.vehicle_id.to_le_bytes());
+                out.extend_from_slice(&p.space_id.to_le_bytes());
+                p.position.encode(&mut out);
+                p.rotation.encode(&mut out);
+                out.extend_from_slice(&(state.len() as u32).to_le_bytes());
+                out.extend_from_slice(&state);
+            }
+            PacketType::EntityProperty(p) => {
+                let spec = self.spec(self.entity_type(p.entity_id)?)?;
+                let idx = spec
+                    .properties
+                    .iter()
+                    .position(|property| property.name == p.property)
+                    .ok_or_else(|| unable_to_encode(format!("Unknown property {}", p.property)))?;
+                let value = spec.properties[idx].prop_type.encode_value(&p.value)?;
+                out.extend_from_slice(&p.entity_id.to_le_bytes());
+                out.extend_from_slice(&(idx as u32).to_le_bytes());
+                out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                out.extend_from_slice(&value);
+            }
+            PacketType::EntityMethod(p) => {
+                let spec = self.spec(self.entity_type(p.entity_id)?)?;
+                let idx = spec
+                    .method_id(p.direction, p.method, p.component)
+                    .ok_or_else(|| unable_to_encode(format!("Unknown method {}", p.method)))?;
+                let method = spec.method(p.direction, idx).unwrap();
+                if method.args.len() != p.args.len() {
+                    return Err(unable_to_encode(format!(
+                        "{} takes {} arguments, got {}",
+                        p.method,
+                        method.args.len(),
+                        p.args.len()
+                    )));
+                }
+                let mut args = vec![];
+                for (argtype, arg) in method.args.iter().zip(p.args.iter()) {
+                    args.extend(argtype.encode_value(arg)?);
+                }
+                out.extend_from_slice(&p.entity_id.to_le_bytes());
+                out.extend_from_slice(&idx.to_le_bytes());
+                out.extend_from_slice(&(args.len() as u32).to_le_bytes());
+                out.extend_from_slice(&args);
+            }
+            PacketType::PropertyUpdate(p) => {
+                let entity_id = p.entity_id as u32;
+                let spec = self.spec(self.entity_type(entity_id)?)?;
+                let idx = spec
+                    .properties
+                    .iter()
+                    .position(|property| property.name == p.property)
+                    .ok_or_else(|| unable_to_encode(format!("Unknown property {}", p.property)))?;
+                let prop_type = &spec.properties[idx].prop_type;
+                let default;
+                let entity = self
+                    .world
+                    .entities
+                    .get(&entity_id)
+                    .ok_or(ErrorKind::UnknownEntity { entity_id })?;
+                let value = match entity.properties.get(&idx) {
+                    Some(value) => value,
+                    None => {
+                        default = prop_type.default_value();
+                        &default
+                    }
+                };
+
+                let mut writer = crate::nested_property_path::BitWriter::new();
+                writer.write(1, 1);
+                writer.write(
+                    idx,
+                    crate::nested_property_path::index_bits(spec.properties.len()),
+                );
+                let payload = crate::nested_property_path::encode_nested_prop_path(
+                    prop_type,
+                    value,
+                    &p.update_cmd,
+                    writer,
+                )?;
+                if payload.len() > 0xff {
+                    return Err(unable_to_encode(format!(
+                        "Nested property update of {} bytes is too long",
+                        payload.len()
+                    )));
+                }
+                out.extend_from_slice(&entity_id.to_le_bytes());
+                out.push(p.update_cmd.action.is_slice() as u8);
+                out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+                out.extend_from_slice(&payload);
+            }
+            PacketType::PlayerOrientation(p) => {
+                out.extend_from_slice(&p.pid.to_le_bytes());
+                out.extend_from_slice(&p.parent_id.to_le_bytes());
+                p.position.encode(&mut out);
+                p.rotation.encode(&mut out);
+            }
+            PacketType::CruiseState(p) => {
+                out.extend_from_slice(&p.key.to_le_bytes());
+                out.extend_from_slice(&p.value.to_le_bytes());
+            }
+            PacketType::Version(version) => {
+                out.extend_from_slice(&(version.len() as u32).to_le_bytes());
+                out.extend_from_slice(version.as_bytes());
+            }
+            PacketType::Camera(p) => {
+                p.unknown.encode(&mut out);
+                out.extend_from_slice(&p.unknown2.to_le_bytes());
+                p.absolute_position.encode(&mut out);
+                out.extend_from_slice(&p.fov.to_le_bytes());
+                p.position.encode(&mut out);
+                p.rotation.encode(&mut out);
+            }
+            PacketType::CameraMode(mode) => out.extend_from_slice(&mode.to_le_bytes()),
+            PacketType::CameraFreeLook(freelook) => out.push(*freelook),
+            PacketType::Map(p) => {
+                if p.blob.len() != 128 || p.matrix.len() != 4 * 4 * 4 {
+                    return Err(unable_to_encode(format!(
+                        "Map blob and matrix must be 128 and 64 bytes, not {} and {}",
+                        p.blob.len(),
+                        p.matrix.len()
+                    )));
+                }
+                out.extend_from_slice(&p.space_id.to_le_bytes());
+                out.extend_from_slice(&p.arena_id.to_le_bytes());
+                out.extend_from_slice(&p.unknown1.to_le_bytes());
+                out.extend_from_slice(&p.unknown2.to_le_bytes());
+                out.extend_from_slice(p.blob);
+                out.extend_from_slice(&(p.map_name.len() as u32).to_le_bytes());
+                out.extend_from_slice(p.map_name.as_bytes());
+                out.extend_from_slice(p.matrix);
+                out.push(p.unknown);
+            }
+            PacketType::EntityControl(p) => {
+                out.extend_from_slice(&p.entity_id.to_le_bytes());
+                out.push(p.is_controlled as u8);
+            }
+            PacketType::OwnShip(entity_id) => out.extend_from_slice(&entity_id.to_le_bytes()),
+            PacketType::ServerTimestamp(t) => out.extend_from_slice(&t.to_le_bytes()),
+            PacketType::ServerTick(period) => out.extend_from_slice(&period.to_le_bytes()),
+            PacketType::InitFlag(flag) => out.push(*flag),
+            PacketType::InitComplete => {}
+            PacketType::AvatarInit(p) => {
+                out.extend_from_slice(&p.entity_id.to_le_bytes());
+                out.extend_from_slice(&p.unknown1.to_le_bytes());
+                out.extend_from_slice(&p.unknown2.to_le_bytes());
+            }
+            PacketType::Camera18(values) => {
+                for value in values.iter() {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            PacketType::EntityPosition(p) => {
+                out.extend_from_slice(&p.entity_id.to_le_bytes());
+                out.extend_from_slice(&p.vehicle_id.to_le_bytes());
+                p.position.encode(&mut out);
+                p.rotation.encode(&mut out);
+            }
+            PacketType::TargetLock(p) => {
+                out.extend_from_slice(&p.unknown1.to_le_bytes());
+                out.extend_from_slice(&p.unknown2.to_le_bytes());
+                out.extend_from_slice(&p.target_id.to_le_bytes());
+            }
+            PacketType::ReplayEnd(contents) => out.extend_from_slice(contents),
+            PacketType::Unknown(raw) => out.extend_from_slice(raw),
+            PacketType::Invalid(p) => out.extend_from_slice(p.raw),
+        }
+        Ok(out)
+    }
+
+    /// Returns an iterator over the packets in the given packet stream. The entity table is
+    /// updated as each packet is pulled from the iterator. Iteration stops after the first
+    /// error.
+    pub fn packets<'parser, 'replay>(
+        &'parser mut self,
+        data: &'replay [u8],
+    ) -> PacketIter<'parser, 'replay, 'argtype> {
+        PacketIter {
+            parser: self,
+            remaining: data,
+        }
+    }
+
+    pub fn parse_packets<P: PacketProcessor>(
+        &mut self,
+        i: &[u8],
         p: &mut P,
     ) -> Result<(), ErrorKind> {
-        let mut i = i;
-        while i.len() > 0 {
-            let (remaining, packet) = self.parse_packet(i)?;
-            i = remaining;
-            p.process(packet);
+        for packet in self.packets(i) {
+            p.process(packet?);
         }
         Ok(())
     }
 }
 
+/// Iterator over the packets in a packet stream, see [Parser::packets]
+pub struct PacketIter<'parser, 'replay, 'argtype> {
+    parser: &'parser mut Parser<'argtype>,
+    remaining: &'replay [u8],
+}
+
+impl<'parser, 'replay, 'argtype> Iterator for PacketIter<'parser, 'replay, 'argtype> {
+    type Item = Result<Packet<'replay, 'argtype>, ErrorKind>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining.is_empty() {
+            return None;
+        }
+        match self.parser.parse_packet(self.remaining) {
+            Ok((remaining, packet)) => {
+                self.remaining = remaining;
+                Some(Ok(packet))
+            }
+            Err(e) => {
+                self.remaining = &[];
+                Some(Err(e.into()))
+            }
+        }
+    }
+}
+
 pub trait PacketProcessor {
     fn process(&mut self, packet: Packet<'_, '_>);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::analyzer::decoder::{DecodedPacket, DecodedPacketPayload};
+    use crate::version::{Datafiles, Version};
+
+    struct Counter(usize);
+
+    impl PacketProcessor for Counter {
+        fn process(&mut self, _packet: Packet<'_, '_>) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn packet_iterator() {
+        let replay = crate::ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let version = replay.meta.clientVersionFromExe.parse::<Version>().unwrap();
+        let datafiles = Datafiles::new(std::path::PathBuf::from("../versions"), version).unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+
+        let mut parser = Parser::new(&specs);
+        let first: Vec<_> = parser
+            .packets(&replay.packet_data)
+            .take(3)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(first.len(), 3);
+        assert!(matches!(first[0].payload, PacketType::Version(_)));
+
+        let mut counter = Counter(0);
+        Parser::new(&specs)
+            .parse_packets(&replay.packet_data, &mut counter)
+            .unwrap();
+        let mut parser = Parser::new(&specs);
+        assert_eq!(parser.packets(&replay.packet_data).count(), counter.0);
+
+        let mut parser = Parser::new(&specs);
+        let before_end = parser
+            .packets(&replay.packet_data)
+            .map(|packet| packet.unwrap())
+            .take_while(|packet| {
+                !matches!(
+                    DecodedPacket::from(&version, false, packet).payload,
+                    DecodedPacketPayload::BattleEnd { .. }
+                )
+            })
+            .count();
+        assert!(before_end > 0 && before_end < counter.0);
+    }
+
+    #[test]
+    fn owned_packets() {
+        let replay = crate::ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let version = replay.meta.clientVersionFromExe.parse::<Version>().unwrap();
+        let datafiles = Datafiles::new(std::path::PathBuf::from("../versions"), version).unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+
+        let mut parser = Parser::new(&specs);
+        let mut packets = vec![];
+        let mut decoded = vec![];
+        for packet in parser.packets(&replay.packet_data) {
+            let packet = packet.unwrap();
+            decoded.push(DecodedPacket::from(&version, false, &packet).into_owned());
+            packets.push(packet.into_owned());
+        }
+        drop(specs);
+
+        // The owned packets outlive the specs, and can be moved to another thread
+        let (packets, decoded) = std::thread::spawn(move || (packets, decoded))
+            .join()
+            .unwrap();
+        assert!(decoded.iter().any(|packet| matches!(
+            packet.payload,
+            crate::analyzer::decoder::OwnedDecodedPacketPayload::BattleEnd { .. }
+        )));
+
+        // Compare as JSON values, since the order of the maps isn't stable
+        let json = serde_json::to_value(&packets).unwrap();
+        let deserialized: Vec<OwnedPacket> = serde_json::from_value(json.clone()).unwrap();
+        assert!(serde_json::to_value(&deserialized).unwrap() == json);
+        let json = serde_json::to_value(&decoded).unwrap();
+        let deserialized: Vec<crate::analyzer::decoder::OwnedDecodedPacket> =
+            serde_json::from_value(json.clone()).unwrap();
+        assert!(serde_json::to_value(&deserialized).unwrap() == json);
+    }
+
+    #[test]
+    fn base_player_state() {
+        let replay = crate::ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let version = replay.meta.clientVersionFromExe.parse::<Version>().unwrap();
+        let datafiles = Datafiles::new(std::path::PathBuf::from("../versions"), version).unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+
+        let mut parser = Parser::new(&specs);
+        let mut i = &replay.packet_data[..];
+        let (entity_id, attrs) = loop {
+            let (remaining, packet) = parser.parse_packet(i).unwrap();
+            i = remaining;
+            if let PacketType::BasePlayerCreate(p) = packet.payload {
+                assert_eq!(p.entity_type, "Avatar");
+                break (p.entity_id, p.props["attrs"].clone());
+            }
+        };
+        let avatar = parser.world().entity(entity_id).unwrap();
+        assert_eq!(avatar.property("attrs"), Some(&attrs));
+        assert!(avatar.property("playerModeState").is_none());
+
+        // A nested update to a property which the base didn't send creates it
+        let spec = &specs[avatar.entity_type() as usize - 1];
+        let prop_idx = spec
+            .properties
+            .iter()
+            .position(|p| p.name == "playerModeState")
+            .unwrap();
+        let prop_bits = spec.properties.len().next_power_of_two().trailing_zeros();
+        // Continue, the property index, stop, then set the second key of the dict
+        let bits = (1 << (prop_bits + 2)) | (prop_idx << 2) | 1;
+        let padding = (8 - (prop_bits + 3) % 8) % 8;
+        let bits = bits << padding;
+        let nbytes = (prop_bits + 3 + padding) as usize / 8;
+        let mut payload: Vec<u8> = (0..nbytes)
+            .rev()
+            .map(|byte| (bits >> (byte * 8)) as u8)
+            .collect();
+        payload.push(3);
+
+        let mut data = vec![];
+        data.extend_from_slice(&(payload.len() as u32 + 9).to_le_bytes());
+        data.extend_from_slice(&0x22u32.to_le_bytes());
+        data.extend_from_slice(&0.0f32.to_le_bytes());
+        data.extend_from_slice(&entity_id.to_le_bytes());
+        data.extend_from_slice(&[0, payload.len() as u8, 0, 0, 0]);
+        data.extend_from_slice(&payload);
+        let (_, packet) = parser.parse_packet(&data).unwrap();
+        assert!(
+            matches!(packet.payload, PacketType::PropertyUpdate(_)),
+            "{:?}",
+            packet.payload
+        );
+        let mut expected = HashMap::new();
+        expected.insert("playerModeType", ArgValue::Uint8(0));
+        expected.insert("observedTeamId", ArgValue::Int8(3));
+        assert_eq!(
+            parser
+                .world()
+                .entity(entity_id)
+                .unwrap()
+                .property("playerModeState"),
+            Some(&ArgValue::FixedDict(expected))
+        );
+    }
+
+    #[test]
+    fn bad_base_player_state() {
+        let replay = crate::ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let version = replay.meta.clientVersionFromExe.parse::<Version>().unwrap();
+        let datafiles = Datafiles::new(std::path::PathBuf::from("../versions"), version).unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+
+        // Replace the BasePlayerCreate packet with one whose state is cut short
+        let mut data = vec![];
+        let mut parser = Parser::new(&specs);
+        let mut i = &replay.packet_data[..];
+        let entity_id = loop {
+            let (remaining, packet) = parser.parse_packet(i).unwrap();
+            let raw = &i[..i.len() - remaining.len()];
+            i = remaining;
+            if let PacketType::BasePlayerCreate(p) = packet.payload {
+                let payload = &raw[12..12 + 6 + 3];
+                data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+                data.extend_from_slice(&raw[4..12]);
+                data.extend_from_slice(payload);
+                break p.entity_id;
+            }
+            data.extend_from_slice(raw);
+        };
+        data.extend_from_slice(i);
+
+        let mut parser = Parser::new(&specs);
+        let mut i = &data[..];
+        let mut methods = 0;
+        while !i.is_empty() {
+            let (remaining, packet) = parser.parse_packet(i).unwrap();
+            i = remaining;
+            match packet.payload {
+                PacketType::BasePlayerCreate(p) => {
+                    assert!(matches!(
+                        p.error.as_deref(),
+                        Some(ErrorKind::UnableToParseRpcValue { .. })
+                    ));
+                    assert!(parser.world().entity(entity_id).is_some());
+                }
+                PacketType::EntityMethod(p) if p.entity_id == entity_id => methods += 1,
+                PacketType::Invalid(p) => panic!("{:?}", p.error),
+                _ => {}
+            }
+        }
+        // The avatar's methods are still parsed
+        assert!(methods > 0);
+    }
+
+    #[test]
+    fn cell_player_internal_properties() {
+        let replay = crate::ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let version = replay.meta.clientVersionFromExe.parse::<Version>().unwrap();
+        let datafiles = Datafiles::new(std::path::PathBuf::from("../versions"), version).unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+
+        let mut parser = Parser::new(&specs);
+        let mut i = &replay.packet_data[..];
+        let (entity_id, props) = loop {
+            let (remaining, packet) = parser.parse_packet(i).unwrap();
+            i = remaining;
+            if let PacketType::CellPlayerCreate(p) = packet.payload {
+                break (p.entity_id, p.props);
+            }
+        };
+        let spec = &specs[parser.world().entity(entity_id).unwrap().entity_type() as usize - 1];
+        assert_eq!(props.len(), spec.internal_properties.len());
+        assert!(props.contains_key("playerModeState"));
+
+        let avatar = parser.world().entity(entity_id).unwrap();
+        for (name, value) in props.iter() {
+            assert_eq!(avatar.property(name), Some(value), "{}", name);
+        }
+    }
+
+    fn parse_test_replay<F: FnMut(&Parser, Packet)>(mut f: F) {
+        let replay = crate::ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let version = replay.meta.clientVersionFromExe.parse::<Version>().unwrap();
+        let datafiles = Datafiles::new(std::path::PathBuf::from("../versions"), version).unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+
+        let mut parser = Parser::new(&specs);
+        let mut i = &replay.packet_data[..];
+        while !i.is_empty() {
+            let (remaining, packet) = parser.parse_packet(i).unwrap();
+            i = remaining;
+            match &packet.payload {
+                PacketType::Unknown(_) | PacketType::Invalid(_) => {
+                    panic!("{:x}: {:?}", packet.packet_type, packet.payload)
+                }
+                _ => {}
+            }
+            f(&parser, packet);
+        }
+    }
+
+    #[test]
+    fn setup_packets() {
+        let mut avatar_id = None;
+        let mut own_ship = None;
+        let mut control = None;
+        let mut avatar_init = None;
+        let mut init_flags = 0;
+        let mut init_complete = 0;
+        let mut timestamp = None;
+        let mut replay_end = None;
+        parse_test_replay(|parser, packet| {
+            match packet.payload {
+                PacketType::BasePlayerCreate(p) => avatar_id = Some(p.entity_id),
+                PacketType::OwnShip(entity_id) => {
+                    let avatar = parser.world().entity(avatar_id.unwrap()).unwrap();
+                    assert_eq!(
+                        avatar.property("ownShipId"),
+                        Some(&ArgValue::Int32(entity_id as i32))
+                    );
+                    own_ship = Some(entity_id);
+                }
+                PacketType::EntityControl(p) => control = Some(p),
+                PacketType::AvatarInit(p) => avatar_init = Some(p),
+                PacketType::InitFlag(flag) => {
+                    assert_eq!(flag, 0);
+                    init_flags += 1;
+                }
+                PacketType::InitComplete => {
+                    assert!(own_ship.is_some());
+                    init_complete += 1;
+                }
+                PacketType::ServerTimestamp(t) => timestamp = Some(t),
+                PacketType::ReplayEnd(contents) => replay_end = Some(contents),
+                _ => {
+                    // The end of the replay must be the last packet
+                    assert!(replay_end.is_none());
+                }
+            }
+        });
+
+        let avatar_id = avatar_id.unwrap();
+        assert_eq!(own_ship, Some(576298));
+        let control = control.unwrap();
+        assert_eq!(control.entity_id, avatar_id);
+        assert!(!control.is_controlled);
+        assert_eq!(avatar_init.unwrap().entity_id, avatar_id);
+        assert_eq!(init_flags, 2);
+        assert_eq!(init_complete, 1);
+        assert!(timestamp.unwrap() > 0.0);
+        assert!(replay_end.is_some());
+    }
+
+    #[test]
+    fn game_packets() {
+        let mut ticks = 0;
+        let mut camera18 = 0;
+        let mut positions = 0;
+        let mut target_locks = 0;
+        parse_test_replay(|parser, packet| match packet.payload {
+            PacketType::ServerTick(period) => {
+                assert!((period - 1.0 / 7.0).abs() < 1e-6);
+                ticks += 1;
+            }
+            PacketType::Camera18(_) => {
+                let version = "0,10,9,0".parse::<Version>().unwrap();
+                let decoded = DecodedPacket::from(&version, true, &packet);
+                assert!(matches!(decoded.payload, DecodedPacketPayload::Camera18(_)));
+                camera18 += 1;
+            }
+            PacketType::EntityPosition(p) => {
+                let entity = parser.world().entity(p.entity_id).unwrap();
+                assert_eq!(entity.type_name(), "SmokeScreen");
+                assert_eq!(entity.position().x, p.position.x);
+                assert_eq!(entity.position().z, p.position.z);
+                positions += 1;
+            }
+            PacketType::TargetLock(p) => {
+                if p.target_id != 0 {
+                    let target = parser.world().entity(p.target_id).unwrap();
+                    assert_eq!(target.type_name(), "Vehicle");
+                }
+                target_locks += 1;
+            }
+            _ => {}
+        });
+        assert_eq!(ticks, 6432);
+        assert_eq!(camera18, 8474);
+        assert_eq!(positions, 71);
+        assert_eq!(target_locks, 10);
+    }
+
+    #[test]
+    fn encode_roundtrip() {
+        let replay = crate::ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let version = replay.meta.clientVersionFromExe.parse::<Version>().unwrap();
+        let datafiles = Datafiles::new(std::path::PathBuf::from("../versions"), version).unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+
+        // The encoder needs the world as it was before each packet, so it lags one packet
+        // behind the parser, and is brought up to date by parsing the encoded packet.
+        let mut parser = Parser::new(&specs);
+        let mut encoder = Parser::new(&specs);
+        let mut i = &replay.packet_data[..];
+        let mut count = 0;
+        while !i.is_empty() {
+            let (remaining, packet) = parser.parse_packet(i).unwrap();
+            let original = &i[..i.len() - remaining.len()];
+            i = remaining;
+
+            let encoded = encoder.encode_packet(&packet).unwrap();
+            assert_eq!(
+                encoded, original,
+                "{:x} at {}: {:?}",
+                packet.packet_type, packet.clock, packet.payload
+            );
+            let (rest, reparsed) = encoder.parse_packet(&encoded).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(
+                serde_json::to_value(&reparsed).unwrap(),
+                serde_json::to_value(&packet).unwrap()
+            );
+            count += 1;
+        }
+        assert!(count > 10000);
+    }
+
+    #[test]
+    fn component_methods() {
+        let version = "0,10,9,0".parse::<Version>().unwrap();
+        let datafiles = Datafiles::new(std::path::PathBuf::from("../versions"), version).unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+        let avatar = specs.iter().find(|spec| spec.name == "Avatar").unwrap();
+
+        let mut parser = Parser::new(&specs);
+        let create = Packet {
+            packet_size: 0,
+            packet_type: 0x5,
+            clock: 0.0,
+            payload: PacketType::EntityCreate(EntityCreatePacket {
+                entity_id: 1,
+                entity_type: "Avatar",
+                space_id: 0,
+                vehicle_id: 0,
+                position: Vec3::default(),
+                rotation: Rot3::default(),
+                state_length: 0,
+                props: HashMap::new(),
+            }),
+            raw: &[],
+        };
+        let method = Packet {
+            packet_size: 0,
+            packet_type: 0x8,
+            clock: 1.0,
+            payload: PacketType::EntityMethod(EntityMethodPacket {
+                entity_id: 1,
+                method: "updateHotFixData",
+                component: Some("HotFixComponent"),
+                direction: MethodDirection::ToClient,
+                args: vec![ArgValue::Blob(vec![1, 2, 3])],
+            }),
+            raw: &[],
+        };
+        // The avatar has to exist before the method call can be encoded
+        let data = parser.encode_packet(&create).unwrap();
+        parser.parse_packet(&data).unwrap();
+        let data = parser.encode_packet(&method).unwrap();
+        let (_, packet) = parser.parse_packet(&data).unwrap();
+        match &packet.payload {
+            PacketType::EntityMethod(p) => {
+                assert_eq!(p.method, "updateHotFixData");
+                assert_eq!(p.component, Some("HotFixComponent"));
+                assert_eq!(p.args, vec![ArgValue::Blob(vec![1, 2, 3])]);
+            }
+            payload => panic!("{:?}", payload),
+        }
+        // The method is numbered after the avatar's own methods
+        let index = u32::from_le_bytes(std::convert::TryInto::try_into(&packet.raw[4..8]).unwrap());
+        assert_eq!(index as usize, avatar.client_methods.len() - 1);
+    }
+
+    #[test]
+    fn cell_methods() {
+        let version = "0,10,9,0".parse::<Version>().unwrap();
+        let datafiles = Datafiles::new(std::path::PathBuf::from("../versions"), version).unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+        let vehicle = specs.iter().find(|spec| spec.name == "Vehicle").unwrap();
+
+        let mut parser = Parser::new(&specs);
+        let create = Packet {
+            packet_size: 0,
+            packet_type: 0x5,
+            clock: 0.0,
+            payload: PacketType::EntityCreate(EntityCreatePacket {
+                entity_id: 1,
+                entity_type: "Vehicle",
+                space_id: 0,
+                vehicle_id: 0,
+                position: Vec3::default(),
+                rotation: Rot3::default(),
+                state_length: 0,
+                props: HashMap::new(),
+            }),
+            raw: &[],
+        };
+        let data = parser.encode_packet(&create).unwrap();
+        parser.parse_packet(&data).unwrap();
+        let call = PacketType::EntityMethod(EntityMethodPacket {
+            entity_id: 1,
+            method: "dev_setVehicleHealth",
+            component: None,
+            direction: MethodDirection::ToCell,
+            args: vec![ArgValue::Int32(1234)],
+        });
+        let data = parser.encode_payload(&call).unwrap();
+        let id = u32::from_le_bytes(std::convert::TryInto::try_into(&data[4..8]).unwrap());
+        assert_eq!(
+            Some(id),
+            vehicle.method_id(MethodDirection::ToCell, "dev_setVehicleHealth", None)
+        );
+
+        let packet = parser
+            .parse_entity_method(MethodDirection::ToCell, &data)
+            .unwrap();
+        assert_eq!(packet.method, "dev_setVehicleHealth");
+        assert_eq!(packet.direction, MethodDirection::ToCell);
+        assert!(packet.direction.is_to_server());
+        assert_eq!(packet.args, vec![ArgValue::Int32(1234)]);
+
+        // The same ID means something else among the client methods
+        let packet = parser.parse_entity_method(MethodDirection::ToClient, &data);
+        assert!(packet.map_or(true, |p| p.method != "dev_setVehicleHealth"));
+    }
+
+    #[test]
+    fn packet_iterator_stops_on_error() {
+        let specs = vec![];
+        let mut parser = Parser::new(&specs);
+        // A packet header claiming a payload longer than the data
+        let data = [8, 0, 0, 0, 0x16, 0, 0, 0, 0, 0, 0, 0, 1];
+        let mut packets = parser.packets(&data);
+        assert!(packets.next().unwrap().is_err());
+        assert!(packets.next().is_none());
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let specs = vec![];
+        let mut parser = Parser::new(&specs);
+        let mut data = vec![];
+        // A method call on an entity which was never created
+        data.extend_from_slice(&[12, 0, 0, 0, 0x8, 0, 0, 0]);
+        data.extend_from_slice(&1.5f32.to_le_bytes());
+        data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // A position packet with an unexpected field set
+        data.extend_from_slice(&[45, 0, 0, 0, 0xA, 0, 0, 0]);
+        data.extend_from_slice(&2.0f32.to_le_bytes());
+        data.extend_from_slice(&[1, 0, 0, 0, 1, 0, 0, 0]);
+        data.extend_from_slice(&[0; 37]);
+        // A packet which is cut off
+        data.extend_from_slice(&[4, 0, 0, 0, 0x4, 0, 0, 0]);
+        data.extend_from_slice(&3.0f32.to_le_bytes());
+
+        let mut packets = parser.packets(&data);
+        let packet = packets.next().unwrap().unwrap();
+        match packet.payload {
+            PacketType::Invalid(p) => {
+                assert_eq!(p.offset, 0);
+                assert!(
+                    matches!(*p.error, ErrorKind::UnknownEntity { entity_id: 1 }),
+                    "{:?}",
+                    p.error
+                );
+            }
+            _ => panic!("Expected an invalid packet"),
+        }
+        let packet = packets.next().unwrap().unwrap();
+        match packet.payload {
+            PacketType::Invalid(p) => {
+                assert_eq!(p.offset, 24);
+                assert!(
+                    matches!(*p.error, ErrorKind::UnexpectedPacketValue { .. }),
+                    "{:?}",
+                    p.error
+                );
+                let owned = OwnedInvalidPacket::from(&p);
+                assert!(owned.message.contains("UnexpectedPacketValue"));
+            }
+            _ => panic!("Expected an invalid packet"),
+        }
+        match packets.next().unwrap() {
+            Err(ErrorKind::BadPacket { offset, clock, .. }) => {
+                assert_eq!(offset, 81);
+                assert_eq!(clock, Some(3.0));
+            }
+            _ => panic!("Expected an error"),
+        }
+        assert!(packets.next().is_none());
+    }
+}
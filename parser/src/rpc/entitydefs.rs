@@ -1,7 +1,8 @@
 //use crate::script_type::TypeAliases;
 use crate::rpc::typedefs::{parse_aliases, parse_type, ArgType, TypeAliases};
+use serde_derive::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Flags {
     AllClients,
     CellPublicAndOwn,
@@ -14,39 +15,45 @@ pub enum Flags {
 }
 
 impl Flags {
-    fn from_str(s: &str) -> Self {
+    fn from_str(s: &str) -> Result<Self, String> {
         if s == "ALL_CLIENTS" {
-            Self::AllClients
+            Ok(Self::AllClients)
         } else if s == "CELL_PUBLIC_AND_OWN" {
-            Self::CellPublicAndOwn
+            Ok(Self::CellPublicAndOwn)
         } else if s == "OWN_CLIENT" {
-            Self::OwnClient
+            Ok(Self::OwnClient)
         } else if s == "BASE_AND_CLIENT" {
-            Self::BaseAndClient
+            Ok(Self::BaseAndClient)
         } else if s == "BASE" {
-            Self::Base
+            Ok(Self::Base)
         } else if s == "CELL_PRIVATE" {
-            Self::CellPrivate
+            Ok(Self::CellPrivate)
         } else if s == "CELL_PUBLIC" {
-            Self::CellPublic
+            Ok(Self::CellPublic)
+        } else if s == "OTHER_CLIENTS" {
+            Ok(Self::OtherClients)
         } else {
-            panic!("Unrecognized flag {}!", s);
+            Err(format!("Unrecognized flag {}", s))
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Property {
     pub name: String,
     pub prop_type: ArgType,
     pub flags: Flags,
+    /// The component which defines this property, if it isn't defined by the entity itself
+    pub component: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Method {
     pub name: String,
     variable_length_header_size: usize,
     pub args: Vec<ArgType>,
+    /// The component which defines this method, if it isn't defined by the entity itself
+    pub component: Option<String>,
 }
 
 impl Method {
@@ -64,14 +71,35 @@ impl Method {
     }
 }
 
+/// Which side of the connection a method call goes to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MethodDirection {
+    /// A client method, called by the server
+    ToClient,
+    /// A base method, called by the client
+    ToBase,
+    /// A cell method, called by the client
+    ToCell,
+}
+
+impl MethodDirection {
+    /// Whether the call goes from the client to the server
+    pub fn is_to_server(self) -> bool {
+        self != Self::ToClient
+    }
+}
+
 struct DefFile {
     base_methods: Vec<Method>,
     cell_methods: Vec<Method>,
     client_methods: Vec<Method>,
     properties: Vec<Property>,
     implements: Vec<String>,
+    /// For components, the entities which the component is attached to
+    of_entity: Vec<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
 pub struct EntitySpec {
     pub name: String,
     pub base_methods: Vec<Method>,
@@ -79,6 +107,39 @@ pub struct EntitySpec {
     pub client_methods: Vec<Method>,
     pub properties: Vec<Property>,
     pub internal_properties: Vec<Property>,
+    /// The components attached to this entity. Their methods and properties follow the
+    /// entity's own, in this order.
+    pub components: Vec<String>,
+}
+
+impl EntitySpec {
+    /// The methods which can be called in the given direction, ordered by their IDs
+    pub fn methods(&self, direction: MethodDirection) -> &[Method] {
+        match direction {
+            MethodDirection::ToClient => &self.client_methods,
+            MethodDirection::ToBase => &self.base_methods,
+            MethodDirection::ToCell => &self.cell_methods,
+        }
+    }
+
+    /// Looks up a method by its ID
+    pub fn method(&self, direction: MethodDirection, id: u32) -> Option<&Method> {
+        self.methods(direction).get(id as usize)
+    }
+
+    /// Looks up the ID of a method. `component` is the component defining the method, or
+    /// None for the entity's own methods.
+    pub fn method_id(
+        &self,
+        direction: MethodDirection,
+        name: &str,
+        component: Option<&str>,
+    ) -> Option<u32> {
+        self.methods(direction)
+            .iter()
+            .position(|method| method.name == name && method.component.as_deref() == component)
+            .map(|id| id as u32)
+    }
 }
 
 fn child_by_name<'a, 'b>(
@@ -93,38 +154,54 @@ fn child_by_name<'a, 'b>(
     None
 }
 
-fn parse_implements(ilist: &roxmltree::Node) -> Vec<String> {
+/// Returns the tag names of the node's child elements
+fn parse_names(list: &roxmltree::Node) -> Vec<String> {
+    list.children()
+        .filter(|child| child.is_element())
+        .map(|child| child.tag_name().name().to_string())
+        .collect()
+}
+
+fn parse_implements(ilist: &roxmltree::Node) -> Result<Vec<String>, String> {
     let mut implements = vec![];
     for implement in ilist.children() {
         if !implement.is_element() {
             continue;
         }
 
-        implements.push(implement.text().unwrap().to_string());
+        let name = implement
+            .text()
+            .ok_or_else(|| "Implements has an empty entry".to_string())?;
+        implements.push(name.to_string());
     }
-    implements
+    Ok(implements)
 }
 
-fn parse_properties(plist: &roxmltree::Node, aliases: &TypeAliases) -> Vec<Property> {
+fn parse_properties(
+    plist: &roxmltree::Node,
+    aliases: &TypeAliases,
+) -> Result<Vec<Property>, String> {
     let mut properties = vec![];
     for property in plist.children() {
         if !property.is_element() {
             continue;
         }
 
+        let name = property.tag_name().name();
+        let prop_type = child_by_name(&property, "Type")
+            .ok_or_else(|| format!("Property {} has no Type", name))?;
+        let flags = child_by_name(&property, "Flags")
+            .and_then(|flags| flags.text())
+            .ok_or_else(|| format!("Property {} has no Flags", name))?;
         properties.push(Property {
-            name: property.tag_name().name().to_string(),
-            prop_type: parse_type(&child_by_name(&property, "Type").unwrap(), aliases),
-            flags: Flags::from_str(
-                child_by_name(&property, "Flags")
-                    .unwrap()
-                    .text()
-                    .unwrap()
-                    .trim(),
-            ),
+            name: name.to_string(),
+            prop_type: parse_type(&prop_type, aliases),
+            flags: Flags::from_str(flags.trim())
+                .map_err(|e| format!("Property {} has bad Flags: {}", name, e))?,
+            component: None,
         });
     }
-    properties
+    Ok(properties)
 }
 
 fn parse_method(method: &roxmltree::Node, aliases: &TypeAliases) -> Method {
@@ -170,6 +247,7 @@ fn parse_method(method: &roxmltree::Node, aliases: &TypeAliases) -> Method {
         name: method.tag_name().name().to_string(),
         variable_length_header_size,
         args: args,
+        component: None,
     }
 }
 
@@ -184,12 +262,22 @@ fn parse_method_list(mlist: &roxmltree::Node, aliases: &TypeAliases) -> Vec<Meth
     methods
 }
 
-fn parse_def(def: &[u8], aliases: &TypeAliases) -> DefFile {
-    let def = std::str::from_utf8(def).unwrap();
+/// Parses the .def file at `path` in the game data
+fn parse_def(
+    def: &[u8],
+    path: &str,
+    aliases: &TypeAliases,
+) -> Result<DefFile, crate::error::ErrorKind> {
+    let invalid = |reason: String| crate::error::ErrorKind::InvalidEntityDef {
+        path: path.to_string(),
+        reason,
+    };
+    let def = std::str::from_utf8(def).map_err(|e| invalid(e.to_string()))?;
     //let def = std::fs::read_to_string(&file).unwrap();
-    let doc = roxmltree::Document::parse(&def).unwrap();
+    let doc = roxmltree::Document::parse(&def).map_err(|e| invalid(e.to_string()))?;
     let root = doc.root();
-    let root = child_by_name(&root, "root").unwrap();
+    let root =
+        child_by_name(&root, "root").ok_or_else(|| invalid("Missing root element".to_string()))?;
     //println!("{:?}", doc);
 
     // Parse out Implements, Properties, and ClientMethods
@@ -205,13 +293,22 @@ fn parse_def(def: &[u8], aliases: &TypeAliases) -> DefFile {
             .unwrap_or(vec![]),
         properties: child_by_name(&root, "Properties")
             .map(|n| parse_properties(&n, aliases))
+            .transpose()
+            .map_err(invalid)?
             .unwrap_or(vec![]),
         implements: child_by_name(&root, "Implements")
             .map(|n| parse_implements(&n))
+            .transpose()
+            .map_err(invalid)?
+            .unwrap_or(vec![]),
+        of_entity: child_by_name(&root, "ofEntity")
+            .map(|n| parse_names(&n))
             .unwrap_or(vec![]),
     };
+    def.base_methods.sort_by_key(|method| method.sort_size());
+    def.cell_methods.sort_by_key(|method| method.sort_size());
     def.client_methods.sort_by_key(|method| method.sort_size());
-    def
+    Ok(def)
 
     /*let mut implements = vec![];
     for child in root.first_child().unwrap().children() {
@@ -254,6 +351,127 @@ fn parse_def(def: &[u8], aliases: &TypeAliases) -> DefFile {
     DefFile { implements }*/
 }
 
+/// Appends the definitions of the given interfaces to `defs`, each one preceded by the
+/// interfaces it implements in turn, so that the most distant ancestors come first. `stack`
+/// holds the interfaces currently being resolved, so that cycles can be reported.
+fn resolve_interfaces(
+    gamedata: &crate::version::Datafiles,
+    aliases: &TypeAliases,
+    implements: &[String],
+    stack: &mut Vec<String>,
+    defs: &mut Vec<DefFile>,
+) -> Result<(), crate::error::ErrorKind> {
+    for parent in implements.iter() {
+        let parent = parent.trim();
+        if stack.iter().any(|name| name == parent) {
+            let mut interfaces = stack.clone();
+            interfaces.push(parent.to_string());
+            return Err(crate::error::ErrorKind::InterfaceCycle { interfaces });
+        }
+        let path = format!("scripts/entity_defs/interfaces/{}.def", parent);
+        let mut def = parse_def(&gamedata.get(&path)?, &path, aliases)?;
+        stack.push(parent.to_string());
+        resolve_interfaces(gamedata, aliases, &def.implements, stack, defs)?;
+        stack.pop();
+        def.implements = vec![];
+        defs.push(def);
+    }
+    Ok(())
+}
+
+/// Parses the components listed in components.xml, along with the interfaces they implement.
+/// Older game versions have no components.
+fn parse_components(
+    gamedata: &crate::version::Datafiles,
+    aliases: &TypeAliases,
+) -> Result<Vec<(String, DefFile)>, crate::error::ErrorKind> {
+    let components_xml = match gamedata.get("scripts/components.xml") {
+        Ok(x) => x,
+        Err(crate::error::ErrorKind::DatafileNotFound { .. }) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let invalid = |reason: String| crate::error::ErrorKind::InvalidEntityDef {
+        path: "scripts/components.xml".to_string(),
+        reason,
+    };
+    let doc = roxmltree::Document::parse(std::str::from_utf8(&components_xml)?)
+        .map_err(|e| invalid(e.to_string()))?;
+    let root = child_by_name(&doc.root(), "root")
+        .ok_or_else(|| invalid("Missing root element".to_string()))?;
+
+    let mut components = vec![];
+    for name in parse_names(&root) {
+        let path = format!("scripts/component_defs/{}.def", name);
+        let def = parse_def(&gamedata.get(&path)?, &path, aliases)?;
+        let mut defs = vec![];
+        resolve_interfaces(gamedata, aliases, &def.implements, &mut vec![], &mut defs)?;
+        defs.push(def);
+
+        let mut component = defs.into_iter().fold(
+            DefFile {
+                base_methods: vec![],
+                cell_methods: vec![],
+                client_methods: vec![],
+                properties: vec![],
+                implements: vec![],
+                of_entity: vec![],
+            },
+            |mut a, mut b| {
+                a.base_methods.append(&mut b.base_methods);
+                a.cell_methods.append(&mut b.cell_methods);
+                a.client_methods.append(&mut b.client_methods);
+                a.properties.append(&mut b.properties);
+                a.of_entity.append(&mut b.of_entity);
+                a
+            },
+        );
+        component
+            .base_methods
+            .sort_by_key(|method| method.sort_size());
+        component
+            .cell_methods
+            .sort_by_key(|method| method.sort_size());
+        component
+            .client_methods
+            .sort_by_key(|method| method.sort_size());
+        component
+            .properties
+            .retain(|property| is_client_property(property.flags));
+        component
+            .properties
+            .sort_by_key(|prop| prop.prop_type.sort_size());
+        for method in component
+            .base_methods
+            .iter_mut()
+            .chain(component.cell_methods.iter_mut())
+            .chain(component.client_methods.iter_mut())
+        {
+            method.component = Some(name.clone());
+        }
+        for property in component.properties.iter_mut() {
+            property.component = Some(name.clone());
+        }
+        components.push((name, component));
+    }
+    Ok(components)
+}
+
+/// Whether the property is sent to clients
+fn is_client_property(flags: Flags) -> bool {
+    /*
+                EntityFlags.ALL_CLIENTS |
+                EntityFlags.BASE_AND_CLIENT |
+                EntityFlags.OTHER_CLIENTS |
+                EntityFlags.OWN_CLIENT |
+                EntityFlags.CELL_PUBLIC_AND_OWN |
+    */
+    flags == Flags::AllClients
+        || flags == Flags::BaseAndClient
+        || flags == Flags::OtherClients
+        || flags == Flags::OwnClient
+        || flags == Flags::CellPublicAndOwn
+}
+
 pub fn parse_scripts(
     gamedata: &crate::version::Datafiles,
 ) -> Result<Vec<EntitySpec>, crate::error::ErrorKind> {
@@ -264,72 +482,52 @@ pub fn parse_scripts(
 
     //let entities_xml_path = gamedata.lookup("scripts/entities.xml");
     //let entities_xml = std::fs::read_to_string(&entities_xml_path).unwrap();
+    let components = parse_components(gamedata, &aliases)?;
+
     let entities_xml = gamedata.get("scripts/entities.xml")?;
-    let doc = roxmltree::Document::parse(std::str::from_utf8(&entities_xml).unwrap()).unwrap();
-    let root = doc.root();
+    let invalid = |reason: String| crate::error::ErrorKind::InvalidEntityDef {
+        path: "scripts/entities.xml".to_string(),
+        reason,
+    };
+    let entities_xml = std::str::from_utf8(&entities_xml).map_err(|e| invalid(e.to_string()))?;
+    let doc = roxmltree::Document::parse(entities_xml).map_err(|e| invalid(e.to_string()))?;
+    let root = child_by_name(&doc.root(), "root")
+        .ok_or_else(|| invalid("Missing root element".to_string()))?;
+    let client_server_entities = child_by_name(&root, "ClientServerEntities")
+        .ok_or_else(|| invalid("Missing ClientServerEntities".to_string()))?;
     let mut entities = vec![];
-    for child in child_by_name(
-        &child_by_name(&root, "root").unwrap(),
-        "ClientServerEntities",
-    )
-    .unwrap()
-    .children()
-    {
+    for child in client_server_entities.children() {
         if !child.is_element() {
             continue;
         }
 
-        let def = gamedata.get(&format!(
-            "scripts/entity_defs/{}.def",
-            child.tag_name().name()
-        ))?;
-        let mut def = parse_def(&def, &aliases);
-        let inherits = def
-            .implements
-            .iter()
-            .map(|parent| {
-                let parent = gamedata
-                    .get(&format!("scripts/entity_defs/interfaces/{}.def", parent))
-                    .unwrap();
-                parse_def(&parent, &aliases)
-            })
-            .flat_map(|mut parent| {
-                // Sometimes, our parents have parents of our own. For now we only support
-                // a single level of indirection.
-                let mut result: Vec<_> = parent
-                    .implements
-                    .iter()
-                    .map(|parent| {
-                        let parent = parent.trim();
-                        let parent = gamedata
-                            .get(&format!("scripts/entity_defs/interfaces/{}.def", parent))
-                            .unwrap();
-                        //println!("Parsing parent {}...", parent);
-                        parse_def(&parent, &aliases)
-                    })
-                    .collect();
-                parent.implements = vec![];
-                result.push(parent);
-                result
-            })
-            .fold(
-                DefFile {
-                    base_methods: vec![],
-                    cell_methods: vec![],
-                    client_methods: vec![],
-                    properties: vec![],
-                    implements: vec![],
-                },
-                |mut a, mut b| {
-                    a.base_methods.append(&mut b.base_methods);
-                    a.cell_methods.append(&mut b.cell_methods);
-                    a.client_methods.append(&mut b.client_methods);
-                    a.properties.append(&mut b.properties);
-                    assert!(a.implements.len() == 0);
-                    assert!(b.implements.len() == 0);
-                    a
-                },
-            );
+        let path = format!("scripts/entity_defs/{}.def", child.tag_name().name());
+        let mut def = parse_def(&gamedata.get(&path)?, &path, &aliases)?;
+        let mut parents = vec![];
+        resolve_interfaces(
+            gamedata,
+            &aliases,
+            &def.implements,
+            &mut vec![],
+            &mut parents,
+        )?;
+        let inherits = parents.into_iter().fold(
+            DefFile {
+                base_methods: vec![],
+                cell_methods: vec![],
+                client_methods: vec![],
+                properties: vec![],
+                implements: vec![],
+                of_entity: vec![],
+            },
+            |mut a, mut b| {
+                a.base_methods.append(&mut b.base_methods);
+                a.cell_methods.append(&mut b.cell_methods);
+                a.client_methods.append(&mut b.client_methods);
+                a.properties.append(&mut b.properties);
+                a
+            },
+        );
         /*println!(
             "{} has {} properties + {} inherited properties",
             child.tag_name().name(),
@@ -346,6 +544,8 @@ pub fn parse_scripts(
         let mut client_methods = inherits.client_methods;
         client_methods.append(&mut def.client_methods);
 
+        base_methods.sort_by_key(|method| method.sort_size());
+        cell_methods.sort_by_key(|method| method.sort_size());
         client_methods.sort_by_key(|method| method.sort_size());
 
         let mut properties = inherits.properties;
@@ -373,25 +573,26 @@ pub fn parse_scripts(
 
         properties = properties
             .iter()
-            .filter(|property| {
-                /*
-                            EntityFlags.ALL_CLIENTS |
-                            EntityFlags.BASE_AND_CLIENT |
-                            EntityFlags.OTHER_CLIENTS |
-                            EntityFlags.OWN_CLIENT |
-                            EntityFlags.CELL_PUBLIC_AND_OWN |
-                */
-                property.flags == Flags::AllClients
-                    || property.flags == Flags::BaseAndClient
-                    || property.flags == Flags::OtherClients
-                    || property.flags == Flags::OwnClient
-                    || property.flags == Flags::CellPublicAndOwn
-            })
+            .filter(|property| is_client_property(property.flags))
             .map(|property| (*property).clone())
             .collect();
 
         properties.sort_by_key(|prop| prop.prop_type.sort_size());
 
+        // Components' methods and properties are numbered after the entity's own
+        let name = child.tag_name().name();
+        let mut component_names = vec![];
+        for (component_name, component) in components.iter() {
+            if !component.of_entity.iter().any(|entity| entity == name) {
+                continue;
+            }
+            component_names.push(component_name.clone());
+            base_methods.extend(component.base_methods.iter().cloned());
+            cell_methods.extend(component.cell_methods.iter().cloned());
+            client_methods.extend(component.client_methods.iter().cloned());
+            properties.extend(component.properties.iter().cloned());
+        }
+
         entities.push(EntitySpec {
             name: child.tag_name().name().to_string(),
             base_methods,
@@ -399,6 +600,7 @@ pub fn parse_scripts(
             client_methods,
             properties,
             internal_properties,
+            components: component_names,
         });
     }
 
@@ -436,3 +638,283 @@ pub fn parse_scripts(
     }*/
     Ok(entities)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorKind;
+    use crate::version::{Datafiles, Version};
+    use std::path::PathBuf;
+
+    /// Writes a set of entity definitions for a fake game version, with one entity named
+    /// "Thing" which implements `implements`. Each interface is given as its name, the
+    /// interface it implements (if any) and the name of its property.
+    fn write_defs(
+        name: &str,
+        implements: &str,
+        interfaces: &[(&str, &str, &str)],
+    ) -> (PathBuf, Datafiles) {
+        let base = std::env::temp_dir().join(format!(
+            "wows-replays-entitydefs-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let version = "99,0,0,0".parse::<Version>().unwrap();
+        let root = base.join(version.to_path()).join("scripts");
+        std::fs::create_dir_all(root.join("entity_defs/interfaces")).unwrap();
+        std::fs::write(
+            root.join("entity_defs/alias.xml"),
+            "<root><BOOL>UINT8</BOOL></root>",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("entities.xml"),
+            "<root><ClientServerEntities><Thing/></ClientServerEntities></root>",
+        )
+        .unwrap();
+
+        let def = |implements: &str, prop: &str| {
+            format!(
+                "<root>
+                    <Implements><Interface>{}</Interface></Implements>
+                    <Properties><{}><Type>BOOL</Type><Flags>ALL_CLIENTS</Flags></{}></Properties>
+                    <ClientMethods><{}Changed><Arg>UINT8</Arg></{}Changed></ClientMethods>
+                </root>",
+                implements, prop, prop, prop, prop
+            )
+            .replace("<Implements><Interface></Interface></Implements>", "")
+        };
+        std::fs::write(root.join("entity_defs/Thing.def"), def(implements, "thing")).unwrap();
+        for (interface, implements, prop) in interfaces.iter() {
+            std::fs::write(
+                root.join(format!("entity_defs/interfaces/{}.def", interface)),
+                def(implements, prop),
+            )
+            .unwrap();
+        }
+        (base.clone(), Datafiles::new(base, version).unwrap())
+    }
+
+    #[test]
+    fn deep_interfaces() {
+        let (base, datafiles) = write_defs(
+            "deep",
+            "A",
+            &[
+                ("A", "B", "a"),
+                ("B", "C", "b"),
+                ("C", "D", "c"),
+                ("D", "", "d"),
+            ],
+        );
+        let specs = parse_scripts(&datafiles);
+        std::fs::remove_dir_all(base).unwrap();
+
+        let specs = specs.unwrap();
+        assert_eq!(specs.len(), 1);
+        // The most distant ancestors come first
+        let names: Vec<_> = specs[0]
+            .properties
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["d", "c", "b", "a", "thing"]);
+        let names: Vec<_> = specs[0]
+            .client_methods
+            .iter()
+            .map(|m| m.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "dChanged",
+                "cChanged",
+                "bChanged",
+                "aChanged",
+                "thingChanged"
+            ]
+        );
+    }
+
+    #[test]
+    fn components() {
+        let datafiles = Datafiles::new(
+            PathBuf::from("../versions"),
+            "0,10,9,0".parse::<Version>().unwrap(),
+        )
+        .unwrap();
+        let specs = parse_scripts(&datafiles).unwrap();
+        let spec = |name: &str| specs.iter().find(|spec| spec.name == name).unwrap();
+
+        let avatar = spec("Avatar");
+        assert_eq!(avatar.components, vec!["HotFixComponent"]);
+        let method = avatar.client_methods.last().unwrap();
+        assert_eq!(method.name, "updateHotFixData");
+        assert_eq!(method.component.as_deref(), Some("HotFixComponent"));
+        assert_eq!(
+            avatar
+                .client_methods
+                .iter()
+                .filter(|method| method.component.is_some())
+                .count(),
+            1
+        );
+
+        // Each component's methods follow the entity's own, in the order of components.xml
+        let account = spec("Account");
+        assert_eq!(account.components.len(), 10);
+        let components: Vec<_> = account
+            .client_methods
+            .iter()
+            .map(|method| method.component.as_deref())
+            .collect();
+        let first = components.iter().position(|c| c.is_some()).unwrap();
+        assert!(components[first..].iter().all(|c| c.is_some()));
+        let mut order: Vec<_> = components[first..].iter().map(|c| c.unwrap()).collect();
+        order.dedup();
+        let expected: Vec<_> = account
+            .components
+            .iter()
+            .map(|c| c.as_str())
+            .filter(|c| order.contains(c))
+            .collect();
+        assert_eq!(order, expected);
+
+        assert!(spec("Vehicle").components.is_empty());
+    }
+
+    #[test]
+    fn method_lookups() {
+        let datafiles = Datafiles::new(
+            PathBuf::from("../versions"),
+            "0,10,9,0".parse::<Version>().unwrap(),
+        )
+        .unwrap();
+        let specs = parse_scripts(&datafiles).unwrap();
+        let vehicle = specs.iter().find(|spec| spec.name == "Vehicle").unwrap();
+
+        for direction in [
+            MethodDirection::ToClient,
+            MethodDirection::ToBase,
+            MethodDirection::ToCell,
+        ]
+        .iter()
+        {
+            let methods = vehicle.methods(*direction);
+            assert!(methods
+                .windows(2)
+                .all(|pair| pair[0].sort_size() <= pair[1].sort_size()));
+            for (id, method) in methods.iter().enumerate() {
+                let found = vehicle
+                    .method_id(*direction, &method.name, method.component.as_deref())
+                    .unwrap();
+                // Names aren't unique across interfaces, but lookups find the first one
+                assert_eq!(vehicle.method(*direction, found).unwrap().name, method.name);
+                assert!(found as usize <= id);
+            }
+        }
+
+        let suicide = vehicle
+            .method_id(MethodDirection::ToCell, "suicide", None)
+            .unwrap();
+        assert!(vehicle
+            .method(MethodDirection::ToCell, suicide)
+            .unwrap()
+            .args
+            .is_empty());
+        let teleport = vehicle
+            .method_id(MethodDirection::ToCell, "dev_teleportShip", None)
+            .unwrap();
+        assert!(teleport > suicide);
+        assert!(vehicle
+            .method_id(MethodDirection::ToClient, "suicide", None)
+            .is_none());
+        assert!(vehicle.method(MethodDirection::ToCell, 10000).is_none());
+    }
+
+    #[test]
+    fn interface_cycle() {
+        let (base, datafiles) = write_defs(
+            "cycle",
+            "A",
+            &[("A", "B", "a"), ("B", "C", "b"), ("C", "A", "c")],
+        );
+        let specs = parse_scripts(&datafiles);
+        std::fs::remove_dir_all(base).unwrap();
+
+        match specs {
+            Err(ErrorKind::InterfaceCycle { interfaces }) => {
+                assert_eq!(interfaces, vec!["A", "B", "C", "A"]);
+            }
+            _ => panic!("Expected an interface cycle"),
+        }
+    }
+
+    #[test]
+    fn missing_interface() {
+        let (base, datafiles) = write_defs("missing", "A", &[("A", "B", "a")]);
+        let specs = parse_scripts(&datafiles);
+        std::fs::remove_dir_all(base).unwrap();
+
+        match specs {
+            Err(ErrorKind::DatafileNotFound { path, .. }) => {
+                assert_eq!(path, "scripts/entity_defs/interfaces/B.def");
+            }
+            _ => panic!("Expected a missing datafile"),
+        }
+    }
+
+    #[test]
+    fn malformed_def() {
+        let contents: &[&[u8]] = &[
+            b"<root><Implements><Interface/></Implements></root>",
+            b"<root><Properties><a><Flags>ALL_CLIENTS</Flags></a></Properties></root>",
+            b"<root><Properties><a><Type>UINT8</Type><Flags>NOPE</Flags></a></Properties></root>",
+            b"<root><Implements></Properties></root>",
+            b"<notroot/>",
+            b"<root>\xff</root>",
+        ];
+        for (idx, content) in contents.iter().enumerate() {
+            let (base, datafiles) = write_defs(&format!("malformed{}", idx), "A", &[]);
+            let path = "scripts/entity_defs/interfaces/A.def";
+            let version: Version = "99,0,0,0".parse().unwrap();
+            std::fs::write(base.join(version.to_path()).join(path), content).unwrap();
+            let specs = parse_scripts(&datafiles);
+            std::fs::remove_dir_all(base).unwrap();
+
+            match specs {
+                Err(ErrorKind::InvalidEntityDef { path: found, .. }) => assert_eq!(found, path),
+                other => panic!("Expected a malformed def for {}: {:?}", idx, other),
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_entities_xml() {
+        let contents: &[&[u8]] = &[
+            b"<root><ClientServerEntities><Thing/></root>",
+            b"<notroot/>",
+            b"<root><ServerOnlyEntities/></root>",
+            b"<root>\xff</root>",
+        ];
+        for (idx, content) in contents.iter().enumerate() {
+            let (base, datafiles) = write_defs(&format!("malformed-entities{}", idx), "", &[]);
+            let path = "scripts/entities.xml";
+            let version: Version = "99,0,0,0".parse().unwrap();
+            std::fs::write(base.join(version.to_path()).join(path), content).unwrap();
+            let specs = parse_scripts(&datafiles);
+            std::fs::remove_dir_all(base).unwrap();
+
+            match specs {
+                Err(ErrorKind::InvalidEntityDef { path: found, .. }) => assert_eq!(found, path),
+                other => panic!("Expected a malformed entities.xml for {}: {:?}", idx, other),
+            }
+        }
+    }
+
+    #[test]
+    fn other_clients_flag() {
+        assert_eq!(Flags::from_str("OTHER_CLIENTS"), Ok(Flags::OtherClients));
+        assert!(Flags::from_str("NOPE").is_err());
+    }
+}
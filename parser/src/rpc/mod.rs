@@ -1,2 +1,3 @@
 pub mod entitydefs;
 pub mod typedefs;
+pub mod udo;
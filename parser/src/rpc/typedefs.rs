@@ -5,6 +5,7 @@ use nom::{
     number::complete::le_u16, number::complete::le_u32,
 };
 use serde::ser::{SerializeMap, SerializeSeq, SerializeTuple};
+use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::TryInto;
 
@@ -22,7 +23,7 @@ fn child_by_name<'a, 'b>(
     None
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum PrimitiveType {
     Uint8,
     Uint16,
@@ -138,20 +139,79 @@ impl PrimitiveType {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// Writes a String, UnicodeString or Blob, using the long form for anything 0xff bytes or longer.
+fn encode_sized(data: &[u8], out: &mut Vec<u8>) -> Result<(), ErrorKind> {
+    if data.len() < 0xff {
+        out.push(data.len() as u8);
+    } else if data.len() <= 0xffff {
+        out.push(0xff);
+        out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        out.push(0);
+    } else {
+        return Err(ErrorKind::UnableToEncodeRpcValue {
+            argtype: "sized".to_string(),
+            error: format!("{} bytes is too long", data.len()),
+        });
+    }
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+impl PrimitiveType {
+    fn encode_value(&self, value: &ArgValue, out: &mut Vec<u8>) -> Result<(), ErrorKind> {
+        match (self, value) {
+            (PrimitiveType::Uint8, ArgValue::Uint8(v)) => out.extend_from_slice(&v.to_le_bytes()),
+            (PrimitiveType::Uint16, ArgValue::Uint16(v)) => out.extend_from_slice(&v.to_le_bytes()),
+            (PrimitiveType::Uint32, ArgValue::Uint32(v)) => out.extend_from_slice(&v.to_le_bytes()),
+            (PrimitiveType::Uint64, ArgValue::Uint64(v)) => out.extend_from_slice(&v.to_le_bytes()),
+            (PrimitiveType::Int8, ArgValue::Int8(v)) => out.extend_from_slice(&v.to_le_bytes()),
+            (PrimitiveType::Int16, ArgValue::Int16(v)) => out.extend_from_slice(&v.to_le_bytes()),
+            (PrimitiveType::Int32, ArgValue::Int32(v)) => out.extend_from_slice(&v.to_le_bytes()),
+            (PrimitiveType::Int64, ArgValue::Int64(v)) => out.extend_from_slice(&v.to_le_bytes()),
+            (PrimitiveType::Float32, ArgValue::Float32(v)) => {
+                out.extend_from_slice(&v.to_le_bytes())
+            }
+            (PrimitiveType::Float64, ArgValue::Float64(v)) => {
+                out.extend_from_slice(&v.to_le_bytes())
+            }
+            (PrimitiveType::Vector2, ArgValue::Vector2((x, y))) => {
+                out.extend_from_slice(&x.to_le_bytes());
+                out.extend_from_slice(&y.to_le_bytes());
+            }
+            (PrimitiveType::Vector3, ArgValue::Vector3((x, y, z))) => {
+                out.extend_from_slice(&x.to_le_bytes());
+                out.extend_from_slice(&y.to_le_bytes());
+                out.extend_from_slice(&z.to_le_bytes());
+            }
+            (PrimitiveType::String, ArgValue::String(data))
+            | (PrimitiveType::UnicodeString, ArgValue::UnicodeString(data))
+            | (PrimitiveType::Blob, ArgValue::Blob(data)) => encode_sized(data, out)?,
+            _ => {
+                return Err(ErrorKind::UnableToEncodeRpcValue {
+                    argtype: format!("{:?}", self),
+                    error: format!("Mismatched value {:?}", value),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct FixedDictProperty {
     pub name: String,
     pub prop_type: ArgType,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ArgType {
     Primitive(PrimitiveType),
     Array((Option<usize>, Box<ArgType>)),
 
     /// (allow_none, properties)
     FixedDict((bool, Vec<FixedDictProperty>)),
-    Tuple((Box<ArgType>, usize)),
+    /// (element type, size). Tuples are sent the same way as arrays.
+    Tuple((Box<ArgType>, Option<usize>)),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -219,7 +279,7 @@ impl<'argtype> serde::Serialize for ArgValue<'argtype> {
                     Err(_) => serializer.serialize_bytes(&blob),
                 }
             }
-            Self::Array(a) => {
+            Self::Array(a) | Self::Tuple(a) => {
                 let mut seq = serializer.serialize_seq(Some(a.len()))?;
                 for element in a.iter() {
                     seq.serialize_element(element)?;
@@ -241,9 +301,64 @@ impl<'argtype> serde::Serialize for ArgValue<'argtype> {
                 obj.end()
             }
             Self::NullableFixedDict(None) => serializer.serialize_none(),
-            Self::Tuple(_t) => {
-                unimplemented!();
-            }
+        }
+    }
+}
+
+/// An owned version of [ArgValue], which doesn't borrow the property names from the
+/// entity specs. Unlike [ArgValue], this serializes with the type of each value so that it
+/// can be deserialized again.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OwnedArgValue {
+    Uint8(u8),
+    Uint16(u16),
+    Uint32(u32),
+    Uint64(u64),
+    Int8(i8),
+    Int16(i16),
+    Int32(i32),
+    Int64(i64),
+    Float32(f32),
+    Float64(f64),
+    Vector2((f32, f32)),
+    Vector3((f32, f32, f32)),
+    String(Vec<u8>),
+    UnicodeString(Vec<u8>),
+    Blob(Vec<u8>),
+    Array(Vec<OwnedArgValue>),
+    FixedDict(HashMap<String, OwnedArgValue>),
+    NullableFixedDict(Option<HashMap<String, OwnedArgValue>>),
+    Tuple(Vec<OwnedArgValue>),
+}
+
+fn owned_dict(d: &HashMap<&str, ArgValue>) -> HashMap<String, OwnedArgValue> {
+    d.iter().map(|(k, v)| (k.to_string(), v.into())).collect()
+}
+
+impl<'argtype> From<&ArgValue<'argtype>> for OwnedArgValue {
+    fn from(value: &ArgValue<'argtype>) -> Self {
+        match value {
+            ArgValue::Uint8(i) => Self::Uint8(*i),
+            ArgValue::Uint16(i) => Self::Uint16(*i),
+            ArgValue::Uint32(i) => Self::Uint32(*i),
+            ArgValue::Uint64(i) => Self::Uint64(*i),
+            ArgValue::Int8(i) => Self::Int8(*i),
+            ArgValue::Int16(i) => Self::Int16(*i),
+            ArgValue::Int32(i) => Self::Int32(*i),
+            ArgValue::Int64(i) => Self::Int64(*i),
+            ArgValue::Float32(f) => Self::Float32(*f),
+            ArgValue::Float64(f) => Self::Float64(*f),
+            ArgValue::Vector2(v) => Self::Vector2(*v),
+            ArgValue::Vector3(v) => Self::Vector3(*v),
+            ArgValue::String(s) => Self::String(s.clone()),
+            ArgValue::UnicodeString(s) => Self::UnicodeString(s.clone()),
+            ArgValue::Blob(blob) => Self::Blob(blob.clone()),
+            ArgValue::Array(a) => Self::Array(a.iter().map(|v| v.into()).collect()),
+            ArgValue::FixedDict(d) => Self::FixedDict(owned_dict(d)),
+            ArgValue::NullableFixedDict(d) => {
+                Self::NullableFixedDict(d.as_ref().map(|d| owned_dict(d)))
+            }
+            ArgValue::Tuple(t) => Self::Tuple(t.iter().map(|v| v.into()).collect()),
         }
     }
 }
@@ -268,8 +383,8 @@ impl ArgType {
             Self::Primitive(PrimitiveType::String) => INFINITY,
             Self::Primitive(PrimitiveType::UnicodeString) => INFINITY,
             Self::Primitive(PrimitiveType::Blob) => INFINITY,
-            Self::Array((None, _)) => INFINITY,
-            Self::Array((Some(count), t)) => {
+            Self::Array((None, _)) | Self::Tuple((_, None)) => INFINITY,
+            Self::Array((Some(count), t)) | Self::Tuple((t, Some(count))) => {
                 let sort_size = t.sort_size();
                 if sort_size == INFINITY {
                     INFINITY
@@ -292,14 +407,6 @@ impl ArgType {
                         }
                     })
             }
-            Self::Tuple((t, count)) => {
-                let sort_size = t.sort_size();
-                if sort_size == INFINITY {
-                    INFINITY
-                } else {
-                    sort_size * count
-                }
-            }
         }
     }
 
@@ -307,18 +414,13 @@ impl ArgType {
         match self {
             Self::Primitive(p) => p.parse_value(i),
             Self::Array((count, atype)) => {
-                let mut values = vec![];
-                let (mut i, length) = match count {
-                    Some(count) => (i, *count as u8),
-                    None => le_u8(i)?,
-                };
-                for _ in 0..length {
-                    let (new_i, element) = atype.parse_value(i)?;
-                    i = new_i;
-                    values.push(element);
-                }
+                let (i, values) = parse_sequence(*count, atype, i)?;
                 Ok((i, ArgValue::Array(values)))
             }
+            Self::Tuple((atype, count)) => {
+                let (i, values) = parse_sequence(*count, atype, i)?;
+                Ok((i, ArgValue::Tuple(values)))
+            }
             Self::FixedDict((allow_none, props)) => {
                 let mut dict: HashMap<&'b str, ArgValue<'b>> = HashMap::new();
                 let mut i = i;
@@ -349,9 +451,127 @@ impl ArgType {
                     Ok((i, ArgValue::FixedDict(dict)))
                 }
             }
-            Self::Tuple((_t, _count)) => {
-                panic!("Tuple parsing is unsupported");
+        }
+    }
+}
+
+/// Parses the elements of an array or tuple, which are preceded by their count unless the
+/// type has a fixed size
+fn parse_sequence<'a, 'b>(
+    count: Option<usize>,
+    atype: &'b ArgType,
+    i: &'a [u8],
+) -> IResult<&'a [u8], Vec<ArgValue<'b>>> {
+    let mut values = vec![];
+    let (mut i, length) = match count {
+        Some(count) => (i, count),
+        None => {
+            let (i, length) = le_u8(i)?;
+            (i, length as usize)
+        }
+    };
+    for _ in 0..length {
+        let (new_i, element) = atype.parse_value(i)?;
+        i = new_i;
+        values.push(element);
+    }
+    Ok((i, values))
+}
+
+impl ArgType {
+    /// Returns the value a property of this type has before it is set: zero, an empty string,
+    /// or a default-filled array or dictionary. Nullable dictionaries are filled in rather than
+    /// `None`, so that nested updates can be applied to them.
+    pub fn default_value(&self) -> ArgValue<'_> {
+        match self {
+            Self::Primitive(PrimitiveType::Uint8) => ArgValue::Uint8(0),
+            Self::Primitive(PrimitiveType::Uint16) => ArgValue::Uint16(0),
+            Self::Primitive(PrimitiveType::Uint32) => ArgValue::Uint32(0),
+            Self::Primitive(PrimitiveType::Uint64) => ArgValue::Uint64(0),
+            Self::Primitive(PrimitiveType::Int8) => ArgValue::Int8(0),
+            Self::Primitive(PrimitiveType::Int16) => ArgValue::Int16(0),
+            Self::Primitive(PrimitiveType::Int32) => ArgValue::Int32(0),
+            Self::Primitive(PrimitiveType::Int64) => ArgValue::Int64(0),
+            Self::Primitive(PrimitiveType::Float32) => ArgValue::Float32(0.0),
+            Self::Primitive(PrimitiveType::Float64) => ArgValue::Float64(0.0),
+            Self::Primitive(PrimitiveType::Vector2) => ArgValue::Vector2((0.0, 0.0)),
+            Self::Primitive(PrimitiveType::Vector3) => ArgValue::Vector3((0.0, 0.0, 0.0)),
+            Self::Primitive(PrimitiveType::String) => ArgValue::String(vec![]),
+            Self::Primitive(PrimitiveType::UnicodeString) => ArgValue::UnicodeString(vec![]),
+            Self::Primitive(PrimitiveType::Blob) => ArgValue::Blob(vec![]),
+            Self::Array((size, t)) => {
+                ArgValue::Array((0..size.unwrap_or(0)).map(|_| t.default_value()).collect())
+            }
+            Self::FixedDict((allow_none, props)) => {
+                let d = props
+                    .iter()
+                    .map(|prop| (prop.name.as_str(), prop.prop_type.default_value()))
+                    .collect();
+                if *allow_none {
+                    ArgValue::NullableFixedDict(Some(d))
+                } else {
+                    ArgValue::FixedDict(d)
+                }
+            }
+            Self::Tuple((t, size)) => {
+                ArgValue::Tuple((0..size.unwrap_or(0)).map(|_| t.default_value()).collect())
+            }
+        }
+    }
+
+    /// Encodes a value into the wire format that `parse_value` reads.
+    pub fn encode_value(&self, value: &ArgValue) -> Result<Vec<u8>, ErrorKind> {
+        let mut out = vec![];
+        self.encode_value_into(value, &mut out)?;
+        Ok(out)
+    }
+
+    fn encode_value_into(&self, value: &ArgValue, out: &mut Vec<u8>) -> Result<(), ErrorKind> {
+        let mismatch = |error: String| ErrorKind::UnableToEncodeRpcValue {
+            argtype: format!("{:?}", self),
+            error,
+        };
+        match (self, value) {
+            (Self::Primitive(p), value) => p.encode_value(value, out),
+            (Self::Array((count, atype)), ArgValue::Array(values))
+            | (Self::Tuple((atype, count)), ArgValue::Tuple(values)) => {
+                match count {
+                    Some(count) if *count != values.len() => {
+                        return Err(mismatch(format!(
+                            "Expected {} elements, got {}",
+                            count,
+                            values.len()
+                        )));
+                    }
+                    Some(_) => {}
+                    None if values.len() > 0xff => {
+                        return Err(mismatch(format!("{} elements is too many", values.len())));
+                    }
+                    None => out.push(values.len() as u8),
+                }
+                for value in values.iter() {
+                    atype.encode_value_into(value, out)?;
+                }
+                Ok(())
+            }
+            (Self::FixedDict((true, _)), ArgValue::NullableFixedDict(None)) => {
+                out.push(0);
+                Ok(())
             }
+            (Self::FixedDict((allow_none, props)), ArgValue::FixedDict(dict))
+            | (Self::FixedDict((allow_none, props)), ArgValue::NullableFixedDict(Some(dict))) => {
+                if *allow_none {
+                    out.push(1);
+                }
+                for property in props.iter() {
+                    let value = dict
+                        .get(property.name.as_str())
+                        .ok_or_else(|| mismatch(format!("Missing key {}", property.name)))?;
+                    property.prop_type.encode_value_into(value, out)?;
+                }
+                Ok(())
+            }
+            (_, value) => Err(mismatch(format!("Mismatched value {:?}", value))),
         }
     }
 }
@@ -432,12 +652,7 @@ pub fn parse_type(arg: &roxmltree::Node, aliases: &HashMap<String, ArgType>) ->
     } else if t == "TUPLE" {
         let subtype = parse_type(&child_by_name(arg, "of").unwrap(), aliases);
         let count = child_by_name(arg, "size")
-            .unwrap()
-            .text()
-            .unwrap()
-            .trim()
-            .parse::<usize>()
-            .unwrap();
+            .map(|count| count.text().unwrap().trim().parse::<usize>().unwrap());
         ArgType::Tuple((Box::new(subtype), count))
     } else if aliases.contains_key(t) {
         aliases.get(t).unwrap().clone()
@@ -665,6 +880,80 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_encode_roundtrip() {
+        let spec = "<Type>ARRAY<of>FIXED_DICT<Properties>
+                <name><Type>STRING</Type></name>
+                <id><Type>INT32</Type></id>
+            </Properties><AllowNone>true</AllowNone></of></Type>";
+        let doc = roxmltree::Document::parse(spec).unwrap();
+        let t = parse_type(&doc.root_element(), &HashMap::new());
+
+        let long_name = vec![b'x'; 300];
+        let mut dict = HashMap::new();
+        dict.insert("name", ArgValue::String(long_name));
+        dict.insert("id", ArgValue::Int32(-3));
+        let value = ArgValue::Array(vec![
+            ArgValue::NullableFixedDict(Some(dict)),
+            ArgValue::NullableFixedDict(None),
+        ]);
+
+        let data = t.encode_value(&value).unwrap();
+        assert_eq!(&data[..5], &[2, 1, 0xff, 44, 1]);
+        let (i, parsed) = t.parse_value(&data).unwrap();
+        assert_eq!(i.len(), 0);
+        assert_eq!(parsed, value);
+    }
+
+    #[test]
+    fn test_tuple() {
+        let spec = "<Type>TUPLE<of>INT16</of><size>2</size></Type>";
+        let doc = roxmltree::Document::parse(spec).unwrap();
+        let t = parse_type(&doc.root_element(), &HashMap::new());
+        assert_eq!(
+            t,
+            ArgType::Tuple((Box::new(ArgType::Primitive(PrimitiveType::Int16)), Some(2)))
+        );
+        assert_eq!(t.sort_size(), 4);
+
+        let data = [1, 0, 0xfd, 0xff];
+        let (i, value) = t.parse_value(&data).unwrap();
+        assert_eq!(i.len(), 0);
+        assert_eq!(
+            value,
+            ArgValue::Tuple(vec![ArgValue::Int16(1), ArgValue::Int16(-3)])
+        );
+        assert_eq!(serde_json::to_string(&value).unwrap(), "[1,-3]");
+        assert_eq!(t.encode_value(&value).unwrap(), data);
+
+        // Without a size, the elements are preceded by their count
+        let spec = "<Type>TUPLE<of>STRING</of></Type>";
+        let doc = roxmltree::Document::parse(spec).unwrap();
+        let t = parse_type(&doc.root_element(), &HashMap::new());
+        assert_eq!(t.sort_size(), INFINITY);
+        let data = [2, 1, b'a', 0];
+        let (i, value) = t.parse_value(&data).unwrap();
+        assert_eq!(i.len(), 0);
+        assert_eq!(
+            value,
+            ArgValue::Tuple(vec![
+                ArgValue::String(b"a".to_vec()),
+                ArgValue::String(vec![])
+            ])
+        );
+        assert_eq!(t.encode_value(&value).unwrap(), data);
+    }
+
+    #[test]
+    fn test_encode_mismatch() {
+        let t = ArgType::Primitive(PrimitiveType::Uint8);
+        assert!(t.encode_value(&ArgValue::Int8(1)).is_err());
+        let t = ArgType::Array((Some(2), Box::new(t)));
+        assert!(t
+            .encode_value(&ArgValue::Array(vec![ArgValue::Uint8(1)]))
+            .is_err());
+    }
+
     #[test]
     fn test_unpacker_macro_single() {
         let args = vec![ArgValue::Uint8(5)];
@@ -0,0 +1,598 @@
+//! User data objects (UDOs) are objects placed on a map by the level designers, such as
+//! control points, spawn points and map borders. Their definitions live in
+//! `scripts/user_data_object_defs`, alongside the definitions of the per-space data in
+//! `scripts/space_defs`.
+//!
+//! The placed objects themselves are stored in the map's chunk files, which aren't part of
+//! the scripts shipped in `versions/`, so only the definitions are exposed for now.
+
+use serde_derive::Serialize;
+use std::collections::HashMap;
+
+use crate::error::ErrorKind;
+use crate::packet2::Vec3;
+use crate::rpc::typedefs::{parse_aliases, parse_type, ArgType, OwnedArgValue, PrimitiveType};
+
+#[derive(Clone, Debug)]
+pub struct UdoProperty {
+    pub name: String,
+    pub prop_type: ArgType,
+    /// The value used when a placed object doesn't set this property
+    pub default: Option<OwnedArgValue>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UdoSpec {
+    pub name: String,
+    /// Where the object lives (e.g. "BASE" or "CLIENT"). Space data has no domain.
+    pub domain: Option<String>,
+    pub properties: Vec<UdoProperty>,
+}
+
+impl UdoSpec {
+    pub fn property(&self, name: &str) -> Option<&UdoProperty> {
+        self.properties.iter().find(|prop| prop.name == name)
+    }
+}
+
+fn child_by_name<'a, 'b>(
+    node: &roxmltree::Node<'a, 'b>,
+    name: &str,
+) -> Option<roxmltree::Node<'a, 'b>> {
+    node.children()
+        .find(|child| child.tag_name().name() == name)
+}
+
+fn invalid(reason: String) -> ErrorKind {
+    ErrorKind::InvalidUserDataObject { reason }
+}
+
+/// Parses the textual form of a value, as used both for property defaults and for the
+/// properties of placed objects. Returns None if the text can't be read as the given type.
+fn parse_text_value(t: &ArgType, node: &roxmltree::Node) -> Option<OwnedArgValue> {
+    let text = node.text().unwrap_or("").trim();
+    // BOOL is an alias for UINT8, but is written as True/False
+    let number = match text.to_lowercase().as_str() {
+        "true" => "1",
+        "false" => "0",
+        _ => text,
+    };
+    let floats = || {
+        text.split_whitespace()
+            .map(|f| f.parse::<f32>().ok())
+            .collect::<Option<Vec<_>>>()
+    };
+    match t {
+        ArgType::Primitive(p) => Some(match p {
+            PrimitiveType::Uint8 => OwnedArgValue::Uint8(number.parse().ok()?),
+            PrimitiveType::Uint16 => OwnedArgValue::Uint16(number.parse().ok()?),
+            PrimitiveType::Uint32 => OwnedArgValue::Uint32(number.parse().ok()?),
+            PrimitiveType::Uint64 => OwnedArgValue::Uint64(number.parse().ok()?),
+            PrimitiveType::Int8 => OwnedArgValue::Int8(number.parse().ok()?),
+            PrimitiveType::Int16 => OwnedArgValue::Int16(number.parse().ok()?),
+            PrimitiveType::Int32 => OwnedArgValue::Int32(number.parse().ok()?),
+            PrimitiveType::Int64 => OwnedArgValue::Int64(number.parse().ok()?),
+            PrimitiveType::Float32 => OwnedArgValue::Float32(text.parse().ok()?),
+            PrimitiveType::Float64 => OwnedArgValue::Float64(text.parse().ok()?),
+            PrimitiveType::Vector2 => match floats()?.as_slice() {
+                [x, y] => OwnedArgValue::Vector2((*x, *y)),
+                _ => return None,
+            },
+            PrimitiveType::Vector3 => match floats()?.as_slice() {
+                [x, y, z] => OwnedArgValue::Vector3((*x, *y, *z)),
+                _ => return None,
+            },
+            PrimitiveType::String => OwnedArgValue::String(text.as_bytes().to_vec()),
+            PrimitiveType::UnicodeString => OwnedArgValue::UnicodeString(text.as_bytes().to_vec()),
+            PrimitiveType::Blob => OwnedArgValue::Blob(text.as_bytes().to_vec()),
+        }),
+        ArgType::Array((_, element_type)) | ArgType::Tuple((element_type, _)) => {
+            let items = node
+                .children()
+                .filter(|child| child.tag_name().name() == "item")
+                .map(|item| parse_text_value(element_type, &item))
+                .collect::<Option<Vec<_>>>()?;
+            Some(match t {
+                ArgType::Tuple(_) => OwnedArgValue::Tuple(items),
+                _ => OwnedArgValue::Array(items),
+            })
+        }
+        ArgType::FixedDict((_, props)) => {
+            let mut dict = HashMap::new();
+            for prop in props.iter() {
+                let value = parse_text_value(&prop.prop_type, &child_by_name(node, &prop.name)?)?;
+                dict.insert(prop.name.clone(), value);
+            }
+            Some(OwnedArgValue::FixedDict(dict))
+        }
+    }
+}
+
+fn parse_udo_def(
+    name: &str,
+    def: &[u8],
+    aliases: &HashMap<String, ArgType>,
+) -> Result<UdoSpec, ErrorKind> {
+    let def = std::str::from_utf8(def).map_err(|e| invalid(format!("{}: {}", name, e)))?;
+    let doc = roxmltree::Document::parse(def).map_err(|e| invalid(format!("{}: {}", name, e)))?;
+    let root = child_by_name(&doc.root(), "root")
+        .ok_or_else(|| invalid(format!("{} has no root element", name)))?;
+
+    let domain = match child_by_name(&root, "Domain") {
+        Some(d) => Some(
+            d.text()
+                .ok_or_else(|| invalid(format!("{} has an empty Domain", name)))?
+                .trim()
+                .to_string(),
+        ),
+        None => None,
+    };
+    let mut properties = vec![];
+    if let Some(plist) = child_by_name(&root, "Properties") {
+        for property in plist.children().filter(|child| child.is_element()) {
+            let prop_type = child_by_name(&property, "Type").ok_or_else(|| {
+                invalid(format!(
+                    "{}.{} has no Type",
+                    name,
+                    property.tag_name().name()
+                ))
+            })?;
+            let prop_type = parse_type(&prop_type, aliases);
+            let default = child_by_name(&property, "Default")
+                .and_then(|default| parse_text_value(&prop_type, &default));
+            properties.push(UdoProperty {
+                name: property.tag_name().name().to_string(),
+                prop_type,
+                default,
+            });
+        }
+    }
+    Ok(UdoSpec {
+        name: name.to_string(),
+        domain,
+        properties,
+    })
+}
+
+/// Parses every definition named in `list` (e.g. "scripts/user_data_objects.xml") from the
+/// directory `defs`. A missing list is treated as empty.
+fn parse_def_list(
+    gamedata: &crate::version::Datafiles,
+    list: &str,
+    defs: &str,
+) -> Result<Vec<UdoSpec>, ErrorKind> {
+    let list_path = list;
+    let list = match gamedata.get(list_path) {
+        Ok(x) => x,
+        Err(ErrorKind::DatafileNotFound { .. }) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut aliases = parse_aliases(&gamedata.get("scripts/entity_defs/alias.xml")?);
+    // References to other objects are stored as the GUID of the referenced object
+    aliases.insert(
+        "UDO_REF".to_string(),
+        ArgType::Primitive(PrimitiveType::String),
+    );
+
+    let doc = roxmltree::Document::parse(std::str::from_utf8(&list)?)
+        .map_err(|e| invalid(format!("{}: {}", list_path, e)))?;
+    let root = child_by_name(&doc.root(), "root")
+        .ok_or_else(|| invalid(format!("{} has no root element", list_path)))?;
+    let mut specs = vec![];
+    for child in root.children().filter(|child| child.is_element()) {
+        let name = child.tag_name().name();
+        let def = gamedata.get(&format!("{}/{}.def", defs, name))?;
+        specs.push(parse_udo_def(name, &def, &aliases)?);
+    }
+    Ok(specs)
+}
+
+/// Parses the user data object definitions listed in user_data_objects.xml
+pub fn parse_udo_defs(gamedata: &crate::version::Datafiles) -> Result<Vec<UdoSpec>, ErrorKind> {
+    parse_def_list(
+        gamedata,
+        "scripts/user_data_objects.xml",
+        "scripts/user_data_object_defs",
+    )
+}
+
+/// Parses the space data definitions listed in spaces.xml
+pub fn parse_space_defs(gamedata: &crate::version::Datafiles) -> Result<Vec<UdoSpec>, ErrorKind> {
+    parse_def_list(gamedata, "scripts/spaces.xml", "scripts/space_defs")
+}
+
+/// Decoding of the objects placed in a map's chunk files.
+///
+/// No real chunk has been checked against this yet, so it is kept out of the public API.
+/// It assumes that each object is a `UserDataObject` element with `guid`, `type`,
+/// `transform/row0`..`row3` (or just `position`) and `properties` children, and that a
+/// `MapBorder` covers the unit square around its origin before its transform is applied.
+#[allow(dead_code)]
+mod chunk {
+    use super::*;
+
+    /// A user data object placed on a map
+    #[derive(Clone, Debug, Serialize)]
+    pub(crate) struct UserDataObject {
+        pub udo_type: String,
+        pub guid: String,
+        /// The rows of the object's transform. The first three rows are the (scaled) axes of
+        /// the object and the last row is its position.
+        pub transform: [[f32; 3]; 4],
+        /// The object's properties, with defaults filled in for any the object doesn't set
+        pub properties: HashMap<String, OwnedArgValue>,
+    }
+
+    impl UserDataObject {
+        pub(crate) fn position(&self) -> Vec3 {
+            let [x, y, z] = self.transform[3];
+            Vec3 { x, y, z }
+        }
+
+        /// Applies the object's transform to a point in the object's own coordinates
+        pub(crate) fn transform_point(&self, point: [f32; 3]) -> [f32; 3] {
+            let mut out = self.transform[3];
+            for (axis, scale) in self.transform[..3].iter().zip(point.iter()) {
+                for (out, a) in out.iter_mut().zip(axis.iter()) {
+                    *out += a * scale;
+                }
+            }
+            out
+        }
+
+        fn float(&self, name: &str) -> Option<f32> {
+            match self.properties.get(name)? {
+                OwnedArgValue::Float32(f) => Some(*f),
+                OwnedArgValue::Float64(f) => Some(*f as f32),
+                _ => None,
+            }
+        }
+
+        fn int(&self, name: &str) -> Option<i64> {
+            Some(match self.properties.get(name)? {
+                OwnedArgValue::Uint8(i) => *i as i64,
+                OwnedArgValue::Uint16(i) => *i as i64,
+                OwnedArgValue::Uint32(i) => *i as i64,
+                OwnedArgValue::Int8(i) => *i as i64,
+                OwnedArgValue::Int16(i) => *i as i64,
+                OwnedArgValue::Int32(i) => *i as i64,
+                OwnedArgValue::Int64(i) => *i,
+                _ => return None,
+            })
+        }
+
+        fn string(&self, name: &str) -> String {
+            match self.properties.get(name) {
+                Some(OwnedArgValue::String(s)) | Some(OwnedArgValue::UnicodeString(s)) => {
+                    String::from_utf8_lossy(s).to_string()
+                }
+                _ => String::new(),
+            }
+        }
+    }
+
+    fn parse_floats(node: &roxmltree::Node) -> Result<[f32; 3], ErrorKind> {
+        let text = node.text().unwrap_or("");
+        let floats = text
+            .split_whitespace()
+            .map(|f| f.parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid(format!("Bad vector {:?}", text)))?;
+        match floats.as_slice() {
+            [x, y, z] => Ok([*x, *y, *z]),
+            _ => Err(invalid(format!("Bad vector {:?}", text))),
+        }
+    }
+
+    fn parse_transform(object: &roxmltree::Node) -> Result<[[f32; 3]; 4], ErrorKind> {
+        let mut transform = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [0.0; 3]];
+        if let Some(node) = child_by_name(object, "transform") {
+            for (i, row) in transform.iter_mut().enumerate() {
+                let name = format!("row{}", i);
+                let node = child_by_name(&node, &name)
+                    .ok_or_else(|| invalid(format!("Transform is missing {}", name)))?;
+                *row = parse_floats(&node)?;
+            }
+        } else if let Some(node) = child_by_name(object, "position") {
+            transform[3] = parse_floats(&node)?;
+        }
+        Ok(transform)
+    }
+
+    /// Decodes the user data objects in the XML of a map chunk, using the definitions from
+    /// [parse_udo_defs] to type their properties.
+    pub(crate) fn parse_user_data_objects(
+        specs: &[UdoSpec],
+        chunk: &[u8],
+    ) -> Result<Vec<UserDataObject>, ErrorKind> {
+        let doc = roxmltree::Document::parse(std::str::from_utf8(chunk)?)
+            .map_err(|e| invalid(e.to_string()))?;
+        let mut objects = vec![];
+        for object in doc
+            .descendants()
+            .filter(|node| node.tag_name().name() == "UserDataObject")
+        {
+            let text = |name: &str| {
+                child_by_name(&object, name)
+                    .and_then(|node| node.text())
+                    .map(|text| text.trim().to_string())
+                    .ok_or_else(|| invalid(format!("User data object is missing its {}", name)))
+            };
+            let udo_type = text("type")?;
+            let spec = specs
+                .iter()
+                .find(|spec| spec.name == udo_type)
+                .ok_or_else(|| invalid(format!("Unknown user data object type {}", udo_type)))?;
+
+            let mut properties = HashMap::new();
+            let values = child_by_name(&object, "properties");
+            for property in spec.properties.iter() {
+                let value = match values.and_then(|values| child_by_name(&values, &property.name)) {
+                    Some(node) => {
+                        Some(parse_text_value(&property.prop_type, &node).ok_or_else(|| {
+                            invalid(format!(
+                                "Bad value for {}.{}: {:?}",
+                                udo_type,
+                                property.name,
+                                node.text()
+                            ))
+                        })?)
+                    }
+                    None => property.default.clone(),
+                };
+                if let Some(value) = value {
+                    properties.insert(property.name.clone(), value);
+                }
+            }
+
+            objects.push(UserDataObject {
+                guid: text("guid")?,
+                transform: parse_transform(&object)?,
+                udo_type,
+                properties,
+            });
+        }
+        Ok(objects)
+    }
+
+    /// Map geometry decoded from a user data object
+    #[derive(Clone, Debug, Serialize)]
+    pub(crate) enum MapFeature {
+        /// The capture circle of a control point
+        CapCircle {
+            name: String,
+            position: Vec3,
+            radius: f32,
+            inner_radius: f32,
+            /// The team owning the point at the start of the battle, or -1 if it's neutral
+            team: i64,
+        },
+        SpawnPoint {
+            tag: String,
+            position: Vec3,
+            team: i64,
+        },
+        /// A map border, as a polygon in the XZ plane. The border is the unit square around
+        /// the object's origin, scaled and rotated by the object's transform.
+        Border {
+            name: String,
+            polygon: Vec<(f32, f32)>,
+        },
+    }
+
+    impl MapFeature {
+        /// Returns the feature described by the object, if it is one we know how to draw
+        pub(crate) fn from_udo(object: &UserDataObject) -> Option<MapFeature> {
+            match object.udo_type.as_str() {
+                "ControlPoint" => Some(MapFeature::CapCircle {
+                    name: object.string("name"),
+                    position: object.position(),
+                    radius: object.float("radius")?,
+                    inner_radius: object.float("innerRadius").unwrap_or(0.0),
+                    team: object.int("team").unwrap_or(-1),
+                }),
+                "SpawnPoint" => Some(MapFeature::SpawnPoint {
+                    tag: object.string("tag"),
+                    position: object.position(),
+                    team: object.int("team")?,
+                }),
+                "MapBorder" => Some(MapFeature::Border {
+                    name: object.string("name"),
+                    polygon: [[-0.5, -0.5], [0.5, -0.5], [0.5, 0.5], [-0.5, 0.5]]
+                        .iter()
+                        .map(|[x, z]| {
+                            let [x, _, z] = object.transform_point([*x, 0.0, *z]);
+                            (x, z)
+                        })
+                        .collect(),
+                }),
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::chunk::*;
+    use super::*;
+    use crate::version::{Datafiles, Version};
+    use std::path::PathBuf;
+
+    fn defs() -> (Vec<UdoSpec>, Vec<UdoSpec>) {
+        let datafiles = Datafiles::new(
+            PathBuf::from("../versions"),
+            "0,10,9,0".parse::<Version>().unwrap(),
+        )
+        .unwrap();
+        (
+            parse_udo_defs(&datafiles).unwrap(),
+            parse_space_defs(&datafiles).unwrap(),
+        )
+    }
+
+    #[test]
+    fn udo_defs() {
+        let (udos, spaces) = defs();
+        assert_eq!(udos.len(), 17);
+
+        let control_point = udos.iter().find(|u| u.name == "ControlPoint").unwrap();
+        assert_eq!(control_point.domain.as_deref(), Some("BASE"));
+        let team = control_point.property("team").unwrap();
+        assert_eq!(team.default, Some(OwnedArgValue::Int8(-1)));
+        let radius = control_point.property("radius").unwrap();
+        assert_eq!(radius.default, Some(OwnedArgValue::Float32(10.0)));
+        let baked = control_point.property("baked").unwrap();
+        assert_eq!(baked.default, Some(OwnedArgValue::Uint8(0)));
+
+        let spawn_point = udos.iter().find(|u| u.name == "SpawnPoint").unwrap();
+        assert_eq!(
+            spawn_point.property("shipLevels").unwrap().default,
+            Some(OwnedArgValue::Array(
+                (1..=10).map(OwnedArgValue::Uint8).collect()
+            ))
+        );
+
+        let waypoint = udos.iter().find(|u| u.name == "WayPoint").unwrap();
+        match &waypoint.property("next").unwrap().prop_type {
+            ArgType::Array((None, t)) => {
+                assert!(matches!(**t, ArgType::Primitive(PrimitiveType::String)))
+            }
+            t => panic!("Unexpected type {:?}", t),
+        }
+
+        assert_eq!(spaces.len(), 1);
+        assert_eq!(spaces[0].name, "GeneralSpaceData");
+        assert_eq!(spaces[0].domain, None);
+        let names: Vec<_> = spaces[0].properties.iter().map(|p| &p.name).collect();
+        assert_eq!(names, vec!["weather", "itemsVisibilityMask"]);
+    }
+
+    #[test]
+    fn map_features() {
+        let (udos, _) = defs();
+        // Hand-written in the layout which the chunk decoder assumes
+        let chunk = r#"<root>
+            <UserDataObject>
+                <guid> 1.2.3.4 </guid>
+                <type> ControlPoint </type>
+                <transform>
+                    <row0> 1 0 0 </row0>
+                    <row1> 0 1 0 </row1>
+                    <row2> 0 0 1 </row2>
+                    <row3> 100 0 -50 </row3>
+                </transform>
+                <properties>
+                    <name> A </name>
+                    <radius> 150.5 </radius>
+                    <team> 0 </team>
+                    <baked> True </baked>
+                </properties>
+            </UserDataObject>
+            <UserDataObject>
+                <guid> 5.6.7.8 </guid>
+                <type> SpawnPoint </type>
+                <position> -300 0 200 </position>
+                <properties><team> 1 </team><tag> north </tag></properties>
+            </UserDataObject>
+            <UserDataObject>
+                <guid> 9.10.11.12 </guid>
+                <type> MapBorder </type>
+                <transform>
+                    <row0> 1200 0 0 </row0>
+                    <row1> 0 1 0 </row1>
+                    <row2> 0 0 800 </row2>
+                    <row3> 0 0 100 </row3>
+                </transform>
+                <properties><name> border </name></properties>
+            </UserDataObject>
+        </root>"#;
+        let objects = parse_user_data_objects(&udos, chunk.as_bytes()).unwrap();
+        assert_eq!(objects.len(), 3);
+        assert_eq!(objects[0].guid, "1.2.3.4");
+        assert_eq!(
+            objects[0].properties.get("baked"),
+            Some(&OwnedArgValue::Uint8(1))
+        );
+
+        let features: Vec<_> = objects.iter().filter_map(MapFeature::from_udo).collect();
+        match &features[0] {
+            MapFeature::CapCircle {
+                name,
+                position,
+                radius,
+                inner_radius,
+                team,
+            } => {
+                assert_eq!(name, "A");
+                assert_eq!((position.x, position.z), (100.0, -50.0));
+                assert_eq!(*radius, 150.5);
+                // Not set by the object, so it comes from the definition's default
+                assert_eq!(*inner_radius, 0.0);
+                assert_eq!(*team, 0);
+            }
+            f => panic!("Unexpected feature {:?}", f),
+        }
+        match &features[1] {
+            MapFeature::SpawnPoint {
+                tag,
+                position,
+                team,
+            } => {
+                assert_eq!(tag, "north");
+                assert_eq!((position.x, position.z), (-300.0, 200.0));
+                assert_eq!(*team, 1);
+            }
+            f => panic!("Unexpected feature {:?}", f),
+        }
+        match &features[2] {
+            MapFeature::Border { name, polygon } => {
+                assert_eq!(name, "border");
+                assert_eq!(
+                    polygon,
+                    &vec![
+                        (-600.0, -300.0),
+                        (600.0, -300.0),
+                        (600.0, 500.0),
+                        (-600.0, 500.0)
+                    ]
+                );
+            }
+            f => panic!("Unexpected feature {:?}", f),
+        }
+    }
+
+    #[test]
+    fn malformed_udo_def() {
+        let aliases = HashMap::new();
+        for def in [
+            "<root><Domain/></root>",
+            "<root><Properties><radius><Default>1</Default></radius></Properties></root>",
+            "<root><Domain>BASE</Properties></root>",
+            "<notroot/>",
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(
+                    parse_udo_def("Thing", def.as_bytes(), &aliases),
+                    Err(ErrorKind::InvalidUserDataObject { .. })
+                ),
+                "{}",
+                def
+            );
+        }
+        assert!(parse_udo_def("Thing", b"<root>\xff</root>", &aliases).is_err());
+    }
+
+    #[test]
+    fn unknown_udo_type() {
+        let (udos, _) = defs();
+        let chunk =
+            "<root><UserDataObject><guid>1</guid><type>Kraken</type></UserDataObject></root>";
+        assert!(matches!(
+            parse_user_data_objects(&udos, chunk.as_bytes()),
+            Err(ErrorKind::InvalidUserDataObject { .. })
+        ));
+    }
+}
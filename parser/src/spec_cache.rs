@@ -0,0 +1,249 @@
+//! Parsing the entity definitions for a version means reading hundreds of XML files, which
+//! dominates the time taken to process a short replay. [SpecCache] parses them once per
+//! version and hands out shared copies, optionally persisting them to disk between runs.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::datafile_source::{stable_hash, DatafileSource};
+use crate::error::ErrorKind;
+use crate::rpc::entitydefs::EntitySpec;
+use crate::version::{Datafiles, Version, VersionPolicy};
+
+/// Bumped whenever parsing changes the resulting specs, so that stale disk caches written
+/// by older builds are ignored
+const DISK_CACHE_FORMAT: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct DiskCache {
+    format: u32,
+    specs: Vec<EntitySpec>,
+}
+
+/// Memoizes the output of [crate::parse_scripts] for each version. Versions which share a
+/// data directory (i.e. which only differ by build number) share their specs.
+pub struct SpecCache {
+    /// Where to read the data files from
+    sources: Vec<Arc<dyn DatafileSource>>,
+    /// Where to persist parsed specs, if anywhere
+    disk_cache: Option<PathBuf>,
+    /// Identifies `sources` in the names of the disk cache files, since the same version can
+    /// be parsed differently from different data files. Identifying them can mean reading
+    /// every script, so this is only set once the disk cache is enabled.
+    sources_id: u64,
+    policy: VersionPolicy,
+    specs: Mutex<HashMap<String, Arc<Vec<EntitySpec>>>>,
+}
+
+impl SpecCache {
+    /// Creates an in-memory cache of the specs for the versions in `base`, laid out as for
+    /// [Datafiles::new].
+    pub fn new(base: PathBuf) -> SpecCache {
+        Self::from_sources(Datafiles::default_sources(base))
+    }
+
+    /// Creates an in-memory cache of the specs for the versions in the given sources, as
+    /// for [Datafiles::from_sources].
+    pub fn from_sources(sources: Vec<Arc<dyn DatafileSource>>) -> SpecCache {
+        SpecCache {
+            sources,
+            disk_cache: None,
+            sources_id: 0,
+            policy: VersionPolicy::Exact,
+            specs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Also stores parsed specs as JSON files in the given directory, and loads them from
+    /// there before parsing the data files.
+    pub fn with_disk_cache(mut self, dir: PathBuf) -> SpecCache {
+        let ids: Vec<_> = self
+            .sources
+            .iter()
+            .map(|source| source.source_id())
+            .collect();
+        self.sources_id = stable_hash(ids.join("\n").as_bytes());
+        self.disk_cache = Some(dir);
+        self
+    }
+
+    /// Sets how to pick the data files for versions we don't have data files for
+    pub fn with_policy(mut self, policy: VersionPolicy) -> SpecCache {
+        self.policy = policy;
+        self
+    }
+
+    /// Returns the version whose data files will be used to parse specs for the given
+    /// version, as chosen by the cache's [VersionPolicy]
+    pub fn scripts_version(&self, version: Version) -> Result<Version, ErrorKind> {
+        Ok(self.datafiles(version)?.version())
+    }
+
+    /// Returns the specs for the given version, parsing them if they aren't cached yet
+    pub fn get(&self, version: Version) -> Result<Arc<Vec<EntitySpec>>, ErrorKind> {
+        let key = version.to_path();
+        if let Some(specs) = self.specs.lock().unwrap().get(&key) {
+            return Ok(specs.clone());
+        }
+
+        let datafiles = self.datafiles(version)?;
+        let resolved_key = datafiles.version().to_path();
+        let cached = self.specs.lock().unwrap().get(&resolved_key).cloned();
+        let specs = match cached {
+            Some(specs) => specs,
+            // Parse without holding the lock, so that other versions can still be looked
+            // up. If two threads race to parse the same version, the first one to finish wins.
+            None => {
+                let specs = Arc::new(match self.load(&resolved_key) {
+                    Some(specs) => specs,
+                    None => self.store(&resolved_key, crate::parse_scripts(&datafiles)?)?,
+                });
+                self.specs
+                    .lock()
+                    .unwrap()
+                    .entry(resolved_key)
+                    .or_insert(specs)
+                    .clone()
+            }
+        };
+        // Versions which fall back to other versions' specs share them
+        Ok(self
+            .specs
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert(specs)
+            .clone())
+    }
+
+    fn datafiles(&self, version: Version) -> Result<Datafiles, ErrorKind> {
+        Datafiles::from_sources(self.sources.clone(), version, self.policy)
+    }
+
+    fn disk_cache_path(&self, key: &str) -> Option<PathBuf> {
+        self.disk_cache
+            .as_ref()
+            .map(|dir| dir.join(format!("{}-{:016x}.json", key, self.sources_id)))
+    }
+
+    /// Loads specs from the disk cache. Missing, unreadable or outdated files are ignored, and
+    /// will be overwritten once the specs have been parsed again.
+    fn load(&self, key: &str) -> Option<Vec<EntitySpec>> {
+        let data = std::fs::read(self.disk_cache_path(key)?).ok()?;
+        let cache: DiskCache = serde_json::from_slice(&data).ok()?;
+        if cache.format != DISK_CACHE_FORMAT {
+            return None;
+        }
+        Some(cache.specs)
+    }
+
+    fn store(&self, key: &str, specs: Vec<EntitySpec>) -> Result<Vec<EntitySpec>, ErrorKind> {
+        let path = match self.disk_cache_path(key) {
+            Some(path) => path,
+            None => return Ok(specs),
+        };
+        let cache = DiskCache {
+            format: DISK_CACHE_FORMAT,
+            specs,
+        };
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, serde_json::to_vec(&cache)?)?;
+        Ok(cache.specs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spec_names(specs: &[EntitySpec]) -> Vec<(String, usize, usize)> {
+        specs
+            .iter()
+            .map(|spec| {
+                (
+                    spec.name.clone(),
+                    spec.properties.len(),
+                    spec.client_methods.len(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn memoizes_per_version() {
+        let cache = SpecCache::new(PathBuf::from("../versions"));
+        let a = cache.get("0,10,9,0".parse::<Version>().unwrap()).unwrap();
+        // A different build of the same version shares the specs
+        let b = cache
+            .get("0,10,9,1234".parse::<Version>().unwrap())
+            .unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        let c = cache.get("0,10,10,0".parse::<Version>().unwrap()).unwrap();
+        assert!(!Arc::ptr_eq(&a, &c));
+    }
+
+    #[test]
+    fn fallback_shares_specs() {
+        let cache =
+            SpecCache::new(PathBuf::from("../versions")).with_policy(VersionPolicy::NearestOlder);
+        let hotfix = "0,11,2,0".parse::<Version>().unwrap();
+        assert_eq!(cache.scripts_version(hotfix).unwrap().to_path(), "0.11.1");
+        let a = cache.get(hotfix).unwrap();
+        let b = cache.get("0,11,1,0".parse::<Version>().unwrap()).unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+
+        let exact = SpecCache::new(PathBuf::from("../versions"));
+        assert!(matches!(
+            exact.get(hotfix),
+            Err(ErrorKind::UnsupportedReplayVersion(_))
+        ));
+    }
+
+    #[test]
+    fn disk_cache() {
+        let dir = std::env::temp_dir().join(format!("wows-replays-specs-{}", std::process::id()));
+        let version = "0,10,9,0".parse::<Version>().unwrap();
+        let parsed = SpecCache::new(PathBuf::from("../versions"))
+            .with_disk_cache(dir.clone())
+            .get(version)
+            .unwrap();
+        let cache = || SpecCache::new(PathBuf::from("../versions")).with_disk_cache(dir.clone());
+        let path = cache().disk_cache_path("0.10.9").unwrap();
+        assert!(path.exists());
+        let loaded = cache().get(version).unwrap();
+        assert_eq!(spec_names(&parsed), spec_names(&loaded));
+        assert_eq!(
+            serde_json::to_value(&*parsed).unwrap(),
+            serde_json::to_value(&*loaded).unwrap()
+        );
+
+        // The specs come from the disk cache rather than the data files
+        let write = |format: u32| {
+            std::fs::write(&path, format!(r#"{{"format": {}, "specs": []}}"#, format)).unwrap()
+        };
+        write(DISK_CACHE_FORMAT);
+        assert!(cache().get(version).unwrap().is_empty());
+
+        // Caches from other data file sources are ignored
+        let other_sources: Vec<Arc<dyn DatafileSource>> = vec![Arc::new(
+            crate::datafile_source::DirectorySource::new(PathBuf::from("../versions")),
+        )];
+        let other = SpecCache::from_sources(other_sources).with_disk_cache(dir.clone());
+        assert_ne!(other.disk_cache_path("0.10.9").unwrap(), path);
+        assert_eq!(
+            spec_names(&parsed),
+            spec_names(&other.get(version).unwrap())
+        );
+
+        // Caches from other builds of the parser are ignored
+        write(0);
+        assert_eq!(
+            spec_names(&parsed),
+            spec_names(&cache().get(version).unwrap())
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
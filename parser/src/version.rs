@@ -1,10 +1,13 @@
+use crate::datafile_source::{DatafileSource, DirectorySource, EmbeddedSource};
 use crate::error::ErrorKind;
-use rust_embed::RustEmbed;
 use serde::Serialize;
 use std::borrow::Cow;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-#[derive(Debug, Serialize, Clone, Copy)]
+/// A game version. Versions are ordered by each of their parts in turn, including the build
+/// number.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Version {
     pub major: u32,
     pub minor: u32,
@@ -13,15 +16,10 @@ pub struct Version {
 }
 
 impl Version {
-    pub fn from_client_exe(version: &str) -> Version {
-        let parts: Vec<_> = version.split(",").collect();
-        assert!(parts.len() == 4);
-        Version {
-            major: parts[0].parse::<u32>().unwrap(),
-            minor: parts[1].parse::<u32>().unwrap(),
-            patch: parts[2].parse::<u32>().unwrap(),
-            build: parts[3].parse::<u32>().unwrap(),
-        }
+    /// Parses the `clientVersionFromExe` string from the replay metadata, e.g.
+    /// "0,10,9,3747819". Fails with [ErrorKind::BadVersion] if it isn't a version.
+    pub fn from_client_exe(version: &str) -> Result<Version, ErrorKind> {
+        version.parse()
     }
 
     pub fn to_path(&self) -> String {
@@ -29,61 +27,183 @@ impl Version {
     }
 
     pub fn is_at_least(&self, other: &Version) -> bool {
-        if self.major > other.major {
-            true
-        } else if self.major < other.major {
-            false
-        } else if self.minor > other.minor {
-            true
-        } else if self.minor < other.minor {
-            false
-        } else if self.patch >= other.patch {
-            true
-        } else {
-            false
+        self >= other
+    }
+
+    /// Whether the versions are the same, treating a build number of zero as unknown. The
+    /// Version packet doesn't include the build number.
+    pub fn matches(&self, other: &Version) -> bool {
+        self.to_path() == other.to_path()
+            && (self.build == 0 || other.build == 0 || self.build == other.build)
+    }
+}
+
+impl std::str::FromStr for Version {
+    type Err = ErrorKind;
+
+    /// Parses any of the forms the game writes versions in: comma-separated as in the replay
+    /// metadata and the Version packet (e.g. "0,10,9,3747819" or "0,10,9,0"), or dotted
+    /// (e.g. "0.10.9.3747819"). The build number may be omitted, in which case it is zero.
+    fn from_str(version: &str) -> Result<Version, ErrorKind> {
+        let bad_version = || ErrorKind::BadVersion {
+            version: version.to_string(),
+        };
+        let parts = version
+            .trim()
+            .split(&[',', '.'][..])
+            .map(|part| part.trim().parse::<u32>().map_err(|_| bad_version()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match parts.as_slice() {
+            [major, minor, patch] => Ok(Version {
+                major: *major,
+                minor: *minor,
+                patch: *patch,
+                build: 0,
+            }),
+            [major, minor, patch, build] => Ok(Version {
+                major: *major,
+                minor: *minor,
+                patch: *patch,
+                build: *build,
+            }),
+            _ => Err(bad_version()),
         }
     }
 }
 
-#[derive(RustEmbed)]
-#[folder = "../versions/"]
-struct Embedded;
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            self.major, self.minor, self.patch, self.build
+        )
+    }
+}
+
+/// How to pick the data files for a version which we don't have data files for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VersionPolicy {
+    /// Only use the data files for the exact version
+    Exact,
+    /// Fall back to the newest version older than the requested one. Hotfix builds rarely
+    /// change the entity definitions, so this usually decodes correctly, but callers should
+    /// warn that it might not.
+    NearestOlder,
+}
 
 pub struct Datafiles {
-    base_path: PathBuf,
+    sources: Vec<Arc<dyn DatafileSource>>,
     version: Version,
+    requested_version: Version,
 }
 
 impl Datafiles {
+    /// Equivalent to [Datafiles::resolve] with [VersionPolicy::Exact]
     pub fn new(base: PathBuf, version: Version) -> Result<Datafiles, ErrorKind> {
-        let mut p = base.clone();
-        p.push(version.to_path());
-        // TODO: Also check the Embedded struct for if this path exists
-        /*if !p.exists() {
-            Err(ErrorKind::UnsupportedReplayVersion(version.to_path()))
-        } else {*/
-        Ok(Datafiles {
-            base_path: base,
-            version,
-        })
-        //}
+        Self::resolve(base, version, VersionPolicy::Exact)
+    }
+
+    /// The sources used by [Datafiles::new]: the version directories in `base`, followed
+    /// by the data files embedded in the library.
+    pub fn default_sources(base: PathBuf) -> Vec<Arc<dyn DatafileSource>> {
+        vec![
+            Arc::new(DirectorySource::new(base)),
+            Arc::new(EmbeddedSource),
+        ]
+    }
+
+    /// Finds the data files to use for the given version, either in `base` or embedded in
+    /// the library. Fails with [ErrorKind::UnsupportedReplayVersion] if the policy doesn't
+    /// allow any of the available versions.
+    pub fn resolve(
+        base: PathBuf,
+        version: Version,
+        policy: VersionPolicy,
+    ) -> Result<Datafiles, ErrorKind> {
+        Self::from_sources(Self::default_sources(base), version, policy)
+    }
+
+    /// Like [Datafiles::resolve], but reading from the given sources. Each file is read
+    /// from the first source which has it.
+    pub fn from_sources(
+        sources: Vec<Arc<dyn DatafileSource>>,
+        version: Version,
+        policy: VersionPolicy,
+    ) -> Result<Datafiles, ErrorKind> {
+        let mut has_version = false;
+        for source in sources.iter() {
+            has_version |= source.has_version(&version)?;
+        }
+        let resolved = if has_version {
+            Some(version)
+        } else {
+            match policy {
+                VersionPolicy::Exact => None,
+                VersionPolicy::NearestOlder => Self::available_versions_in(&sources)?
+                    .into_iter()
+                    .rev()
+                    .find(|available| version.is_at_least(available)),
+            }
+        };
+        match resolved {
+            Some(resolved) => Ok(Datafiles {
+                sources,
+                version: resolved,
+                requested_version: version,
+            }),
+            None => Err(ErrorKind::UnsupportedReplayVersion(version.to_path())),
+        }
+    }
+
+    /// Lists the versions which have data files, either in `base` or embedded in the
+    /// library, from oldest to newest. The build numbers of the versions are zero.
+    pub fn available_versions(base: &Path) -> Vec<Version> {
+        // Listing can only fail if `base` can't be read, in which case only the embedded
+        // versions are available
+        Self::available_versions_in(&Self::default_sources(base.to_path_buf()))
+            .or_else(|_| EmbeddedSource.versions())
+            .unwrap_or_default()
+    }
+
+    /// Lists the versions which have data files in any of the sources, from oldest to
+    /// newest. The build numbers of the versions are zero.
+    pub fn available_versions_in(
+        sources: &[Arc<dyn DatafileSource>],
+    ) -> Result<Vec<Version>, ErrorKind> {
+        let mut versions = std::collections::BTreeSet::new();
+        for source in sources.iter() {
+            versions.extend(source.versions()?);
+        }
+        Ok(versions.into_iter().collect())
+    }
+
+    /// The version of the data files being used
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// The version the data files were requested for. This differs from
+    /// [Datafiles::version] if the data files are from a fallback version.
+    pub fn requested_version(&self) -> Version {
+        self.requested_version
+    }
+
+    /// Whether the data files are from a different version than the one requested
+    pub fn is_fallback(&self) -> bool {
+        self.version.to_path() != self.requested_version.to_path()
     }
 
     pub fn get(&self, path: &str) -> Result<Cow<'static, [u8]>, ErrorKind> {
-        let mut p = self.base_path.clone();
-        p.push(self.version.to_path());
-        p.push(path);
-        if !p.exists() {
-            let p = format!("{}/{}", self.version.to_path(), path);
-            if let Some(x) = Embedded::get(&p) {
-                return Ok(x.data);
+        for source in self.sources.iter() {
+            if let Some(data) = source.get(&self.version, path)? {
+                return Ok(data);
             }
-            return Err(ErrorKind::DatafileNotFound {
-                version: self.version,
-                path: path.to_string(),
-            });
         }
-        Ok(Cow::from(std::fs::read(p).unwrap()))
+        Err(ErrorKind::DatafileNotFound {
+            version: self.version,
+            path: path.to_string(),
+        })
     }
 }
 
@@ -99,22 +219,122 @@ mod test {
 
     #[test]
     fn different_patch() {
-        let older = Version::from_client_exe("0,10,9,0");
-        let newer = Version::from_client_exe("0,10,10,0");
+        let older = "0,10,9,0".parse::<Version>().unwrap();
+        let newer = "0,10,10,0".parse::<Version>().unwrap();
         assert_older_newer(older, newer);
     }
 
     #[test]
     fn different_minor() {
-        let older = Version::from_client_exe("0,10,9,0");
-        let newer = Version::from_client_exe("0,11,0,0");
+        let older = "0,10,9,0".parse::<Version>().unwrap();
+        let newer = "0,11,0,0".parse::<Version>().unwrap();
         assert_older_newer(older, newer);
     }
 
     #[test]
     fn different_major() {
-        let older = Version::from_client_exe("0,11,5,0");
-        let newer = Version::from_client_exe("1,0,0,0");
+        let older = "0,11,5,0".parse::<Version>().unwrap();
+        let newer = "1,0,0,0".parse::<Version>().unwrap();
         assert_older_newer(older, newer);
     }
+
+    #[test]
+    fn different_build() {
+        let older = "0,10,9,3747819".parse::<Version>().unwrap();
+        let newer = "0,10,9,3800000".parse::<Version>().unwrap();
+        assert_older_newer(older, newer);
+        assert!(older < newer);
+        assert_ne!(older, newer);
+    }
+
+    #[test]
+    fn parse_forms() {
+        let full = Version {
+            major: 0,
+            minor: 10,
+            patch: 3,
+            build: 3747819,
+        };
+        assert_eq!("0,10,3,3747819".parse::<Version>().unwrap(), full);
+        assert_eq!("0.10.3.3747819".parse::<Version>().unwrap(), full);
+        assert_eq!(full.to_string().parse::<Version>().unwrap(), full);
+        assert_eq!(full.to_string(), "0.10.3.3747819");
+
+        // The Version packet has no build number
+        let packet = "0,10,3,0".parse::<Version>().unwrap();
+        assert_eq!(packet.build, 0);
+        assert!(packet.matches(&full));
+        assert!(full.matches(&packet));
+        assert!("0.10.3".parse::<Version>().unwrap().matches(&full));
+        assert!(!"0,10,4,0".parse::<Version>().unwrap().matches(&full));
+        assert!(!"0,10,3,12".parse::<Version>().unwrap().matches(&full));
+
+        for bad in ["", "0,10", "0,10,3,4,5", "0,ten,3,0", "-1,10,3,0"].iter() {
+            assert!(matches!(
+                bad.parse::<Version>(),
+                Err(ErrorKind::BadVersion { version }) if &version == bad
+            ));
+        }
+    }
+
+    #[test]
+    fn from_client_exe() {
+        let version = Version::from_client_exe("0,10,3,3747819").unwrap();
+        assert_eq!(version.to_string(), "0.10.3.3747819");
+        assert!(matches!(
+            Version::from_client_exe("0,10,three"),
+            Err(ErrorKind::BadVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn available_versions() {
+        let base =
+            std::env::temp_dir().join(format!("wows-replays-versions-{}", std::process::id()));
+        std::fs::create_dir_all(base.join("99.0.0/scripts")).unwrap();
+        std::fs::write(base.join("99.0.0/scripts/entities.xml"), "<root/>").unwrap();
+        // Not a version, or a version without any data
+        std::fs::create_dir_all(base.join("notes")).unwrap();
+        std::fs::create_dir_all(base.join("98.0.0")).unwrap();
+
+        let versions: Vec<_> = Datafiles::available_versions(&base)
+            .iter()
+            .map(|version| version.to_path())
+            .collect();
+        // The embedded versions are available from anywhere
+        assert_eq!(versions.first().map(|v| v.as_str()), Some("0.9.10"));
+        assert!(versions.contains(&"0.10.10".to_string()));
+        assert_eq!(versions.last().map(|v| v.as_str()), Some("99.0.0"));
+        assert!(!versions.contains(&"98.0.0".to_string()));
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn resolve_versions() {
+        let base = PathBuf::from("../versions");
+        let resolve = |version: &str, policy| {
+            Datafiles::resolve(base.clone(), version.parse::<Version>().unwrap(), policy)
+        };
+
+        let exact = resolve("0,10,9,4567", VersionPolicy::Exact).unwrap();
+        assert_eq!(exact.version().to_path(), "0.10.9");
+        assert!(!exact.is_fallback());
+        assert!(matches!(
+            resolve("0,11,2,0", VersionPolicy::Exact),
+            Err(ErrorKind::UnsupportedReplayVersion(v)) if v == "0.11.2"
+        ));
+
+        let hotfix = resolve("0,11,2,0", VersionPolicy::NearestOlder).unwrap();
+        assert_eq!(hotfix.version().to_path(), "0.11.1");
+        assert_eq!(hotfix.requested_version().to_path(), "0.11.2");
+        assert!(hotfix.is_fallback());
+        assert!(hotfix.get("scripts/entities.xml").is_ok());
+
+        // Between two known versions, the older one is used
+        let between = resolve("0,10,12,0", VersionPolicy::NearestOlder).unwrap();
+        assert_eq!(between.version().to_path(), "0.10.11");
+
+        // There's nothing to fall back to
+        assert!(resolve("0,8,0,0", VersionPolicy::NearestOlder).is_err());
+    }
 }
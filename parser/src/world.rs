@@ -0,0 +1,202 @@
+//! Tracks the state of every entity in the game as packets are parsed.
+//!
+//! The [World] is kept up to date by the [crate::packet2::Parser] as it parses each packet, so
+//! that after parsing up to some clock the world reflects the state of the game at that clock:
+//! ```ignore
+//! let mut parser = Parser::new(&specs);
+//! for packet in parser.packets(&replay.packet_data) {
+//!     if packet?.clock > 300.0 {
+//!         break;
+//!     }
+//! }
+//! let health = parser.world().entity(vehicle_id).and_then(|v| v.property("health"));
+//! ```
+
+use std::collections::HashMap;
+
+use crate::packet2::{Rot3, Vec3};
+use crate::rpc::entitydefs::EntitySpec;
+use crate::rpc::typedefs::ArgValue;
+
+/// The last known state of an entity
+#[derive(Debug, Clone)]
+pub struct Entity<'argtype> {
+    pub(crate) spec: &'argtype EntitySpec,
+    pub(crate) entity_type: u16,
+    /// Property values, keyed by their index into the spec's properties
+    pub(crate) properties: HashMap<usize, ArgValue<'argtype>>,
+    pub(crate) space_id: u32,
+    pub(crate) vehicle_id: u32,
+    pub(crate) position: Vec3,
+    pub(crate) rotation: Rot3,
+    pub(crate) present: bool,
+}
+
+impl<'argtype> Entity<'argtype> {
+    pub(crate) fn new(spec: &'argtype EntitySpec, entity_type: u16) -> Self {
+        Self {
+            spec,
+            entity_type,
+            properties: HashMap::new(),
+            space_id: 0,
+            vehicle_id: 0,
+            position: Vec3::default(),
+            rotation: Rot3::default(),
+            present: true,
+        }
+    }
+
+    /// The index of this entity's type in the entity specs, plus one
+    pub fn entity_type(&self) -> u16 {
+        self.entity_type
+    }
+
+    /// The name of this entity's type, e.g. "Vehicle" or "Avatar"
+    pub fn type_name(&self) -> &'argtype str {
+        &self.spec.name
+    }
+
+    /// Returns the current value of the named property, if it has been set
+    pub fn property(&self, name: &str) -> Option<&ArgValue<'argtype>> {
+        let idx = self.spec.properties.iter().position(|p| p.name == name)?;
+        self.properties.get(&idx)
+    }
+
+    /// Iterates over every property which has been set, in no particular order
+    pub fn properties(&self) -> impl Iterator<Item = (&'argtype str, &ArgValue<'argtype>)> {
+        let spec = self.spec;
+        self.properties
+            .iter()
+            .map(move |(idx, value)| (spec.properties[*idx].name.as_str(), value))
+    }
+
+    pub fn space_id(&self) -> u32 {
+        self.space_id
+    }
+
+    pub fn vehicle_id(&self) -> u32 {
+        self.vehicle_id
+    }
+
+    /// The last known position, from the entity's creation or the most recent `Position`,
+    /// `PlayerOrientation` or `EntityPosition` packet
+    pub fn position(&self) -> &Vec3 {
+        &self.position
+    }
+
+    pub fn rotation(&self) -> &Rot3 {
+        &self.rotation
+    }
+
+    /// Whether the entity is currently in the player's area of interest. Entities which have
+    /// left keep their last known state, but don't receive updates until they're created again.
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+}
+
+/// The state of every entity which has been created so far
+#[derive(Debug, Clone, Default)]
+pub struct World<'argtype> {
+    pub(crate) entities: HashMap<u32, Entity<'argtype>>,
+    pub(crate) clock: f32,
+}
+
+impl<'argtype> World<'argtype> {
+    /// The clock of the most recently parsed packet
+    pub fn clock(&self) -> f32 {
+        self.clock
+    }
+
+    pub fn entity(&self, entity_id: u32) -> Option<&Entity<'argtype>> {
+        self.entities.get(&entity_id)
+    }
+
+    /// Iterates over every entity which has ever been created, in no particular order
+    pub fn entities(&self) -> impl Iterator<Item = (u32, &Entity<'argtype>)> {
+        self.entities.iter().map(|(id, entity)| (*id, entity))
+    }
+
+    /// Iterates over the entities of the given type, e.g. "Vehicle"
+    pub fn entities_of_type<'a>(
+        &'a self,
+        type_name: &'a str,
+    ) -> impl Iterator<Item = (u32, &'a Entity<'argtype>)> {
+        self.entities()
+            .filter(move |(_, entity)| entity.type_name() == type_name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::packet2::{PacketType, Parser};
+    use crate::version::{Datafiles, Version};
+    use std::collections::HashMap;
+
+    #[test]
+    fn track_test_replay() {
+        let replay = crate::ReplayFile::from_file(&std::path::PathBuf::from(
+            "../test/replays/version-3747819.wowsreplay",
+        ))
+        .unwrap();
+        let datafiles = Datafiles::new(
+            std::path::PathBuf::from("../versions"),
+            replay.meta.clientVersionFromExe.parse::<Version>().unwrap(),
+        )
+        .unwrap();
+        let specs = crate::parse_scripts(&datafiles).unwrap();
+
+        // Track the health and position of each vehicle from the packets themselves
+        let mut health = HashMap::new();
+        let mut positions = HashMap::new();
+        let mut present = HashMap::new();
+        let mut parser = Parser::new(&specs);
+        for packet in parser.packets(&replay.packet_data) {
+            let packet = packet.unwrap();
+            match packet.payload {
+                PacketType::EntityCreate(p) => {
+                    if let Some(value) = p.props.get("health") {
+                        health.insert(p.entity_id, value.clone());
+                    }
+                    present.insert(p.entity_id, true);
+                }
+                PacketType::EntityProperty(p) if p.property == "health" => {
+                    health.insert(p.entity_id, p.value);
+                }
+                PacketType::EntityLeave(p) => {
+                    present.insert(p.entity_id, false);
+                }
+                PacketType::Position(p) => {
+                    positions.insert(p.pid, p.position);
+                }
+                _ => {}
+            }
+            if packet.clock > 300.0 {
+                break;
+            }
+        }
+
+        let world = parser.world();
+        assert!(world.clock() > 300.0);
+        assert!(!health.is_empty());
+        for (entity_id, value) in health.iter() {
+            let entity = world.entity(*entity_id).unwrap();
+            assert_eq!(entity.type_name(), "Vehicle");
+            assert_eq!(entity.property("health"), Some(value));
+        }
+        for (entity_id, position) in positions.iter() {
+            let tracked = world.entity(*entity_id).unwrap().position();
+            assert_eq!(
+                (tracked.x, tracked.y, tracked.z),
+                (position.x, position.y, position.z)
+            );
+        }
+        assert!(present.values().any(|present| !present));
+        for (entity_id, present) in present.iter() {
+            assert_eq!(world.entity(*entity_id).unwrap().is_present(), *present);
+        }
+        // Not every vehicle has been spotted by this point
+        let vehicles = world.entities_of_type("Vehicle").count();
+        assert!(vehicles > 0 && vehicles <= replay.meta.vehicles.len());
+    }
+}
@@ -1,9 +1,10 @@
-use crypto::symmetriccipher::BlockDecryptor;
+use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
 use nom::bytes::complete::take;
 use nom::number::complete::le_u32;
+use serde::Deserialize as _;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::io::Read;
+use std::io::{Read, Write};
 
 use crate::error::*;
 
@@ -45,91 +46,402 @@ pub struct ReplayMeta {
     pub battleDuration: u32,
 }
 
+/// Results of the battle, as shown on the post-battle screen. This is only present in replays
+/// which were saved after the battle results arrived.
+///
+/// The per-player and private entries are positional lists whose layout changes between game
+/// versions, so they are kept as raw JSON.
+#[allow(non_snake_case)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BattleResults {
+    pub arenaUniqueID: u64,
+    /// Public results (damage, XP, kills, etc.) of every player, keyed by account ID
+    #[serde(default)]
+    pub playersPublicInfo: HashMap<String, Vec<serde_json::Value>>,
+    /// Results only visible to the recording player, such as credits and XP earned
+    #[serde(default)]
+    pub privateDataList: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub commonList: Vec<serde_json::Value>,
+    /// Any other keys in the results block
+    #[serde(flatten)]
+    pub other: HashMap<String, serde_json::Value>,
+}
+
+const REPLAY_MAGIC: u32 = 0x11343212;
+
+/// The fixed Blowfish key which the game uses to encrypt the packet stream.
+const BLOWFISH_KEY: [u8; 16] = [
+    0x29, 0xB7, 0xC9, 0x09, 0x38, 0x3F, 0x84, 0x88, 0xFA, 0x98, 0xEC, 0x4E, 0x13, 0x19, 0x79, 0xFB,
+];
+
 #[derive(Debug)]
 struct Replay<'a> {
     meta: ReplayMeta,
-    uncompressed_size: u32,
+    blocks: Vec<serde_json::Value>,
     compressed_stream: &'a [u8],
 }
 
-fn decode_meta(meta: &[u8]) -> Result<ReplayMeta, Error> {
-    let meta = std::str::from_utf8(meta)?;
-    let meta: ReplayMeta = serde_json::from_str(meta)?;
-    Ok(meta)
+fn parse_json_block(i: &[u8]) -> IResult<&[u8], serde_json::Value> {
+    let (i, block_len) = le_u32(i)?;
+    let (i, block) = take(block_len)(i)?;
+    match serde_json::from_slice(block) {
+        Ok(value) => Ok((i, value)),
+        Err(err) => Err(failure_from_kind(ErrorKind::BadMeta { err })),
+    }
 }
 
-fn parse_meta(i: &[u8]) -> IResult<&[u8], ReplayMeta> {
-    let (i, meta_len) = le_u32(i)?;
-    let (i, meta) = take(meta_len)(i)?;
-    let meta = match decode_meta(meta) {
+fn replay_format(i: &[u8]) -> IResult<&[u8], Replay> {
+    let (i, magic) = le_u32(i)?;
+    if magic != REPLAY_MAGIC {
+        return Err(failure_from_kind(ErrorKind::BadMagic { magic }));
+    }
+    let (i, block_count) = le_u32(i)?;
+
+    // The first block is always the replay metadata
+    let (mut i, value) = parse_json_block(i)?;
+    let meta: ReplayMeta = match ReplayMeta::deserialize(&value) {
         Ok(x) => x,
-        Err(e) => {
-            return Err(nom::Err::Error(e.into()));
+        Err(err) => {
+            return Err(failure_from_kind(ErrorKind::BadMeta { err }));
         }
     };
-    Ok((i, meta))
-}
+    let mut blocks = vec![value];
+    for _ in 1..block_count {
+        let (new_i, value) = parse_json_block(i)?;
+        i = new_i;
+        blocks.push(value);
+    }
 
-fn replay_format(i: &[u8]) -> IResult<&[u8], Replay> {
-    let (i, unknown) = take(8usize)(i)?;
-    let (i, meta) = parse_meta(i)?;
-    let (i, uncompressed_size) = le_u32(i)?;
+    let (i, _uncompressed_size) = le_u32(i)?;
     let (i, _stream_size) = le_u32(i)?;
     Ok((
         i,
         Replay {
             meta: meta,
-            uncompressed_size: uncompressed_size,
-            compressed_stream: unknown,
+            blocks,
+            compressed_stream: i,
         },
     ))
 }
 
+/// Undoes the Blowfish encryption on the packet stream. Each block is XOR'd with
+/// the previous plaintext block after decryption.
+fn decrypt(encrypted: &[u8]) -> Result<Vec<u8>, ErrorKind> {
+    let blowfish = crypto::blowfish::Blowfish::new(&BLOWFISH_KEY);
+    let blocks = encrypted.chunks_exact(BlockDecryptor::block_size(&blowfish));
+    if !blocks.remainder().is_empty() {
+        return Err(ErrorKind::BadEncryptedLength {
+            length: encrypted.len(),
+        });
+    }
+    let mut decrypted = vec![0u8; encrypted.len()];
+    let mut previous = [0; 8]; // 8 == block size
+    for (block, out) in blocks.zip(decrypted.chunks_exact_mut(8)) {
+        blowfish.decrypt_block(block, out);
+        for (b, p) in out.iter_mut().zip(previous.iter()) {
+            *b ^= p;
+        }
+        previous.copy_from_slice(out);
+    }
+    Ok(decrypted)
+}
+
+/// The inverse of `decrypt`. The plaintext must already be padded to the block size.
+fn encrypt(plaintext: &[u8]) -> Vec<u8> {
+    let blowfish = crypto::blowfish::Blowfish::new(&BLOWFISH_KEY);
+    let mut encrypted = vec![0u8; plaintext.len()];
+    let mut previous = [0; 8];
+    for (block, out) in plaintext
+        .chunks_exact(BlockEncryptor::block_size(&blowfish))
+        .zip(encrypted.chunks_exact_mut(8))
+    {
+        let mut xored = [0; 8];
+        for ((x, b), p) in xored.iter_mut().zip(block.iter()).zip(previous.iter()) {
+            *x = b ^ p;
+        }
+        blowfish.encrypt_block(&xored, out);
+        previous.copy_from_slice(block);
+    }
+    encrypted
+}
+
 #[derive(Debug)]
 pub struct ReplayFile {
     pub meta: ReplayMeta,
+    /// Every JSON block from the file header, in file order. The first block is the one which
+    /// `meta` is decoded from.
+    pub blocks: Vec<serde_json::Value>,
+    /// The post-battle results, if the second block is present. A block which doesn't match
+    /// `BattleResults` is kept as the decode error rather than dropped.
+    pub battle_results: Option<Result<BattleResults, serde_json::Error>>,
     pub packet_data: Vec<u8>,
 }
 
 impl ReplayFile {
     pub fn from_file(replay: &std::path::PathBuf) -> Result<ReplayFile, ErrorKind> {
-        let mut f = std::fs::File::open(replay).unwrap();
+        let f = std::fs::File::open(replay)?;
+        Self::from_reader(f)
+    }
+
+    /// Reads an entire replay from the given reader, e.g. an upload buffer or an
+    /// archive entry.
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<ReplayFile, ErrorKind> {
         let mut contents = vec![];
-        f.read_to_end(&mut contents).unwrap();
-
-        let (remaining, result) = replay_format(&contents)?;
-
-        // Decrypt
-        let key = [
-            0x29, 0xB7, 0xC9, 0x09, 0x38, 0x3F, 0x84, 0x88, 0xFA, 0x98, 0xEC, 0x4E, 0x13, 0x19,
-            0x79, 0xFB,
-        ];
-        let blowfish = crypto::blowfish::Blowfish::new(&key);
-        assert!(blowfish.block_size() == 8);
-        let encrypted = remaining; //result.compressed_stream
-        let mut decrypted = vec![];
-        decrypted.resize(encrypted.len(), 0u8);
-        let num_blocks = encrypted.len() / blowfish.block_size();
-        let mut previous = [0; 8]; // 8 == block size
-        for i in 0..num_blocks {
-            let offset = i * blowfish.block_size();
-            blowfish.decrypt_block(
-                &encrypted[offset..offset + blowfish.block_size()],
-                &mut decrypted[offset..offset + blowfish.block_size()],
-            );
-            for j in 0..8 {
-                decrypted[offset + j] = decrypted[offset + j] ^ previous[j];
-                previous[j] = decrypted[offset + j];
-            }
-        }
+        reader.read_to_end(&mut contents)?;
+        Self::from_bytes(&contents)
+    }
+
+    pub fn from_bytes(contents: &[u8]) -> Result<ReplayFile, ErrorKind> {
+        let (_, result) = replay_format(contents).map_err(|e| match ErrorKind::from(e) {
+            ErrorKind::Nom { .. } | ErrorKind::Incomplete => ErrorKind::TruncatedHeader,
+            e => e,
+        })?;
+
+        let decrypted = decrypt(result.compressed_stream)?;
 
         let mut deflater = flate2::read::ZlibDecoder::new(&decrypted[..]);
         let mut contents = vec![];
-        deflater.read_to_end(&mut contents).unwrap();
+        deflater
+            .read_to_end(&mut contents)
+            .map_err(|err| ErrorKind::Zlib { err })?;
+
+        let battle_results = result.blocks.get(1).map(BattleResults::deserialize);
 
         Ok(ReplayFile {
             meta: result.meta,
+            blocks: result.blocks,
+            battle_results,
             packet_data: contents,
         })
     }
 }
+
+/// Produces a replay file from a set of header blocks and a raw packet stream, i.e. the
+/// inverse of `ReplayFile`. The packet stream is compressed and encrypted the same way the
+/// game does it.
+#[derive(Clone, Debug)]
+pub struct ReplayWriter {
+    /// The JSON blocks to write into the header. The first block must be the replay metadata.
+    pub blocks: Vec<serde_json::Value>,
+    pub packet_data: Vec<u8>,
+}
+
+impl ReplayWriter {
+    pub fn new(meta: &ReplayMeta, packet_data: Vec<u8>) -> Result<ReplayWriter, ErrorKind> {
+        Ok(ReplayWriter {
+            blocks: vec![serde_json::to_value(meta)?],
+            packet_data,
+        })
+    }
+
+    /// Creates a writer which reproduces the given replay. Since this uses the raw header
+    /// blocks rather than `ReplayMeta`, metadata keys which we don't parse are preserved.
+    pub fn from_replay(replay: &ReplayFile) -> ReplayWriter {
+        ReplayWriter {
+            blocks: replay.blocks.clone(),
+            packet_data: replay.packet_data.clone(),
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), ErrorKind> {
+        let mut compressed = {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(&self.packet_data)?;
+            encoder.finish()?
+        };
+        let compressed_size = compressed.len();
+        // Pad out to the Blowfish block size
+        compressed.resize(compressed_size + (8 - compressed_size % 8) % 8, 0);
+
+        writer.write_all(&REPLAY_MAGIC.to_le_bytes())?;
+        writer.write_all(&(self.blocks.len() as u32).to_le_bytes())?;
+        for block in self.blocks.iter() {
+            let block = serde_json::to_vec(block)?;
+            writer.write_all(&(block.len() as u32).to_le_bytes())?;
+            writer.write_all(&block)?;
+        }
+        writer.write_all(&(self.packet_data.len() as u32).to_le_bytes())?;
+        writer.write_all(&(compressed_size as u32).to_le_bytes())?;
+        writer.write_all(&encrypt(&compressed))?;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ErrorKind> {
+        let mut data = vec![];
+        self.write(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn to_file(&self, path: &std::path::Path) -> Result<(), ErrorKind> {
+        let f = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn test_replay() -> Vec<u8> {
+        std::fs::read("../test/replays/version-3747819.wowsreplay").unwrap()
+    }
+
+    /// Offset of the encrypted stream within the test replay
+    fn stream_offset(replay: &[u8]) -> usize {
+        let meta_len = u32::from_le_bytes(replay[8..12].try_into().unwrap()) as usize;
+        12 + meta_len + 8
+    }
+
+    #[test]
+    fn loads_test_replay() {
+        let replay = ReplayFile::from_reader(&test_replay()[..]).unwrap();
+        assert_eq!(replay.meta.playerName, "lkolbly");
+        assert_eq!(replay.packet_data.len(), 7933087);
+        assert_eq!(replay.blocks.len(), 1);
+        assert_eq!(replay.blocks[0]["playerName"], "lkolbly");
+        assert!(replay.battle_results.is_none());
+    }
+
+    #[test]
+    fn multiple_blocks() {
+        let data = test_replay();
+        let meta = &data[8..stream_offset(&data) - 8];
+        let results = br#"{"arenaUniqueID": 2332830286679680, "playersPublicInfo": {"403703021": [1, "lkolbly"]}, "commonList": [5], "privateDataList": [], "accountDBID": 403703021}"#;
+
+        let mut header = vec![];
+        header.extend_from_slice(&REPLAY_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u32.to_le_bytes());
+        header.extend_from_slice(meta);
+        header.extend_from_slice(&(results.len() as u32).to_le_bytes());
+        header.extend_from_slice(results);
+        header.extend_from_slice(&data[stream_offset(&data) - 8..]);
+
+        let replay = ReplayFile::from_bytes(&header).unwrap();
+        assert_eq!(replay.blocks.len(), 2);
+        assert_eq!(replay.packet_data.len(), 7933087);
+        let results = replay.battle_results.unwrap().unwrap();
+        assert_eq!(results.arenaUniqueID, 2332830286679680);
+        assert_eq!(
+            results.playersPublicInfo["403703021"][1],
+            serde_json::json!("lkolbly")
+        );
+        assert_eq!(results.commonList, vec![serde_json::json!(5)]);
+        assert_eq!(results.other["accountDBID"], 403703021);
+    }
+
+    #[test]
+    fn malformed_battle_results() {
+        let data = test_replay();
+        let mut writer = ReplayWriter::from_replay(&ReplayFile::from_bytes(&data).unwrap());
+        writer
+            .blocks
+            .push(serde_json::json!({"arenaUniqueID": "not a number"}));
+        let replay = ReplayFile::from_bytes(&writer.to_bytes().unwrap()).unwrap();
+        assert_eq!(replay.blocks.len(), 2);
+        assert!(replay.battle_results.unwrap().is_err());
+    }
+
+    #[test]
+    fn encrypt_roundtrip() {
+        let data = test_replay();
+        let encrypted = &data[stream_offset(&data)..];
+        assert_eq!(encrypt(&decrypt(encrypted).unwrap()), encrypted);
+    }
+
+    #[test]
+    fn write_roundtrip() {
+        let replay = ReplayFile::from_bytes(&test_replay()).unwrap();
+        let written = ReplayWriter::from_replay(&replay).to_bytes().unwrap();
+        assert_eq!(written.len() % 8, stream_offset(&written) % 8);
+
+        let reread = ReplayFile::from_bytes(&written).unwrap();
+        assert_eq!(reread.blocks, replay.blocks);
+        assert_eq!(reread.packet_data, replay.packet_data);
+
+        // Writing is deterministic, so a second round trip is byte-identical
+        let rewritten = ReplayWriter::from_replay(&reread).to_bytes().unwrap();
+        assert_eq!(rewritten, written);
+    }
+
+    #[test]
+    fn write_from_meta() {
+        let replay = ReplayFile::from_bytes(&test_replay()).unwrap();
+        let packets = replay.packet_data[..1000].to_vec();
+        let written = ReplayWriter::new(&replay.meta, packets.clone())
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+
+        let reread = ReplayFile::from_bytes(&written).unwrap();
+        assert_eq!(reread.meta.playerName, "lkolbly");
+        assert_eq!(reread.meta.vehicles.len(), replay.meta.vehicles.len());
+        assert_eq!(reread.packet_data, packets);
+        let uncompressed_size = stream_offset(&written) - 8;
+        assert_eq!(
+            u32::from_le_bytes(
+                written[uncompressed_size..uncompressed_size + 4]
+                    .try_into()
+                    .unwrap()
+            ),
+            1000
+        );
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut data = test_replay();
+        data[0] = 0;
+        match ReplayFile::from_bytes(&data) {
+            Err(ErrorKind::BadMagic { magic }) => assert_eq!(magic, 0x11343200),
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn truncated_header() {
+        let data = test_replay();
+        for len in [0, 3, 8, 100, stream_offset(&data) - 1] {
+            match ReplayFile::from_bytes(&data[..len]) {
+                Err(ErrorKind::TruncatedHeader) => {}
+                x => panic!("{}: {:?}", len, x),
+            }
+        }
+    }
+
+    #[test]
+    fn bad_meta() {
+        let mut data = test_replay();
+        data[12] = b'x';
+        match ReplayFile::from_bytes(&data) {
+            Err(ErrorKind::BadMeta { .. }) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn bad_encrypted_length() {
+        let data = test_replay();
+        match ReplayFile::from_bytes(&data[..data.len() - 1]) {
+            Err(ErrorKind::BadEncryptedLength { length }) => {
+                assert_eq!(length, data.len() - 1 - stream_offset(&data))
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn zlib_failure() {
+        let mut data = test_replay();
+        let offset = stream_offset(&data);
+        for b in data[offset..offset + 64].iter_mut() {
+            *b = 0;
+        }
+        match ReplayFile::from_bytes(&data) {
+            Err(ErrorKind::Zlib { .. }) => {}
+            x => panic!("{:?}", x),
+        }
+    }
+}
@@ -11,8 +11,8 @@ use tera::{Context, Tera};
 use wows_replays::analyzer::decoder::DecodedPacketPayload;
 use wows_replays::analyzer::AnalyzerBuilder;
 use wows_replays::packet2::Packet;
-use wows_replays::parse_scripts;
 use wows_replays::ReplayFile;
+use wows_replays::SpecCache;
 
 #[macro_use]
 extern crate rocket;
@@ -38,10 +38,10 @@ struct ReplayInfo {
     player_team: i64,
 }
 
-impl wows_replays::packet2::PacketProcessor for ReplayInfo {
-    fn process(&mut self, packet: Packet<'_, '_>) {
+impl ReplayInfo {
+    fn process(&mut self, packet: &Packet<'_, '_>) {
         let packet =
-            wows_replays::analyzer::decoder::DecodedPacket::from(&self.version, false, &packet);
+            wows_replays::analyzer::decoder::DecodedPacket::from(&self.version, false, packet);
         match &packet.payload {
             DecodedPacketPayload::OnArenaStateReceived { players, .. } => {
                 for player in players.iter() {
@@ -63,41 +63,40 @@ impl wows_replays::packet2::PacketProcessor for ReplayInfo {
 }
 
 impl ReplayInfo {
-    fn new(path: &std::path::PathBuf, hash: String, meta: &wows_replays::ReplayMeta) -> ReplayInfo {
-        ReplayInfo {
+    fn new(
+        path: &std::path::PathBuf,
+        hash: String,
+        meta: &wows_replays::ReplayMeta,
+    ) -> Result<ReplayInfo, wows_replays::ErrorKind> {
+        Ok(ReplayInfo {
             username: meta.playerName.clone(),
             date: meta.dateTime.clone(),
             ship: meta.playerVehicle.clone(),
             map: meta.mapDisplayName.clone(),
             hash: hash,
             path: path.clone(),
-            version: wows_replays::version::Version::from_client_exe(&meta.clientVersionFromExe),
+            version: meta.clientVersionFromExe.parse()?,
             victory: None,
             num_packets: 0,
             player_team: -1,
-        }
+        })
     }
 
     fn from(replay: &std::path::PathBuf) -> Result<ReplayInfo, wows_replays::ErrorKind> {
         let replay_file = ReplayFile::from_file(replay)?;
 
-        let datafiles = wows_replays::version::Datafiles::new(
-            std::path::PathBuf::from("versions"),
-            wows_replays::version::Version::from_client_exe(&replay_file.meta.clientVersionFromExe),
-        )?;
-        let specs = parse_scripts(&datafiles)?;
-        let version_parts: Vec<_> = replay_file.meta.clientVersionFromExe.split(",").collect();
-        assert!(version_parts.len() == 4);
-
-        // Parse packets
-        let mut p = wows_replays::packet2::Parser::new(&specs);
-
         let mut processor = ReplayInfo::new(
             replay,
             replay.file_name().unwrap().to_str().unwrap().to_string(),
             &replay_file.meta,
-        );
-        p.parse_packets(&replay_file.packet_data, &mut processor);
+        )?;
+        let specs = SPECS.get(processor.version)?;
+
+        // Parse packets
+        let mut p = wows_replays::packet2::Parser::new(&specs);
+        for packet in p.packets(&replay_file.packet_data) {
+            processor.process(&packet?);
+        }
         Ok(processor)
     }
 }
@@ -170,6 +169,7 @@ fn replay_updater(file_source: Receiver<std::path::PathBuf>) {
 lazy_static! {
     static ref DATABASE: Mutex<Database> = Mutex::new(Database::new());
     static ref TOTAL_FILES: AtomicUsize = AtomicUsize::new(0);
+    static ref SPECS: SpecCache = SpecCache::new(std::path::PathBuf::from("versions"));
 }
 
 #[get("/<name>/<age>")]
@@ -254,25 +254,18 @@ fn damage_trails(replay: ReplayInfo) -> (rocket::http::ContentType, Vec<u8>) {
     {
         let replay_file = ReplayFile::from_file(&replay.path).unwrap();
 
-        let datafiles = wows_replays::version::Datafiles::new(
-            std::path::PathBuf::from("versions"),
-            wows_replays::version::Version::from_client_exe(&replay_file.meta.clientVersionFromExe),
-        )
-        .unwrap();
-        let specs = parse_scripts(&datafiles).unwrap();
-        let version_parts: Vec<_> = replay_file.meta.clientVersionFromExe.split(",").collect();
-        assert!(version_parts.len() == 4);
+        let specs = SPECS.get(replay.version).unwrap();
 
         let processor = analysis::damage_trails::DamageTrailsBuilder::new("foo.png");
-        let processor = processor.build(&replay_file.meta);
+        let processor = processor.build(&replay_file.meta).unwrap();
 
         // Parse packets
         let mut p = wows_replays::packet2::Parser::new(&specs);
 
         let mut analyzer_set = wows_replays::analyzer::AnalyzerAdapter::new(vec![processor]);
-        p.parse_packets(&replay_file.packet_data, &mut analyzer_set)
+        analyzer_set
+            .consume(p.packets(&replay_file.packet_data))
             .unwrap();
-        analyzer_set.finish();
     }
 
     let mut content = vec![];
@@ -289,25 +282,18 @@ fn trails(replay: ReplayInfo) -> (rocket::http::ContentType, Vec<u8>) {
     {
         let replay_file = ReplayFile::from_file(&replay.path).unwrap();
 
-        let datafiles = wows_replays::version::Datafiles::new(
-            std::path::PathBuf::from("versions"),
-            wows_replays::version::Version::from_client_exe(&replay_file.meta.clientVersionFromExe),
-        )
-        .unwrap();
-        let specs = parse_scripts(&datafiles).unwrap();
-        let version_parts: Vec<_> = replay_file.meta.clientVersionFromExe.split(",").collect();
-        assert!(version_parts.len() == 4);
+        let specs = SPECS.get(replay.version).unwrap();
 
         let processor = analysis::trails::TrailsBuilder::new("/tmp/tmp.png");
-        let processor = processor.build(&replay_file.meta);
+        let processor = processor.build(&replay_file.meta).unwrap();
 
         // Parse packets
         let mut p = wows_replays::packet2::Parser::new(&specs);
 
         let mut analyzer_set = wows_replays::analyzer::AnalyzerAdapter::new(vec![processor]);
-        p.parse_packets(&replay_file.packet_data, &mut analyzer_set)
+        analyzer_set
+            .consume(p.packets(&replay_file.packet_data))
             .unwrap();
-        analyzer_set.finish();
     }
 
     let mut content = vec![];
@@ -340,10 +326,10 @@ struct DecodedResponder {
     result: String,
 }
 
-impl wows_replays::packet2::PacketProcessor for DecodedResponder {
-    fn process(&mut self, packet: Packet<'_, '_>) {
+impl DecodedResponder {
+    fn process(&mut self, packet: &Packet<'_, '_>) {
         let packet =
-            wows_replays::analyzer::decoder::DecodedPacket::from(&self.version, false, &packet);
+            wows_replays::analyzer::decoder::DecodedPacket::from(&self.version, false, packet);
         let encoded = serde_json::to_string(&packet).unwrap();
         self.result.push_str("\n");
         self.result.push_str(&encoded);
@@ -366,14 +352,7 @@ impl<'r> rocket::response::Responder<'r, 'r> for DecodedResponder {
 fn download_decoded(replay: ReplayInfo) -> DecodedResponder {
     let replay_file = ReplayFile::from_file(&replay.path).unwrap();
 
-    let datafiles = wows_replays::version::Datafiles::new(
-        std::path::PathBuf::from("versions"),
-        wows_replays::version::Version::from_client_exe(&replay_file.meta.clientVersionFromExe),
-    )
-    .unwrap();
-    let specs = parse_scripts(&datafiles).unwrap();
-    let version_parts: Vec<_> = replay_file.meta.clientVersionFromExe.split(",").collect();
-    assert!(version_parts.len() == 4);
+    let specs = SPECS.get(replay.version).unwrap();
 
     let mut processor = DecodedResponder {
         filename: format!(
@@ -384,17 +363,16 @@ fn download_decoded(replay: ReplayInfo) -> DecodedResponder {
             replay.username,
             &replay.hash[0..10]
         ),
-        version: wows_replays::version::Version::from_client_exe(
-            &replay_file.meta.clientVersionFromExe,
-        ),
+        version: replay.version,
         result: serde_json::to_string(&replay_file.meta).unwrap(),
     };
 
     // Parse packets
     let mut p = wows_replays::packet2::Parser::new(&specs);
 
-    p.parse_packets(&replay_file.packet_data, &mut processor)
-        .unwrap();
+    for packet in p.packets(&replay_file.packet_data) {
+        processor.process(&packet.unwrap());
+    }
     processor
 }
 
@@ -2,7 +2,15 @@ use clap::{App, Arg, SubCommand};
 use std::collections::HashMap;
 use std::io::Write;
 
-use wows_replays::{parse_scripts, ErrorKind, ReplayFile};
+use wows_replays::{ErrorKind, ReplayFile, SpecCache};
+
+#[macro_use]
+extern crate lazy_static;
+
+lazy_static! {
+    static ref SPECS: SpecCache = SpecCache::new(std::path::PathBuf::from("versions"))
+        .with_policy(wows_replays::version::VersionPolicy::NearestOlder);
+}
 
 mod built_info {
     // The file has been placed there by the build script.
@@ -112,8 +120,11 @@ pub struct InvestigativeBuilder {
 }
 
 impl wows_replays::analyzer::AnalyzerBuilder for InvestigativeBuilder {
-    fn build(&self, meta: &wows_replays::ReplayMeta) -> Box<dyn wows_replays::analyzer::Analyzer> {
-        let version = wows_replays::version::Version::from_client_exe(&meta.clientVersionFromExe);
+    fn build(
+        &self,
+        meta: &wows_replays::ReplayMeta,
+    ) -> Result<Box<dyn wows_replays::analyzer::Analyzer>, ErrorKind> {
+        let version = meta.clientVersionFromExe.parse()?;
         let decoder = InvestigativePrinter {
             version: version,
             filter_packet: self
@@ -143,10 +154,27 @@ impl wows_replays::analyzer::AnalyzerBuilder for InvestigativeBuilder {
         if !self.no_meta {
             println!("{}", &serde_json::to_string(&meta).unwrap());
         }
-        Box::new(decoder)
+        Ok(Box::new(decoder))
     }
 }
 
+/// Gets the specs for a version from the shared cache, warning if they come from the scripts
+/// of an older version
+fn load_specs(
+    version: wows_replays::version::Version,
+) -> Result<std::sync::Arc<Vec<wows_replays::rpc::entitydefs::EntitySpec>>, ErrorKind> {
+    // Surveys parse many replays of the same few versions, so share the specs between them
+    let scripts_version = SPECS.scripts_version(version)?;
+    if scripts_version.to_path() != version.to_path() {
+        eprintln!(
+            "Warning: No scripts for version {}, decoding with scripts from {}",
+            version.to_path(),
+            scripts_version.to_path()
+        );
+    }
+    SPECS.get(version)
+}
+
 fn parse_replay<P: wows_replays::analyzer::AnalyzerBuilder>(
     replay: &std::path::PathBuf,
     processor: P,
@@ -156,30 +184,14 @@ fn parse_replay<P: wows_replays::analyzer::AnalyzerBuilder>(
     //let mut file = std::fs::File::create("foo.bin").unwrap();
     //file.write_all(&replay_file.packet_data).unwrap();
 
-    let datafiles = wows_replays::version::Datafiles::new(
-        std::path::PathBuf::from("versions"),
-        wows_replays::version::Version::from_client_exe(&replay_file.meta.clientVersionFromExe),
-    )?;
-    let specs = parse_scripts(&datafiles)?;
-
-    let version_parts: Vec<_> = replay_file.meta.clientVersionFromExe.split(",").collect();
-    assert!(version_parts.len() == 4);
+    let specs = load_specs(replay_file.meta.clientVersionFromExe.parse()?)?;
 
-    let processor = processor.build(&replay_file.meta);
+    let processor = processor.build(&replay_file.meta)?;
 
     // Parse packets
     let mut p = wows_replays::packet2::Parser::new(&specs);
     let mut analyzer_set = wows_replays::analyzer::AnalyzerAdapter::new(vec![processor]);
-    match p.parse_packets::<wows_replays::analyzer::AnalyzerAdapter>(
-        &replay_file.packet_data,
-        &mut analyzer_set,
-    ) {
-        Ok(()) => {
-            analyzer_set.finish();
-            Ok(())
-        }
-        Err(e) => Err(e),
-    }
+    analyzer_set.consume(p.packets(&replay_file.packet_data))
 }
 
 fn truncate_string(s: &str, length: usize) -> &str {
@@ -378,7 +390,7 @@ fn survey_file(skip_decode: bool, replay: std::path::PathBuf) -> SurveyResult {
     }
 }
 
-fn main() {
+fn main() -> Result<(), ErrorKind> {
     let replay_arg = Arg::with_name("REPLAY")
         .help("The replay file to use")
         .required(true)
@@ -429,6 +441,46 @@ fn main() {
                 )
                 .arg(replay_arg.clone()),
         )
+        .subcommand(
+            SubCommand::with_name("anonymize")
+                .about("Replace player names, clan tags and account IDs with pseudonyms")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .help("Replay file to write the anonymized replay to")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(replay_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("cut")
+                .about("Write a clip containing only the given range of the game clock")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .help("Game clock, in seconds, to start the clip at")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .help("Game clock, in seconds, to end the clip at")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .help("Replay file to write the clip to")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(replay_arg.clone()),
+        )
         .subcommand(
             SubCommand::with_name("spec")
                 .about("Dump the scripts specifications to console")
@@ -520,13 +572,27 @@ fn main() {
         };
         parse_replay(&std::path::PathBuf::from(input), dump).unwrap();
     }
+    if let Some(matches) = matches.subcommand_matches("anonymize") {
+        let input = matches.value_of("REPLAY").unwrap();
+        let replay = ReplayFile::from_file(&std::path::PathBuf::from(input)).unwrap();
+        let specs = load_specs(replay.meta.clientVersionFromExe.parse()?)?;
+        let anonymized = wows_replays::anonymizer::anonymize(&replay, &specs).unwrap();
+        anonymized
+            .to_file(std::path::Path::new(matches.value_of("output").unwrap()))
+            .unwrap();
+    }
+    if let Some(matches) = matches.subcommand_matches("cut") {
+        let input = matches.value_of("REPLAY").unwrap();
+        let from: f32 = matches.value_of("from").unwrap().parse().unwrap();
+        let to: f32 = matches.value_of("to").unwrap().parse().unwrap();
+        let replay = ReplayFile::from_file(&std::path::PathBuf::from(input)).unwrap();
+        let specs = load_specs(replay.meta.clientVersionFromExe.parse()?)?;
+        let clip = wows_replays::cut::cut(&replay, &specs, from, to).unwrap();
+        clip.to_file(std::path::Path::new(matches.value_of("output").unwrap()))
+            .unwrap();
+    }
     if let Some(matches) = matches.subcommand_matches("spec") {
-        let datafiles = wows_replays::version::Datafiles::new(
-            std::path::PathBuf::from("versions"),
-            wows_replays::version::Version::from_client_exe(matches.value_of("version").unwrap()),
-        )
-        .unwrap();
-        let specs = parse_scripts(&datafiles).unwrap();
+        let specs = load_specs(matches.value_of("version").unwrap().parse()?)?;
         printspecs(&specs);
     }
     if let Some(matches) = matches.subcommand_matches("summary") {
@@ -617,4 +683,5 @@ fn main() {
             );
         }
     }
+    Ok(())
 }